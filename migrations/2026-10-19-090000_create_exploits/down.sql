@@ -0,0 +1,5 @@
+DROP TABLE settings;
+DROP TABLE exploit_target_teams;
+DROP TABLE exploit_key_values;
+DROP TABLE exploits;
+DROP TABLE policies;
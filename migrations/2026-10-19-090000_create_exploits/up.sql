@@ -0,0 +1,69 @@
+CREATE TABLE policies (
+    id              SERIAL NOT NULL,
+    name            TEXT NOT NULL UNIQUE,
+    argv_pattern    TEXT NOT NULL,
+    repeat_interval INT NOT NULL,
+    disabled        BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY(id)
+);
+
+CREATE TABLE exploits (
+    id                SERIAL NOT NULL,
+    command           TEXT NOT NULL,
+    author            TEXT NOT NULL,
+    vuln_title        TEXT NOT NULL,
+    target_challenge  TEXT NOT NULL,
+    policy_id         INT NOT NULL,
+    script_timeout    INT NOT NULL,
+    overrun_policy    SMALLINT NOT NULL,
+    working_directory TEXT NOT NULL,
+    disabled          BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY(id),
+    FOREIGN KEY(policy_id) REFERENCES policies(id)
+);
+
+CREATE TABLE exploit_key_values (
+    exploit_id INT NOT NULL,
+    key        TEXT NOT NULL,
+    value      TEXT NOT NULL,
+    PRIMARY KEY(exploit_id, key),
+    FOREIGN KEY(exploit_id) REFERENCES exploits(id) ON DELETE CASCADE
+);
+
+CREATE TABLE exploit_target_teams (
+    exploit_id INT NOT NULL,
+    team_id    INT NOT NULL,
+    policy_id  INT NOT NULL,
+    PRIMARY KEY(exploit_id, team_id),
+    FOREIGN KEY(exploit_id) REFERENCES exploits(id) ON DELETE CASCADE,
+    FOREIGN KEY(team_id) REFERENCES teams(id) ON DELETE CASCADE,
+    FOREIGN KEY(policy_id) REFERENCES policies(id)
+);
+
+CREATE TABLE settings (
+    id                              SMALLINT NOT NULL DEFAULT 1 CHECK (id = 1),
+    flag_regex                      TEXT NOT NULL,
+    tick_length                     INT NOT NULL,
+    exploit_timeout                 INT NOT NULL,
+    exploit_working_dir             TEXT NOT NULL,
+    default_policy_id               INT,
+    own_team_id                     INT,
+    nop_team_id                     INT,
+    nop_team_grants_points          BOOLEAN NOT NULL,
+    flag_submission_batch_size      INT NOT NULL,
+    number_of_parallel_exploit_runs INT NOT NULL,
+    PRIMARY KEY(id),
+    FOREIGN KEY(default_policy_id) REFERENCES policies(id),
+    FOREIGN KEY(own_team_id) REFERENCES teams(id) ON DELETE SET NULL,
+    FOREIGN KEY(nop_team_id) REFERENCES teams(id) ON DELETE SET NULL
+);
+
+INSERT INTO settings (
+    flag_regex,
+    tick_length,
+    exploit_timeout,
+    exploit_working_dir,
+    nop_team_grants_points,
+    flag_submission_batch_size,
+    number_of_parallel_exploit_runs
+) VALUES ('[A-Z0-9]{31}=', 60, 30, '.', FALSE, 100, 8);
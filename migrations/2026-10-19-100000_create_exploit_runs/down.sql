@@ -0,0 +1,5 @@
+ALTER TABLE settings DROP COLUMN game_start;
+
+DROP TABLE flag_occurrences;
+DROP TABLE flags;
+DROP TABLE exploit_runs;
@@ -0,0 +1,45 @@
+CREATE TABLE exploit_runs (
+    id          BIGSERIAL NOT NULL,
+    exploit_id  INT NOT NULL,
+    team_id     INT NOT NULL,
+    command     TEXT NOT NULL,
+    tick        INT NOT NULL,
+    start_time  TIMESTAMPTZ NOT NULL,
+    end_time    TIMESTAMPTZ,
+    exit_code   INT,
+    timed_out   BOOLEAN NOT NULL DEFAULT FALSE,
+    killed      BOOLEAN NOT NULL DEFAULT FALSE,
+    PRIMARY KEY(id),
+    FOREIGN KEY(exploit_id) REFERENCES exploits(id),
+    FOREIGN KEY(team_id) REFERENCES teams(id)
+);
+
+CREATE INDEX exploit_runs_exploit_id_idx ON exploit_runs(exploit_id, id);
+CREATE INDEX exploit_runs_team_id_idx ON exploit_runs(team_id, id);
+CREATE INDEX exploit_runs_tick_idx ON exploit_runs(tick);
+
+CREATE TABLE flags (
+    id                BIGSERIAL NOT NULL,
+    flag              TEXT NOT NULL UNIQUE,
+    tick              INT NOT NULL,
+    exploit_run_id    BIGINT,
+    submission_time   TIMESTAMPTZ,
+    submission_result SMALLINT NOT NULL,
+    PRIMARY KEY(id),
+    FOREIGN KEY(exploit_run_id) REFERENCES exploit_runs(id) ON DELETE SET NULL
+);
+
+CREATE INDEX flags_exploit_run_id_idx ON flags(exploit_run_id);
+CREATE INDEX flags_submission_result_idx ON flags(submission_result, id);
+
+CREATE TABLE flag_occurrences (
+    flag_id         BIGINT NOT NULL,
+    exploit_run_id  BIGINT NOT NULL,
+    collection_time TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY(flag_id, exploit_run_id),
+    FOREIGN KEY(flag_id) REFERENCES flags(id) ON DELETE CASCADE,
+    FOREIGN KEY(exploit_run_id) REFERENCES exploit_runs(id) ON DELETE CASCADE
+);
+
+ALTER TABLE settings
+    ADD COLUMN game_start TIMESTAMPTZ NOT NULL DEFAULT now();
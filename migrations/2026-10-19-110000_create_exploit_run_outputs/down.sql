@@ -0,0 +1,5 @@
+ALTER TABLE settings
+    DROP COLUMN run_output_max_bytes,
+    DROP COLUMN run_output_retention;
+
+DROP TABLE exploit_run_outputs;
@@ -0,0 +1,12 @@
+CREATE TABLE exploit_run_outputs (
+    exploit_run_id BIGINT NOT NULL,
+    output         BYTEA NOT NULL,
+    size           INT NOT NULL,
+    truncated      BOOLEAN NOT NULL,
+    PRIMARY KEY(exploit_run_id),
+    FOREIGN KEY(exploit_run_id) REFERENCES exploit_runs(id) ON DELETE CASCADE
+);
+
+ALTER TABLE settings
+    ADD COLUMN run_output_max_bytes INT NOT NULL DEFAULT 65536,
+    ADD COLUMN run_output_retention INT NOT NULL DEFAULT 86400;
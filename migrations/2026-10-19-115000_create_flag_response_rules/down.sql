@@ -0,0 +1 @@
+DROP TABLE flag_response_rules;
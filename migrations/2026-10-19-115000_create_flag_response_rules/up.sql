@@ -0,0 +1,16 @@
+CREATE TABLE flag_response_rules (
+    id                SERIAL NOT NULL,
+    position          INT NOT NULL,
+    pattern           TEXT NOT NULL,
+    submission_result SMALLINT NOT NULL,
+    PRIMARY KEY(id)
+);
+
+-- Valid = 1, AlreadySubmitted = 2, Invalid = 3, Expired = 4, Own = 5, NOPTeam = 6
+INSERT INTO flag_response_rules (position, pattern, submission_result) VALUES
+    (0, '(?i)already|duplicate|resubmit', 2),
+    (1, '(?i)too old|expired', 4),
+    (2, '(?i)your own|own flag', 5),
+    (3, '(?i)nop team', 6),
+    (4, '(?i)invalid|not a flag|unknown flag|no such flag', 3),
+    (5, '(?i)accepted|congrat|^ok', 1);
@@ -0,0 +1,5 @@
+ALTER TABLE settings
+    DROP COLUMN submitter_command,
+    DROP COLUMN flag_submission_interval;
+
+DROP TABLE unknown_flag_responses;
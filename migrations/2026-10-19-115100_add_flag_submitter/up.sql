@@ -0,0 +1,10 @@
+CREATE TABLE unknown_flag_responses (
+    flag_id               BIGINT NOT NULL,
+    raw_submission_result BYTEA NOT NULL,
+    PRIMARY KEY(flag_id),
+    FOREIGN KEY(flag_id) REFERENCES flags(id) ON DELETE CASCADE
+);
+
+ALTER TABLE settings
+    ADD COLUMN submitter_command TEXT,
+    ADD COLUMN flag_submission_interval INT NOT NULL DEFAULT 5;
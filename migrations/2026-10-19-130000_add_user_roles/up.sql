@@ -0,0 +1,5 @@
+ALTER TABLE users
+    ADD COLUMN role SMALLINT NOT NULL DEFAULT 1;
+
+-- Everybody could change everything before.
+UPDATE users SET role = 3;
@@ -0,0 +1,14 @@
+CREATE TABLE audit_log (
+    id          BIGSERIAL PRIMARY KEY,
+    user_id     INT,
+    username    TEXT NOT NULL,
+    action      TEXT NOT NULL,
+    object_type TEXT NOT NULL,
+    object_id   BIGINT,
+    before      JSONB,
+    after       JSONB,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE SET NULL
+);
+
+CREATE INDEX audit_log_object ON audit_log(object_type, object_id);
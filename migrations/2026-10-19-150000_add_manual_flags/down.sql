@@ -0,0 +1,4 @@
+ALTER TABLE flags
+    DROP COLUMN user_id,
+    DROP COLUMN team_id,
+    DROP COLUMN target_challenge;
@@ -0,0 +1,5 @@
+-- Flags pasted by hand instead of found by an exploit run.
+ALTER TABLE flags
+    ADD COLUMN user_id INT REFERENCES users(id) ON DELETE SET NULL,
+    ADD COLUMN team_id INT REFERENCES teams(id) ON DELETE SET NULL,
+    ADD COLUMN target_challenge TEXT;
@@ -0,0 +1,3 @@
+ALTER TABLE settings DROP COLUMN alert_missing_flag_ticks;
+
+DROP TABLE alerts;
@@ -0,0 +1,19 @@
+CREATE TABLE alerts (
+    id              BIGSERIAL PRIMARY KEY,
+    kind            SMALLINT NOT NULL,
+    exploit_id      INT NOT NULL,
+    team_id         INT,
+    tick            INT NOT NULL,
+    message         TEXT NOT NULL,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    acknowledged_by INT,
+    acknowledged_at TIMESTAMPTZ,
+    FOREIGN KEY(exploit_id) REFERENCES exploits(id) ON DELETE CASCADE,
+    FOREIGN KEY(team_id) REFERENCES teams(id) ON DELETE CASCADE,
+    FOREIGN KEY(acknowledged_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+-- Every problem is only reported once, even if a tick is checked again.
+CREATE UNIQUE INDEX alerts_unique_idx ON alerts(kind, exploit_id, COALESCE(team_id, 0), tick);
+
+ALTER TABLE settings ADD COLUMN alert_missing_flag_ticks INT NOT NULL DEFAULT 3;
@@ -0,0 +1,8 @@
+CREATE TABLE webhooks (
+    id            SERIAL PRIMARY KEY,
+    name          TEXT NOT NULL,
+    url           TEXT NOT NULL,
+    body_template TEXT NOT NULL,
+    events        SMALLINT[] NOT NULL DEFAULT '{}'
+);
+
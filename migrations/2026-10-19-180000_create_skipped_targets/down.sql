@@ -0,0 +1,5 @@
+ALTER TABLE settings
+    DROP COLUMN skip_unresponsive_after_ticks,
+    DROP COLUMN skipped_target_retry_ticks;
+
+DROP TABLE skipped_targets;
@@ -0,0 +1,14 @@
+-- Teams which didn't respond to any exploit of a challenge for a while.
+-- They're only attacked every few ticks until a run succeeds again.
+CREATE TABLE skipped_targets (
+    team_id          INT NOT NULL,
+    target_challenge TEXT NOT NULL,
+    tick             INT NOT NULL,
+    created_at       TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    PRIMARY KEY(team_id, target_challenge),
+    FOREIGN KEY(team_id) REFERENCES teams(id) ON DELETE CASCADE
+);
+
+ALTER TABLE settings
+    ADD COLUMN skip_unresponsive_after_ticks INT NOT NULL DEFAULT 3,
+    ADD COLUMN skipped_target_retry_ticks INT NOT NULL DEFAULT 5;
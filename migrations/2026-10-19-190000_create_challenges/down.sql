@@ -0,0 +1,5 @@
+ALTER TABLE skipped_targets DROP CONSTRAINT skipped_targets_target_challenge_fkey;
+ALTER TABLE flags DROP CONSTRAINT flags_target_challenge_fkey;
+ALTER TABLE exploits DROP CONSTRAINT exploits_target_challenge_fkey;
+
+DROP TABLE challenges;
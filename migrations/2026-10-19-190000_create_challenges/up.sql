@@ -0,0 +1,24 @@
+-- The services of the game. Exploits, manual flags and skipped teams reference them by name,
+-- so renaming a challenge updates all of them.
+CREATE TABLE challenges (
+    id          SERIAL PRIMARY KEY,
+    name        TEXT NOT NULL UNIQUE,
+    ports       INT[] NOT NULL DEFAULT '{}',
+    flag_id_key TEXT,
+    disabled    BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+INSERT INTO challenges (name)
+SELECT target_challenge FROM exploits
+UNION SELECT target_challenge FROM flags WHERE target_challenge IS NOT NULL
+UNION SELECT target_challenge FROM skipped_targets;
+
+ALTER TABLE exploits
+    ADD CONSTRAINT exploits_target_challenge_fkey FOREIGN KEY(target_challenge)
+        REFERENCES challenges(name) ON UPDATE CASCADE;
+ALTER TABLE flags
+    ADD CONSTRAINT flags_target_challenge_fkey FOREIGN KEY(target_challenge)
+        REFERENCES challenges(name) ON UPDATE CASCADE ON DELETE SET NULL;
+ALTER TABLE skipped_targets
+    ADD CONSTRAINT skipped_targets_target_challenge_fkey FOREIGN KEY(target_challenge)
+        REFERENCES challenges(name) ON UPDATE CASCADE ON DELETE CASCADE;
@@ -0,0 +1,7 @@
+ALTER TABLE exploit_runs DROP COLUMN version_id;
+
+ALTER TABLE exploits
+    DROP COLUMN source,
+    DROP COLUMN pinned_version_id;
+
+DROP TABLE exploit_versions;
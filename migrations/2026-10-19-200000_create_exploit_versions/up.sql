@@ -0,0 +1,22 @@
+-- Uploaded exploit scripts and archives. Versions are never changed or deleted,
+-- so runs and their flags can always be traced back to the code which produced them.
+CREATE TABLE exploit_versions (
+    id         SERIAL PRIMARY KEY,
+    exploit_id INT NOT NULL,
+    filename   TEXT NOT NULL,
+    sha256     TEXT NOT NULL,
+    size       INT NOT NULL,
+    author     TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    content    BYTEA NOT NULL,
+    FOREIGN KEY(exploit_id) REFERENCES exploits(id)
+);
+
+CREATE INDEX exploit_versions_exploit_id_idx ON exploit_versions(exploit_id);
+
+-- Directory = 1, Upload = 2
+ALTER TABLE exploits
+    ADD COLUMN source SMALLINT NOT NULL DEFAULT 1,
+    ADD COLUMN pinned_version_id INT REFERENCES exploit_versions(id);
+
+ALTER TABLE exploit_runs ADD COLUMN version_id INT REFERENCES exploit_versions(id);
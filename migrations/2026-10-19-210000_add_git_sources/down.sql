@@ -0,0 +1,5 @@
+ALTER TABLE exploit_runs DROP COLUMN commit_hash;
+
+ALTER TABLE exploits
+    DROP COLUMN git_repository,
+    DROP COLUMN git_ref;
@@ -0,0 +1,6 @@
+-- Git = 3: the exploit runs in a worktree of the commit the ref points to.
+ALTER TABLE exploits
+    ADD COLUMN git_repository TEXT,
+    ADD COLUMN git_ref TEXT;
+
+ALTER TABLE exploit_runs ADD COLUMN commit_hash TEXT;
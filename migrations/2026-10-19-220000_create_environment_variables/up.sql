@@ -0,0 +1,15 @@
+-- Environment variables passed to exploit processes.
+-- Variables without an exploit are passed to all exploits.
+CREATE TABLE environment_variables (
+    id         SERIAL PRIMARY KEY,
+    exploit_id INT,
+    name       TEXT NOT NULL,
+    value      TEXT NOT NULL,
+    secret     BOOLEAN NOT NULL DEFAULT FALSE,
+    FOREIGN KEY(exploit_id) REFERENCES exploits(id) ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX environment_variables_global_name_idx
+    ON environment_variables(name) WHERE exploit_id IS NULL;
+CREATE UNIQUE INDEX environment_variables_exploit_name_idx
+    ON environment_variables(exploit_id, name) WHERE exploit_id IS NOT NULL;
@@ -0,0 +1,18 @@
+ALTER TABLE exploit_runs DROP COLUMN limits_hit;
+
+ALTER TABLE exploits
+    DROP COLUMN cpu_time_limit,
+    DROP COLUMN memory_limit,
+    DROP COLUMN process_limit,
+    DROP COLUMN open_files_limit,
+    DROP COLUMN cgroup_memory_max,
+    DROP COLUMN cgroup_cpu_max;
+
+ALTER TABLE settings
+    DROP COLUMN run_cpu_time_limit,
+    DROP COLUMN run_memory_limit,
+    DROP COLUMN run_process_limit,
+    DROP COLUMN run_open_files_limit,
+    DROP COLUMN run_cgroup_path,
+    DROP COLUMN run_cgroup_memory_max,
+    DROP COLUMN run_cgroup_cpu_max;
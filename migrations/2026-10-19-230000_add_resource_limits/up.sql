@@ -0,0 +1,21 @@
+-- Resource limits of exploit processes. 0 means unlimited.
+ALTER TABLE settings
+    ADD COLUMN run_cpu_time_limit INT NOT NULL DEFAULT 0,
+    ADD COLUMN run_memory_limit INT NOT NULL DEFAULT 0,
+    ADD COLUMN run_process_limit INT NOT NULL DEFAULT 0,
+    ADD COLUMN run_open_files_limit INT NOT NULL DEFAULT 0,
+    ADD COLUMN run_cgroup_path TEXT NOT NULL DEFAULT '',
+    ADD COLUMN run_cgroup_memory_max INT NOT NULL DEFAULT 0,
+    ADD COLUMN run_cgroup_cpu_max INT NOT NULL DEFAULT 0;
+
+-- Overrides of the settings for a single exploit. NULL uses the settings.
+ALTER TABLE exploits
+    ADD COLUMN cpu_time_limit INT,
+    ADD COLUMN memory_limit INT,
+    ADD COLUMN process_limit INT,
+    ADD COLUMN open_files_limit INT,
+    ADD COLUMN cgroup_memory_max INT,
+    ADD COLUMN cgroup_cpu_max INT;
+
+-- CpuTime = 1, Memory = 2, Processes = 3, CpuQuota = 4
+ALTER TABLE exploit_runs ADD COLUMN limits_hit SMALLINT[] NOT NULL DEFAULT '{}';
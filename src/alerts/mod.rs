@@ -0,0 +1,271 @@
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+
+use crate::db;
+use crate::exploit;
+use crate::schema::alerts;
+use crate::team;
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::{Pg, PgValue};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::*;
+use serde::{Deserialize, Serialize};
+
+mod monitor;
+pub use monitor::AlertMonitor;
+
+pub const MAX_ALERTS_PER_PAGE: i64 = 500;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = SmallInt)]
+pub enum AlertKind {
+    /// The exploit stopped getting flags from a team it got flags from before.
+    /// The team probably patched the vulnerability.
+    NoFlags,
+    /// All runs of the exploit in a tick failed. The exploit is probably broken.
+    AllRunsFailed,
+}
+
+impl ToSql<SmallInt, Pg> for AlertKind {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        let v = match *self {
+            AlertKind::NoFlags => 1,
+            AlertKind::AllRunsFailed => 2,
+        };
+        <i16 as ToSql<SmallInt, Pg>>::to_sql(&v, &mut out.reborrow())
+    }
+}
+
+impl FromSql<SmallInt, Pg> for AlertKind
+where
+    i16: FromSql<SmallInt, Pg>,
+{
+    fn from_sql(bytes: PgValue) -> deserialize::Result<Self> {
+        let v = i16::from_sql(bytes)?;
+        Ok(match v {
+            1 => AlertKind::NoFlags,
+            2 => AlertKind::AllRunsFailed,
+            id => return Err(format!("invalid alert kind id {}", id).into()),
+        })
+    }
+}
+
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+#[diesel(table_name = alerts)]
+pub struct Alert {
+    pub id: i64,
+    pub kind: AlertKind,
+    pub exploit_id: i32,
+    /// The team the exploit stopped working against. Not set if it failed against all teams.
+    pub team_id: Option<i32>,
+    /// The tick the problem was noticed in.
+    pub tick: i32,
+    pub message: String,
+    pub created_at: DateTime<Utc>,
+    /// The user who took care of the alert.
+    pub acknowledged_by: Option<i32>,
+    pub acknowledged_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = alerts)]
+struct NewAlert {
+    kind: AlertKind,
+    exploit_id: i32,
+    team_id: Option<i32>,
+    tick: i32,
+    message: String,
+}
+
+/// Filter alerts. Alerts are returned newest first.
+#[derive(Deserialize, Default, Debug)]
+pub struct AlertFilter {
+    pub acknowledged: Option<bool>,
+    pub exploit_id: Option<i32>,
+    /// Cursor to continue after. Only alerts with a smaller id are returned.
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(QueryableByName, Debug)]
+struct NoFlagsRow {
+    #[diesel(sql_type = Integer)]
+    exploit_id: i32,
+    #[diesel(sql_type = Integer)]
+    team_id: i32,
+}
+
+#[derive(QueryableByName, Debug)]
+struct AllRunsFailedRow {
+    #[diesel(sql_type = Integer)]
+    exploit_id: i32,
+}
+
+impl Alert {
+    pub fn acknowledge(&mut self, conn: &mut PgConnection, user_id: i32) -> Result<(), db::Error> {
+        let now = Utc::now();
+        diesel::update(&*self)
+            .set((
+                alerts::acknowledged_by.eq(user_id),
+                alerts::acknowledged_at.eq(now),
+            ))
+            .execute(conn)?;
+        self.acknowledged_by = Some(user_id);
+        self.acknowledged_at = Some(now);
+        Ok(())
+    }
+}
+
+/// Exploit and team pairs which got flags in the tick `missing_ticks` before the given one,
+/// but none since. Only pairs still attacked in the given tick are reported,
+/// and every pair only once when the streak of ticks without flags gets long enough.
+fn find_missing_flags(
+    conn: &mut PgConnection,
+    tick: i32,
+    missing_ticks: i32,
+) -> Result<Vec<NoFlagsRow>, db::Error> {
+    Ok(diesel::sql_query(
+        "SELECT exploit_runs.exploit_id, exploit_runs.team_id
+        FROM exploit_runs
+        WHERE exploit_runs.tick = $1
+        GROUP BY exploit_runs.exploit_id, exploit_runs.team_id
+        HAVING EXISTS (
+            SELECT 1 FROM flag_occurrences
+            INNER JOIN exploit_runs AS earlier ON earlier.id = flag_occurrences.exploit_run_id
+            WHERE earlier.exploit_id = exploit_runs.exploit_id
+                AND earlier.team_id = exploit_runs.team_id
+                AND earlier.tick = $1 - $2
+        ) AND NOT EXISTS (
+            SELECT 1 FROM flag_occurrences
+            INNER JOIN exploit_runs AS later ON later.id = flag_occurrences.exploit_run_id
+            WHERE later.exploit_id = exploit_runs.exploit_id
+                AND later.team_id = exploit_runs.team_id
+                AND later.tick > $1 - $2 AND later.tick <= $1
+        )",
+    )
+    .bind::<Integer, _>(tick)
+    .bind::<Integer, _>(missing_ticks)
+    .load::<NoFlagsRow>(conn)?)
+}
+
+/// Exploits of which all finished runs in the given tick failed.
+/// Only reported if at least one run succeeded in the tick before, so every failure is reported once.
+fn find_failed_exploits(
+    conn: &mut PgConnection,
+    tick: i32,
+) -> Result<Vec<AllRunsFailedRow>, db::Error> {
+    Ok(diesel::sql_query(
+        "SELECT exploit_runs.exploit_id
+        FROM exploit_runs
+        WHERE exploit_runs.tick = $1 AND exploit_runs.end_time IS NOT NULL
+        GROUP BY exploit_runs.exploit_id
+        HAVING NOT bool_or(COALESCE(exploit_runs.exit_code = 0, FALSE)
+                AND NOT exploit_runs.timed_out AND NOT exploit_runs.killed)
+            AND (
+                SELECT COALESCE(bool_or(COALESCE(earlier.exit_code = 0, FALSE)
+                    AND NOT earlier.timed_out AND NOT earlier.killed), TRUE)
+                FROM exploit_runs AS earlier
+                WHERE earlier.exploit_id = exploit_runs.exploit_id AND earlier.tick = $1 - 1
+            )",
+    )
+    .bind::<Integer, _>(tick)
+    .load::<AllRunsFailedRow>(conn)?)
+}
+
+fn exploit_name(conn: &mut PgConnection, exploit_id: i32) -> Result<String, db::Error> {
+    Ok(match exploit::find_exploit_by_id(conn, exploit_id)? {
+        Some(exploit) => format!("Exploit {} ({})", exploit.id, exploit.vuln_title),
+        None => format!("Exploit {exploit_id}"),
+    })
+}
+
+fn team_name(conn: &mut PgConnection, team_id: i32) -> Result<String, db::Error> {
+    Ok(match team::find_team_by_id(conn, team_id)? {
+        Some(team::Team {
+            name: Some(name), ..
+        }) => format!("team {team_id} ({name})"),
+        _ => format!("team {team_id}"),
+    })
+}
+
+/// Look for exploits which stopped working in the given tick and store alerts for them.
+/// Returns the new alerts.
+pub fn check_tick(
+    conn: &mut PgConnection,
+    tick: i32,
+    missing_ticks: i32,
+) -> Result<Vec<Alert>, db::Error> {
+    let mut new_alerts = Vec::new();
+    if missing_ticks > 0 {
+        for row in find_missing_flags(conn, tick, missing_ticks)? {
+            let message = format!(
+                "{} got no flags from {} for {} ticks",
+                exploit_name(conn, row.exploit_id)?,
+                team_name(conn, row.team_id)?,
+                missing_ticks
+            );
+            new_alerts.push(NewAlert {
+                kind: AlertKind::NoFlags,
+                exploit_id: row.exploit_id,
+                team_id: Some(row.team_id),
+                tick,
+                message,
+            });
+        }
+    }
+    for row in find_failed_exploits(conn, tick)? {
+        let message = format!(
+            "{} failed against all teams in tick {}",
+            exploit_name(conn, row.exploit_id)?,
+            tick
+        );
+        new_alerts.push(NewAlert {
+            kind: AlertKind::AllRunsFailed,
+            exploit_id: row.exploit_id,
+            team_id: None,
+            tick,
+            message,
+        });
+    }
+
+    if new_alerts.is_empty() {
+        return Ok(Vec::new());
+    }
+    Ok(diesel::insert_into(alerts::table)
+        .values(&new_alerts)
+        .on_conflict_do_nothing()
+        .get_results::<Alert>(conn)?)
+}
+
+pub fn find_alert_by_id(
+    conn: &mut PgConnection,
+    alert_id: i64,
+) -> Result<Option<Alert>, db::Error> {
+    Ok(alerts::table
+        .find(alert_id)
+        .first::<Alert>(conn)
+        .optional()?)
+}
+
+pub fn get_alerts(conn: &mut PgConnection, filter: &AlertFilter) -> Result<Vec<Alert>, db::Error> {
+    let mut query = alerts::table
+        .order(alerts::id.desc())
+        .limit(filter.limit.unwrap_or(50).clamp(1, MAX_ALERTS_PER_PAGE))
+        .into_boxed();
+
+    if let Some(acknowledged) = filter.acknowledged {
+        if acknowledged {
+            query = query.filter(alerts::acknowledged_at.is_not_null());
+        } else {
+            query = query.filter(alerts::acknowledged_at.is_null());
+        }
+    }
+    if let Some(exploit_id) = filter.exploit_id {
+        query = query.filter(alerts::exploit_id.eq(exploit_id));
+    }
+    if let Some(before) = filter.before {
+        query = query.filter(alerts::id.lt(before));
+    }
+
+    Ok(query.load::<Alert>(conn)?)
+}
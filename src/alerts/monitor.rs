@@ -0,0 +1,74 @@
+use std::sync::Arc;
+
+use actix::prelude::*;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+
+use super::Alert;
+use crate::db;
+use crate::events::{self, Event, EventSender};
+use crate::settings;
+use crate::DbPool;
+
+/// Checks whether exploits stopped working whenever a new tick starts.
+pub struct AlertMonitor {
+    pool: DbPool,
+    events: EventSender,
+}
+
+impl AlertMonitor {
+    pub fn new(pool: DbPool, events: EventSender) -> Self {
+        Self { pool, events }
+    }
+}
+
+impl Actor for AlertMonitor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.add_stream(BroadcastStream::new(self.events.subscribe()));
+    }
+}
+
+impl StreamHandler<Result<Arc<Event>, BroadcastStreamRecvError>> for AlertMonitor {
+    fn handle(
+        &mut self,
+        event: Result<Arc<Event>, BroadcastStreamRecvError>,
+        ctx: &mut Self::Context,
+    ) {
+        let tick = match event.as_deref() {
+            Ok(Event::TickChanged(tick)) => *tick,
+            Ok(_) => return,
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                log::warn!("Alert monitor missed {} events", skipped);
+                return;
+            }
+        };
+
+        let pool = self.pool.clone();
+        let events = self.events.clone();
+        ctx.spawn(
+            async move {
+                // Runs started at the end of the previous tick might still be running.
+                let result = tokio::task::spawn_blocking(move || check_tick(&pool, tick - 2)).await;
+                match result {
+                    Ok(Ok(alerts)) => {
+                        for alert in alerts {
+                            log::warn!("{}", alert.message);
+                            events::publish(&events, Event::AlertRaised(alert));
+                        }
+                    }
+                    Ok(Err(err)) => log::error!("Failed to check for alerts: {}", err),
+                    Err(err) => log::error!("Failed to check for alerts: {}", err),
+                }
+            }
+            .into_actor(self),
+        );
+    }
+}
+
+fn check_tick(pool: &DbPool, tick: i32) -> Result<Vec<Alert>, db::Error> {
+    let conn = &mut pool.get()?;
+    let settings = settings::get_settings(conn)?;
+    super::check_tick(conn, tick, settings.alert_missing_flag_ticks)
+}
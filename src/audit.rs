@@ -0,0 +1,138 @@
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::db;
+use crate::schema::audit_log;
+use crate::user::User;
+
+pub const MAX_ENTRIES_PER_PAGE: i64 = 500;
+
+/// Who changed what and when.
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+#[diesel(table_name = audit_log)]
+pub struct AuditEntry {
+    pub id: i64,
+    /// Not set anymore if the user was deleted since.
+    pub user_id: Option<i32>,
+    /// Name of the user at the time of the change.
+    pub username: String,
+    /// Name of the API operation like `update_exploit`.
+    pub action: String,
+    /// Kind of object that was changed like `exploit`.
+    pub object_type: String,
+    pub object_id: Option<i64>,
+    /// The object before the change. Not set if it was created.
+    pub before: Option<Value>,
+    /// The object after the change. Not set if it was deleted.
+    pub after: Option<Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = audit_log)]
+struct NewAuditEntry<'a> {
+    user_id: Option<i32>,
+    username: &'a str,
+    action: &'a str,
+    object_type: &'a str,
+    object_id: Option<i64>,
+    before: Option<Value>,
+    after: Option<Value>,
+}
+
+/// A change about to be recorded in the audit log.
+#[derive(Debug)]
+pub struct Change {
+    action: &'static str,
+    object_type: &'static str,
+    object_id: Option<i64>,
+    before: Option<Value>,
+    after: Option<Value>,
+}
+
+impl Change {
+    pub fn new(action: &'static str, object_type: &'static str, object_id: Option<i64>) -> Self {
+        Self {
+            action,
+            object_type,
+            object_id,
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn before<T: Serialize>(mut self, before: &T) -> Self {
+        self.before = serde_json::to_value(before).ok();
+        self
+    }
+
+    pub fn after<T: Serialize>(mut self, after: &T) -> Self {
+        self.after = serde_json::to_value(after).ok();
+        self
+    }
+}
+
+/// Filter audit entries. Entries are returned newest first.
+#[derive(Deserialize, Default, Debug)]
+pub struct AuditFilter {
+    pub user_id: Option<i32>,
+    pub action: Option<String>,
+    pub object_type: Option<String>,
+    pub object_id: Option<i64>,
+    /// Only entries at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Cursor to continue after. Only entries with a smaller id are returned.
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+pub fn add_entry(
+    conn: &mut PgConnection,
+    user: &User,
+    change: Change,
+) -> Result<AuditEntry, db::Error> {
+    Ok(diesel::insert_into(audit_log::table)
+        .values(&NewAuditEntry {
+            user_id: Some(user.id),
+            username: &user.username,
+            action: change.action,
+            object_type: change.object_type,
+            object_id: change.object_id,
+            before: change.before,
+            after: change.after,
+        })
+        .get_result(conn)?)
+}
+
+pub fn get_entries(
+    conn: &mut PgConnection,
+    filter: &AuditFilter,
+) -> Result<Vec<AuditEntry>, db::Error> {
+    let mut query = audit_log::table
+        .order(audit_log::id.desc())
+        .limit(filter.limit.unwrap_or(50).clamp(1, MAX_ENTRIES_PER_PAGE))
+        .into_boxed();
+
+    if let Some(user_id) = filter.user_id {
+        query = query.filter(audit_log::user_id.eq(user_id));
+    }
+    if let Some(action) = &filter.action {
+        query = query.filter(audit_log::action.eq(action.clone()));
+    }
+    if let Some(object_type) = &filter.object_type {
+        query = query.filter(audit_log::object_type.eq(object_type.clone()));
+    }
+    if let Some(object_id) = filter.object_id {
+        query = query.filter(audit_log::object_id.eq(object_id));
+    }
+    if let Some(since) = filter.since {
+        query = query.filter(audit_log::created_at.ge(since));
+    }
+    if let Some(before) = filter.before {
+        query = query.filter(audit_log::id.lt(before));
+    }
+
+    Ok(query.load::<AuditEntry>(conn)?)
+}
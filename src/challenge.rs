@@ -0,0 +1,112 @@
+use diesel::prelude::*;
+
+use crate::db;
+use crate::schema::{challenges, exploits};
+use serde::{Deserialize, Serialize};
+
+/// A service of the game which can be attacked.
+/// Exploits reference it by name in `target_challenge`.
+#[derive(Identifiable, Queryable, AsChangeset, Serialize, Deserialize, Clone, Debug)]
+#[diesel(table_name = challenges)]
+pub struct Challenge {
+    pub id: i32,
+    pub name: String,
+    /// Ports the service listens on. The first one is `{service.port}` in templates.
+    pub ports: Vec<i32>,
+    /// Key of the service in the flag ids published by the organizers.
+    pub flag_id_key: Option<String>,
+    /// Don't run any exploits against the service, e.g. while the organizers took it offline.
+    pub disabled: bool,
+}
+
+#[derive(Insertable, AsChangeset, Deserialize, Debug)]
+#[diesel(table_name = challenges)]
+pub struct NewChallenge {
+    pub name: String,
+    #[serde(default)]
+    pub ports: Vec<i32>,
+    pub flag_id_key: Option<String>,
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl Challenge {
+    pub fn save(&self, conn: &mut PgConnection) -> Result<(), db::Error> {
+        diesel::update(self).set(self).execute(conn)?;
+        Ok(())
+    }
+
+    /// Exploits attacking this service.
+    pub fn get_exploit_ids(&self, conn: &mut PgConnection) -> Result<Vec<i32>, db::Error> {
+        Ok(exploits::table
+            .filter(exploits::target_challenge.eq(&self.name))
+            .select(exploits::id)
+            .order(exploits::id)
+            .load::<i32>(conn)?)
+    }
+
+    /// Values of the `{service.*}` template placeholders.
+    pub fn template_variables(&self) -> Vec<(String, String)> {
+        let mut variables = vec![
+            ("service.id".to_string(), self.id.to_string()),
+            ("service.name".to_string(), self.name.clone()),
+            (
+                "service.ports".to_string(),
+                self.ports
+                    .iter()
+                    .map(i32::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+        ];
+        if let Some(port) = self.ports.first() {
+            variables.push(("service.port".to_string(), port.to_string()));
+        }
+        if let Some(flag_id_key) = &self.flag_id_key {
+            variables.push(("service.flag_id_key".to_string(), flag_id_key.clone()));
+        }
+        variables
+    }
+}
+
+pub fn find_challenge_by_id(
+    conn: &mut PgConnection,
+    challenge_id: i32,
+) -> Result<Option<Challenge>, db::Error> {
+    Ok(challenges::table
+        .find(challenge_id)
+        .first::<Challenge>(conn)
+        .optional()?)
+}
+
+pub fn find_challenge_by_name(
+    conn: &mut PgConnection,
+    name: &str,
+) -> Result<Option<Challenge>, db::Error> {
+    Ok(challenges::table
+        .filter(challenges::name.eq(name))
+        .first::<Challenge>(conn)
+        .optional()?)
+}
+
+pub fn get_challenges(conn: &mut PgConnection) -> Result<Vec<Challenge>, db::Error> {
+    Ok(challenges::table
+        .order(challenges::name)
+        .load::<Challenge>(conn)?)
+}
+
+pub fn add_challenge(
+    conn: &mut PgConnection,
+    challenge: NewChallenge,
+) -> Result<Challenge, db::Error> {
+    Ok(diesel::insert_into(challenges::table)
+        .values(&challenge)
+        .get_result(conn)?)
+}
+
+/// Delete the challenge. Flags added by hand for it lose their challenge,
+/// teams skipped for it are attacked normally again.
+pub fn delete_challenge(conn: &mut PgConnection, challenge: Challenge) -> Result<(), db::Error> {
+    diesel::delete(&challenge).execute(conn)?;
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use diesel::prelude::*;
+
+use crate::db;
+use crate::schema::environment_variables;
+use serde::{Deserialize, Serialize};
+
+/// Shown instead of the value of secret variables.
+pub const MASKED_VALUE: &str = "********";
+
+/// Variables anthill sets for every run. They can't be configured.
+pub const AUTOMATIC_VARIABLES: &[&str] = &["TARGET_IP", "TARGET_TEAM_ID", "TICK", "FLAG_REGEX"];
+
+/// An environment variable passed to exploit processes,
+/// either to all of them or only to the runs of one exploit.
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+#[diesel(table_name = environment_variables)]
+pub struct EnvironmentVariable {
+    pub id: i32,
+    /// Only passed to this exploit. Passed to all exploits if not set.
+    pub exploit_id: Option<i32>,
+    pub name: String,
+    pub value: String,
+    /// The value is masked in the API, the audit log and the output of runs.
+    pub secret: bool,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewEnvironmentVariable {
+    pub value: String,
+    #[serde(default)]
+    pub secret: bool,
+}
+
+impl EnvironmentVariable {
+    /// Copy of the variable with the value hidden if it's a secret.
+    pub fn masked(&self) -> Self {
+        let mut variable = self.clone();
+        if variable.secret {
+            variable.value = MASKED_VALUE.to_string();
+        }
+        variable
+    }
+}
+
+/// Names must be usable in shell scripts and must not shadow the automatic variables.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("Invalid environment variable name {name:?}"));
+    }
+    if AUTOMATIC_VARIABLES.contains(&name) {
+        return Err(format!("{name} is set by anthill automatically"));
+    }
+    Ok(())
+}
+
+/// Variables of all exploits (`Some(exploit_id)`) or the global ones (`None`).
+pub fn get_variables(
+    conn: &mut PgConnection,
+    exploit_id: Option<i32>,
+) -> Result<Vec<EnvironmentVariable>, db::Error> {
+    let query = environment_variables::table
+        .order(environment_variables::name)
+        .into_boxed();
+    let query = match exploit_id {
+        Some(exploit_id) => query.filter(environment_variables::exploit_id.eq(exploit_id)),
+        None => query.filter(environment_variables::exploit_id.is_null()),
+    };
+    Ok(query.load::<EnvironmentVariable>(conn)?)
+}
+
+/// Global and exploit specific variables at once.
+pub fn get_all_variables(conn: &mut PgConnection) -> Result<Vec<EnvironmentVariable>, db::Error> {
+    Ok(environment_variables::table
+        .order(environment_variables::id)
+        .load::<EnvironmentVariable>(conn)?)
+}
+
+/// Add or replace the variable with the given name.
+pub fn set_variable(
+    conn: &mut PgConnection,
+    exploit_id: Option<i32>,
+    name: String,
+    variable: NewEnvironmentVariable,
+) -> Result<EnvironmentVariable, db::Error> {
+    conn.transaction(|conn| {
+        let existing = get_variables(conn, exploit_id)?
+            .into_iter()
+            .find(|existing| existing.name == name);
+        Ok(match existing {
+            Some(existing) => diesel::update(&existing)
+                .set((
+                    environment_variables::value.eq(variable.value),
+                    environment_variables::secret.eq(variable.secret),
+                ))
+                .get_result(conn)?,
+            None => diesel::insert_into(environment_variables::table)
+                .values((
+                    environment_variables::exploit_id.eq(exploit_id),
+                    environment_variables::name.eq(name),
+                    environment_variables::value.eq(variable.value),
+                    environment_variables::secret.eq(variable.secret),
+                ))
+                .get_result(conn)?,
+        })
+    })
+}
+
+pub fn delete_variable(
+    conn: &mut PgConnection,
+    variable: &EnvironmentVariable,
+) -> Result<(), db::Error> {
+    diesel::delete(variable).execute(conn)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_name_accepts_shell_names() {
+        for name in ["TOKEN", "_private", "api_key_2", "a"] {
+            assert_eq!(validate_name(name), Ok(()), "{name}");
+        }
+    }
+
+    #[test]
+    fn validate_name_rejects_invalid_names() {
+        for name in ["", "2FA", "API-KEY", "with space", "A=B", "ÄPI"] {
+            assert!(validate_name(name).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn validate_name_rejects_automatic_variables() {
+        for name in AUTOMATIC_VARIABLES {
+            assert_eq!(
+                validate_name(name),
+                Err(format!("{name} is set by anthill automatically"))
+            );
+        }
+        assert_eq!(validate_name("target_ip"), Ok(()));
+    }
+}
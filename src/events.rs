@@ -0,0 +1,109 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::alerts::Alert;
+use crate::audit::AuditEntry;
+use crate::challenge::Challenge;
+use crate::exploit::ExploitRun;
+use crate::flag_submitter::{Flag, FlagSubmissionResult};
+use crate::settings::Settings;
+use crate::team::{SkippedTarget, Team};
+
+/// Number of events buffered for subscribers before the slowest one starts missing events.
+const EVENT_CAPACITY: usize = 1024;
+
+/// Notifies all interested parties like websocket clients about changes.
+pub type EventSender = broadcast::Sender<Arc<Event>>;
+
+pub fn event_channel() -> EventSender {
+    broadcast::channel(EVENT_CAPACITY).0
+}
+
+/// Publish an event. Nobody listening is fine.
+pub fn publish(events: &EventSender, event: Event) {
+    let _ = events.send(Arc::new(event));
+}
+
+/// Group of events clients can subscribe to.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    Runs,
+    Flags,
+    Submissions,
+    Teams,
+    Challenges,
+    Settings,
+    Ticks,
+    Audit,
+    Alerts,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct FlagSubmission {
+    pub flag_id: i64,
+    pub flag: String,
+    pub submission_result: FlagSubmissionResult,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    /// An exploit process was started.
+    RunStarted(ExploitRun),
+    /// An exploit process stopped.
+    RunFinished(ExploitRun),
+    /// An exploit run found flags nobody saw before.
+    FlagsFound {
+        exploit_run_id: i64,
+        flags: Vec<Flag>,
+    },
+    /// Somebody added flags by hand.
+    FlagsAdded {
+        user_id: i32,
+        flags: Vec<Flag>,
+    },
+    /// The submission server answered for a batch of flags.
+    FlagsSubmitted(Vec<FlagSubmission>),
+    /// The submitter command failed to submit a batch of flags.
+    SubmitterFailed {
+        error: String,
+    },
+    /// A team was added or changed.
+    TeamChanged(Team),
+    /// All exploits of a challenge failed against the team for a while.
+    /// It's attacked less often from now on.
+    TargetSkipped(SkippedTarget),
+    /// A run against a skipped team succeeded again.
+    TargetRestored(SkippedTarget),
+    /// A challenge was added or changed.
+    ChallengeChanged(Challenge),
+    SettingsChanged(Settings),
+    /// A new tick started.
+    TickChanged(i32),
+    /// Somebody changed something through the API.
+    AuditLogged(AuditEntry),
+    /// An exploit probably stopped working.
+    AlertRaised(Alert),
+    AlertAcknowledged(Alert),
+}
+
+impl Event {
+    pub fn topic(&self) -> Topic {
+        match self {
+            Event::RunStarted(_) | Event::RunFinished(_) => Topic::Runs,
+            Event::FlagsFound { .. } | Event::FlagsAdded { .. } => Topic::Flags,
+            Event::FlagsSubmitted(_) | Event::SubmitterFailed { .. } => Topic::Submissions,
+            Event::TeamChanged(_) | Event::TargetSkipped(_) | Event::TargetRestored(_) => {
+                Topic::Teams
+            }
+            Event::ChallengeChanged(_) => Topic::Challenges,
+            Event::SettingsChanged(_) => Topic::Settings,
+            Event::TickChanged(_) => Topic::Ticks,
+            Event::AuditLogged(_) => Topic::Audit,
+            Event::AlertRaised(_) | Event::AlertAcknowledged(_) => Topic::Alerts,
+        }
+    }
+}
@@ -1,24 +1,62 @@
-use std::time::{Instant, Duration};
-use std::path::Path;
+use chrono::{DateTime, Utc};
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use std::collections::HashMap;
-use crate::team::Team;
-use crate::flag_submitter::FlagOccurence;
+use std::io::{Read, Write};
+use std::time::Duration;
 
-#[derive(Debug)]
+use crate::db;
+use crate::schema::{
+    exploit_key_values, exploit_run_outputs, exploit_runs, exploit_target_teams, exploit_versions,
+    exploits, flags, policies,
+};
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::{Pg, PgValue};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Identifiable, Queryable, AsChangeset, Serialize, Deserialize, Eq, PartialEq, Debug)]
+#[diesel(table_name = policies)]
 pub struct Policy {
+    pub id: i32,
     /// Description of the settings.
-    name: String,
+    pub name: String,
     /// The template pattern containing the full command and arguments to start the exploit.
-    argv_pattern: String,
-    /// Time after which the exploit should be run again.
-    repeat_interval: Duration,
+    pub argv_pattern: String,
+    /// Time in seconds after which the exploit should be run again.
+    pub repeat_interval: i32,
     /// Don't run the exploit.
-    disabled: bool,
+    pub disabled: bool,
+}
+
+#[derive(Insertable, AsChangeset, Deserialize, Debug)]
+#[diesel(table_name = policies)]
+pub struct NewPolicy {
+    pub name: String,
+    pub argv_pattern: String,
+    pub repeat_interval: i32,
+    pub disabled: bool,
+}
+
+/// Everything referencing a policy. A policy can only be deleted while this is empty.
+#[derive(Serialize, Debug)]
+pub struct PolicyUsage {
+    /// Exploits using the policy for all teams without an override.
+    pub exploits: Vec<i32>,
+    /// Team specific overrides of exploits using the policy.
+    pub targets: Vec<ExploitTarget>,
+    /// Is the policy pre-selected when creating a new exploit?
+    pub default_policy: bool,
 }
 
 /// How to handle situations of the previous run still going while the next one should be started.
-#[derive(Debug)]
-enum OverrunPolicy {
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = SmallInt)]
+pub enum OverrunPolicy {
     /// Stop old exploit run before starting a new run.
     StopOld,
     /// Only keep the still running instance and don't start a new run.
@@ -27,47 +65,794 @@ enum OverrunPolicy {
     KeepOldAndStartNew,
 }
 
-#[derive(Debug)]
-pub struct Exploit<'c> {
+impl ToSql<SmallInt, Pg> for OverrunPolicy {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        let v = match *self {
+            OverrunPolicy::StopOld => 1,
+            OverrunPolicy::KeepOldOnly => 2,
+            OverrunPolicy::KeepOldAndStartNew => 3,
+        };
+        <i16 as ToSql<SmallInt, Pg>>::to_sql(&v, &mut out.reborrow())
+    }
+}
+
+impl FromSql<SmallInt, Pg> for OverrunPolicy
+where
+    i16: FromSql<SmallInt, Pg>,
+{
+    fn from_sql(bytes: PgValue) -> deserialize::Result<Self> {
+        let v = i16::from_sql(bytes)?;
+        Ok(match v {
+            1 => OverrunPolicy::StopOld,
+            2 => OverrunPolicy::KeepOldOnly,
+            3 => OverrunPolicy::KeepOldAndStartNew,
+            id => return Err(format!("invalid overrun policy id {}", id).into()),
+        })
+    }
+}
+
+/// Where the code of an exploit comes from.
+#[derive(
+    PartialEq, Eq, Copy, Clone, Debug, Default, Serialize, Deserialize, AsExpression, FromSqlRow,
+)]
+#[diesel(sql_type = SmallInt)]
+pub enum ExploitSource {
+    /// The command is run in the working directory on the farm host.
+    #[default]
+    Directory,
+    /// The command is run in the extracted uploaded version.
+    Upload,
+    /// The command is run in a worktree of the commit a git ref points to.
+    Git,
+}
+
+impl ToSql<SmallInt, Pg> for ExploitSource {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        let v = match *self {
+            ExploitSource::Directory => 1,
+            ExploitSource::Upload => 2,
+            ExploitSource::Git => 3,
+        };
+        <i16 as ToSql<SmallInt, Pg>>::to_sql(&v, &mut out.reborrow())
+    }
+}
+
+impl FromSql<SmallInt, Pg> for ExploitSource
+where
+    i16: FromSql<SmallInt, Pg>,
+{
+    fn from_sql(bytes: PgValue) -> deserialize::Result<Self> {
+        let v = i16::from_sql(bytes)?;
+        Ok(match v {
+            1 => ExploitSource::Directory,
+            2 => ExploitSource::Upload,
+            3 => ExploitSource::Git,
+            id => return Err(format!("invalid exploit source id {}", id).into()),
+        })
+    }
+}
+
+/// A resource limit an exploit run ran into.
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = SmallInt)]
+pub enum ResourceLimit {
+    /// The process used up its CPU time and was killed with SIGXCPU,
+    /// or with SIGKILL one second later if it handled SIGXCPU.
+    CpuTime,
+    /// The processes of the run hit the memory maximum of the cgroup.
+    Memory,
+    /// Starting another process failed because of the process limit of the cgroup,
+    /// or the process failed with an error message of the process rlimit.
+    Processes,
+    /// The processes of the run were throttled to the CPU maximum of the cgroup.
+    CpuQuota,
+    /// The process failed with an error message of the address space rlimit.
+    AddressSpace,
+    /// The process failed with an error message of the open files rlimit.
+    OpenFiles,
+}
+
+impl ToSql<SmallInt, Pg> for ResourceLimit {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        let v = match *self {
+            ResourceLimit::CpuTime => 1,
+            ResourceLimit::Memory => 2,
+            ResourceLimit::Processes => 3,
+            ResourceLimit::CpuQuota => 4,
+            ResourceLimit::AddressSpace => 5,
+            ResourceLimit::OpenFiles => 6,
+        };
+        <i16 as ToSql<SmallInt, Pg>>::to_sql(&v, &mut out.reborrow())
+    }
+}
+
+impl FromSql<SmallInt, Pg> for ResourceLimit
+where
+    i16: FromSql<SmallInt, Pg>,
+{
+    fn from_sql(bytes: PgValue) -> deserialize::Result<Self> {
+        let v = i16::from_sql(bytes)?;
+        Ok(match v {
+            1 => ResourceLimit::CpuTime,
+            2 => ResourceLimit::Memory,
+            3 => ResourceLimit::Processes,
+            4 => ResourceLimit::CpuQuota,
+            5 => ResourceLimit::AddressSpace,
+            6 => ResourceLimit::OpenFiles,
+            id => return Err(format!("invalid resource limit id {}", id).into()),
+        })
+    }
+}
+
+#[derive(
+    Identifiable, Queryable, AsChangeset, Associations, Serialize, Deserialize, Eq, PartialEq, Debug,
+)]
+#[diesel(table_name = exploits)]
+#[diesel(belongs_to(Policy))]
+#[diesel(treat_none_as_null = true)]
+pub struct Exploit {
+    pub id: i32,
     /// Command to execute pointing to the exploit script. Expands template patterns.
-    command: String,
-    /// Specify which teams to attack in which way
-    // Map<Team, Policy>
-    target_teams: HashMap<&'c Team, &'c Policy>,
+    pub command: String,
     /// Author of the exploit script to contact on problems.
-    author: String,
+    pub author: String,
     /// Short description of the exploited vulnerability to distinguish between multiple exploits for the same challenge.
-    vuln_title: String,
+    pub vuln_title: String,
     /// Challenge name to group exploits.
-    target_challenge: String,
+    pub target_challenge: String,
     /// Exploit policy to apply by default to all active teams.
-    policy: &'c Policy,
-    /// Timeout after which the process is killed if it's running too long.
-    script_timeout: Duration,
-    /// Custom meta key/values which can be accessed in the template patterns.
-    meta: HashMap<String, String>,
+    pub policy_id: i32,
+    /// Timeout in seconds after which the process is killed if it's running too long.
+    pub script_timeout: i32,
     /// How to handle situations of the previous run still going while the next one should be started.
-    overrun_policy: OverrunPolicy,
+    pub overrun_policy: OverrunPolicy,
     /// Set as the current working directory when starting the exploit script.
-    working_directory: &'c Path,
+    pub working_directory: String,
     /// Exploits are never hard deleted, only disabled to preserve history.
-    disabled: bool,
+    pub disabled: bool,
+    /// Run the command in the working directory or in an uploaded version.
+    pub source: ExploitSource,
+    /// Uploaded version to run. The latest version is run if not set.
+    pub pinned_version_id: Option<i32>,
+    /// Path of a local git repository holding the exploit.
+    pub git_repository: Option<String>,
+    /// Branch, tag or commit in the repository to run.
+    pub git_ref: Option<String>,
+    /// Overrides `run_cpu_time_limit` of the settings.
+    pub cpu_time_limit: Option<i32>,
+    /// Overrides `run_memory_limit` of the settings.
+    pub memory_limit: Option<i32>,
+    /// Overrides `run_process_limit` of the settings.
+    pub process_limit: Option<i32>,
+    /// Overrides `run_open_files_limit` of the settings.
+    pub open_files_limit: Option<i32>,
+    /// Overrides `run_cgroup_memory_max` of the settings.
+    pub cgroup_memory_max: Option<i32>,
+    /// Overrides `run_cgroup_cpu_max` of the settings.
+    pub cgroup_cpu_max: Option<i32>,
+}
+
+#[derive(Insertable, AsChangeset, Deserialize, Debug)]
+#[diesel(table_name = exploits)]
+pub struct NewExploit {
+    pub command: String,
+    pub author: String,
+    pub vuln_title: String,
+    pub target_challenge: String,
+    pub policy_id: i32,
+    pub script_timeout: i32,
+    pub overrun_policy: OverrunPolicy,
+    pub working_directory: String,
+    pub disabled: bool,
+    #[serde(default)]
+    pub source: ExploitSource,
+    #[serde(default)]
+    pub pinned_version_id: Option<i32>,
+    #[serde(default)]
+    pub git_repository: Option<String>,
+    #[serde(default)]
+    pub git_ref: Option<String>,
+    #[serde(default)]
+    pub cpu_time_limit: Option<i32>,
+    #[serde(default)]
+    pub memory_limit: Option<i32>,
+    #[serde(default)]
+    pub process_limit: Option<i32>,
+    #[serde(default)]
+    pub open_files_limit: Option<i32>,
+    #[serde(default)]
+    pub cgroup_memory_max: Option<i32>,
+    #[serde(default)]
+    pub cgroup_cpu_max: Option<i32>,
+}
+
+/// Specify which teams to attack in which way.
+/// Overrides the default policy of the exploit for a single team.
+#[derive(
+    Identifiable,
+    Insertable,
+    Queryable,
+    AsChangeset,
+    Associations,
+    Serialize,
+    Deserialize,
+    Eq,
+    PartialEq,
+    Debug,
+)]
+#[diesel(table_name = exploit_target_teams)]
+#[diesel(primary_key(exploit_id, team_id))]
+#[diesel(belongs_to(Exploit))]
+pub struct ExploitTarget {
+    pub exploit_id: i32,
+    pub team_id: i32,
+    pub policy_id: i32,
 }
 
-#[derive(Debug)]
-pub struct ExploitRun<'c> {
+/// Custom meta key/values which can be accessed in the template patterns.
+#[derive(
+    Identifiable, Insertable, Queryable, AsChangeset, Associations, Serialize, Eq, PartialEq, Debug,
+)]
+#[diesel(table_name = exploit_key_values)]
+#[diesel(primary_key(exploit_id, key))]
+#[diesel(belongs_to(Exploit))]
+pub struct ExploitMeta {
+    pub exploit_id: i32,
+    pub key: String,
+    pub value: String,
+}
+
+#[derive(Identifiable, Queryable, Associations, Serialize, Clone, Debug)]
+#[diesel(table_name = exploit_runs)]
+#[diesel(belongs_to(Exploit))]
+pub struct ExploitRun {
+    pub id: i64,
     /// The exploit that was run.
-    exploit: &'c Exploit<'c>,
+    pub exploit_id: i32,
     /// The targeted team.
-    team: &'c Team,
+    pub team_id: i32,
     /// Expanded exploit commandline that was executed.
-    command: String,
+    pub command: String,
+    /// Tick in which the exploit was started.
+    pub tick: i32,
     /// Time when the exploit process was started.
-    starttime: Instant,
+    pub start_time: DateTime<Utc>,
     /// Time when the exploit process stopped.
-    endtime: Instant,
-    /// List of all flags extracted from the output of this run.
-    found_flags: [&'c FlagOccurence<'c>],
+    pub end_time: Option<DateTime<Utc>>,
+    /// Exit code of the process. Not set if it was killed by a signal or failed to start.
+    pub exit_code: Option<i32>,
+    /// The process was killed because it ran longer than the script timeout.
+    pub timed_out: bool,
+    /// The process was stopped before the next run was started.
+    pub killed: bool,
+    /// Uploaded version of the exploit which was run.
+    pub version_id: Option<i32>,
+    /// Git commit of the exploit which was run.
+    pub commit_hash: Option<String>,
+    /// Resource limits the run ran into.
+    pub limits_hit: Vec<ResourceLimit>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = exploit_runs)]
+pub struct NewExploitRun {
+    pub exploit_id: i32,
+    pub team_id: i32,
+    pub command: String,
+    pub tick: i32,
+    pub start_time: DateTime<Utc>,
+    pub version_id: Option<i32>,
+    pub commit_hash: Option<String>,
+}
+
+/// An uploaded script or archive of an exploit. The content is loaded separately.
+#[derive(Identifiable, Queryable, Associations, Serialize, Clone, Debug)]
+#[diesel(table_name = exploit_versions)]
+#[diesel(belongs_to(Exploit))]
+pub struct ExploitVersion {
+    pub id: i32,
+    pub exploit_id: i32,
+    /// Name of the uploaded file. Decides how it's unpacked.
+    pub filename: String,
+    /// Hex encoded SHA-256 hash of the content.
+    pub sha256: String,
+    /// Size of the uploaded file in bytes.
+    pub size: i32,
+    /// User who uploaded the version.
+    pub author: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = exploit_versions)]
+pub struct NewExploitVersion {
+    pub exploit_id: i32,
+    pub filename: String,
+    pub sha256: String,
+    pub size: i32,
+    pub author: String,
+    pub content: Vec<u8>,
+}
+
+/// Columns of `ExploitVersion`, skipping the potentially large content.
+type VersionColumns = (
+    exploit_versions::id,
+    exploit_versions::exploit_id,
+    exploit_versions::filename,
+    exploit_versions::sha256,
+    exploit_versions::size,
+    exploit_versions::author,
+    exploit_versions::created_at,
+);
+
+const VERSION_COLUMNS: VersionColumns = (
+    exploit_versions::id,
+    exploit_versions::exploit_id,
+    exploit_versions::filename,
+    exploit_versions::sha256,
+    exploit_versions::size,
+    exploit_versions::author,
+    exploit_versions::created_at,
+);
+
+/// Combined stdout and stderr of an exploit run.
+#[derive(Identifiable, Insertable, Queryable, Associations, Debug)]
+#[diesel(table_name = exploit_run_outputs)]
+#[diesel(primary_key(exploit_run_id))]
+#[diesel(belongs_to(ExploitRun))]
+pub struct ExploitRunOutput {
+    pub exploit_run_id: i64,
+    /// Gzip compressed output lines prefixed with a timestamp and the stream.
+    pub output: Vec<u8>,
+    /// Size of the uncompressed output in bytes.
+    pub size: i32,
+    /// The output was cut off after `Settings::run_output_max_bytes`.
+    pub truncated: bool,
+}
+
+/// Filter exploit runs. Runs are returned newest first.
+#[derive(Deserialize, Default, Debug)]
+pub struct RunFilter {
+    pub exploit_id: Option<i32>,
+    pub team_id: Option<i32>,
+    pub target_challenge: Option<String>,
+    /// First tick to include.
+    pub tick_from: Option<i32>,
+    /// Last tick to include.
+    pub tick_to: Option<i32>,
+    pub exit_code: Option<i32>,
+    pub timed_out: Option<bool>,
+    pub killed: Option<bool>,
+    /// Only runs which found at least one flag nobody saw before (or none at all).
+    pub new_flags: Option<bool>,
+    /// Cursor to continue after. Only runs with a smaller id are returned.
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+impl Policy {
+    pub fn repeat_interval(&self) -> Duration {
+        Duration::from_secs(self.repeat_interval.max(0) as u64)
+    }
+
+    pub fn save(&self, conn: &mut PgConnection) -> Result<(), db::Error> {
+        diesel::update(self).set(self).execute(conn)?;
+        Ok(())
+    }
+
+    /// Collect all exploits, team overrides and settings referencing this policy.
+    pub fn get_usage(&self, conn: &mut PgConnection) -> Result<PolicyUsage, db::Error> {
+        let exploits = Exploit::belonging_to(self)
+            .select(exploits::id)
+            .order(exploits::id)
+            .load::<i32>(conn)?;
+        let targets = exploit_target_teams::table
+            .filter(exploit_target_teams::policy_id.eq(self.id))
+            .order((
+                exploit_target_teams::exploit_id,
+                exploit_target_teams::team_id,
+            ))
+            .load::<ExploitTarget>(conn)?;
+        let default_policy =
+            crate::settings::get_settings(conn)?.default_policy_id == Some(self.id);
+
+        Ok(PolicyUsage {
+            exploits,
+            targets,
+            default_policy,
+        })
+    }
+}
+
+impl ExploitRunOutput {
+    pub fn compress(
+        exploit_run_id: i64,
+        output: &[u8],
+        truncated: bool,
+    ) -> Result<Self, db::Error> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(output)?;
+        Ok(Self {
+            exploit_run_id,
+            output: encoder.finish()?,
+            size: output.len() as i32,
+            truncated,
+        })
+    }
+
+    pub fn decompress(&self) -> Result<Vec<u8>, db::Error> {
+        let mut output = Vec::with_capacity(self.size.max(0) as usize);
+        GzDecoder::new(self.output.as_slice()).read_to_end(&mut output)?;
+        Ok(output)
+    }
+}
+
+impl PolicyUsage {
+    pub fn is_unused(&self) -> bool {
+        self.exploits.is_empty() && self.targets.is_empty() && !self.default_policy
+    }
+}
+
+impl ExploitRun {
+    /// Did the process exit with 0 in time?
+    pub fn is_successful(&self) -> bool {
+        self.exit_code == Some(0) && !self.timed_out && !self.killed
+    }
+}
+
+impl Exploit {
+    pub fn script_timeout(&self) -> Duration {
+        Duration::from_secs(self.script_timeout.max(0) as u64)
+    }
+
+    pub fn get_meta_data(&self, conn: &mut PgConnection) -> Result<Vec<ExploitMeta>, db::Error> {
+        Ok(ExploitMeta::belonging_to(self).load::<ExploitMeta>(conn)?)
+    }
+
+    pub fn get_targets(&self, conn: &mut PgConnection) -> Result<Vec<ExploitTarget>, db::Error> {
+        Ok(ExploitTarget::belonging_to(self).load::<ExploitTarget>(conn)?)
+    }
+
+    pub fn save(&self, conn: &mut PgConnection) -> Result<(), db::Error> {
+        diesel::update(self).set(self).execute(conn)?;
+        Ok(())
+    }
+
+    /// Attack the given team using a different policy than the exploit default.
+    pub fn set_target_policy(
+        &self,
+        conn: &mut PgConnection,
+        team_id: i32,
+        policy_id: i32,
+    ) -> Result<ExploitTarget, db::Error> {
+        let target = ExploitTarget {
+            exploit_id: self.id,
+            team_id,
+            policy_id,
+        };
+        Ok(diesel::insert_into(exploit_target_teams::table)
+            .values(&target)
+            .on_conflict((
+                exploit_target_teams::exploit_id,
+                exploit_target_teams::team_id,
+            ))
+            .do_update()
+            .set(exploit_target_teams::policy_id.eq(policy_id))
+            .get_result(conn)?)
+    }
+
+    /// Fall back to the exploit default policy for the given team.
+    pub fn remove_target_policy(
+        &self,
+        conn: &mut PgConnection,
+        team_id: i32,
+    ) -> Result<bool, db::Error> {
+        let deleted = diesel::delete(
+            ExploitTarget::belonging_to(self).filter(exploit_target_teams::team_id.eq(team_id)),
+        )
+        .execute(conn)?;
+        Ok(deleted > 0)
+    }
+
+    /// Add or replace the meta value with the given key.
+    pub fn set_meta_value(
+        &self,
+        conn: &mut PgConnection,
+        key: String,
+        value: String,
+    ) -> Result<ExploitMeta, db::Error> {
+        let meta = ExploitMeta {
+            exploit_id: self.id,
+            key,
+            value,
+        };
+        Ok(diesel::insert_into(exploit_key_values::table)
+            .values(&meta)
+            .on_conflict((exploit_key_values::exploit_id, exploit_key_values::key))
+            .do_update()
+            .set(exploit_key_values::value.eq(&meta.value))
+            .get_result(conn)?)
+    }
+
+    pub fn remove_meta_value(&self, conn: &mut PgConnection, key: &str) -> Result<bool, db::Error> {
+        let deleted =
+            diesel::delete(ExploitMeta::belonging_to(self).filter(exploit_key_values::key.eq(key)))
+                .execute(conn)?;
+        Ok(deleted > 0)
+    }
+}
+
+pub fn find_policy_by_id(
+    conn: &mut PgConnection,
+    policy_id: i32,
+) -> Result<Option<Policy>, db::Error> {
+    use crate::schema::policies::dsl::*;
+
+    let policy = policies
+        .filter(id.eq(policy_id))
+        .first::<Policy>(conn)
+        .optional()?;
+
+    Ok(policy)
+}
+
+pub fn find_policy_by_name(
+    conn: &mut PgConnection,
+    policy_name: &str,
+) -> Result<Option<Policy>, db::Error> {
+    use crate::schema::policies::dsl::*;
+
+    Ok(policies
+        .filter(name.eq(policy_name))
+        .first::<Policy>(conn)
+        .optional()?)
+}
+
+pub fn get_policies(conn: &mut PgConnection) -> Result<Vec<Policy>, db::Error> {
+    use crate::schema::policies::dsl::*;
+    Ok(policies.order(id).load::<Policy>(conn)?)
+}
+
+pub fn add_policy(conn: &mut PgConnection, policy: NewPolicy) -> Result<Policy, db::Error> {
+    use crate::schema::policies::dsl::*;
+
+    Ok(diesel::insert_into(policies)
+        .values(&policy)
+        .get_result(conn)?)
+}
+
+/// Delete the policy if nothing references it anymore.
+/// Returns the current usage of the policy otherwise.
+pub fn delete_policy(conn: &mut PgConnection, policy: Policy) -> Result<PolicyUsage, db::Error> {
+    conn.transaction(|conn| {
+        let usage = policy.get_usage(conn)?;
+        if usage.is_unused() {
+            diesel::delete(&policy).execute(conn)?;
+        }
+        Ok(usage)
+    })
+}
+
+pub fn find_exploit_by_id(
+    conn: &mut PgConnection,
+    exploit_id: i32,
+) -> Result<Option<Exploit>, db::Error> {
+    use crate::schema::exploits::dsl::*;
+
+    let exploit = exploits
+        .filter(id.eq(exploit_id))
+        .first::<Exploit>(conn)
+        .optional()?;
+
+    Ok(exploit)
+}
+
+pub fn get_exploits(conn: &mut PgConnection) -> Result<Vec<Exploit>, db::Error> {
+    use crate::schema::exploits::dsl::*;
+    Ok(exploits.order(id).load::<Exploit>(conn)?)
+}
+
+/// All exploits which should currently be run.
+pub fn get_enabled_exploits(conn: &mut PgConnection) -> Result<Vec<Exploit>, db::Error> {
+    use crate::schema::exploits::dsl::*;
+    Ok(exploits
+        .filter(disabled.eq(false))
+        .order(id)
+        .load::<Exploit>(conn)?)
+}
+
+/// Team specific policy overrides of all exploits at once.
+pub fn get_all_targets(conn: &mut PgConnection) -> Result<Vec<ExploitTarget>, db::Error> {
+    Ok(exploit_target_teams::table.load::<ExploitTarget>(conn)?)
+}
+
+/// Meta data of all exploits at once.
+pub fn get_all_meta_data(conn: &mut PgConnection) -> Result<Vec<ExploitMeta>, db::Error> {
+    Ok(exploit_key_values::table.load::<ExploitMeta>(conn)?)
 }
 
+pub fn add_exploit(conn: &mut PgConnection, exploit: NewExploit) -> Result<Exploit, db::Error> {
+    use crate::schema::exploits::dsl::*;
 
+    Ok(diesel::insert_into(exploits)
+        .values(&exploit)
+        .get_result(conn)?)
+}
+
+pub fn add_version(
+    conn: &mut PgConnection,
+    version: NewExploitVersion,
+) -> Result<ExploitVersion, db::Error> {
+    Ok(diesel::insert_into(exploit_versions::table)
+        .values(&version)
+        .returning(VERSION_COLUMNS)
+        .get_result(conn)?)
+}
+
+pub fn find_version_by_id(
+    conn: &mut PgConnection,
+    version_id: i32,
+) -> Result<Option<ExploitVersion>, db::Error> {
+    Ok(exploit_versions::table
+        .find(version_id)
+        .select(VERSION_COLUMNS)
+        .first::<ExploitVersion>(conn)
+        .optional()?)
+}
+
+/// All uploaded versions of the exploit, newest first.
+pub fn get_versions(
+    conn: &mut PgConnection,
+    exploit_id: i32,
+) -> Result<Vec<ExploitVersion>, db::Error> {
+    Ok(exploit_versions::table
+        .filter(exploit_versions::exploit_id.eq(exploit_id))
+        .select(VERSION_COLUMNS)
+        .order(exploit_versions::id.desc())
+        .load::<ExploitVersion>(conn)?)
+}
+
+/// The uploaded file of the version.
+pub fn load_version_content(
+    conn: &mut PgConnection,
+    version_id: i32,
+) -> Result<Vec<u8>, db::Error> {
+    Ok(exploit_versions::table
+        .find(version_id)
+        .select(exploit_versions::content)
+        .first::<Vec<u8>>(conn)?)
+}
+
+/// Newest version id of every exploit with uploaded versions.
+pub fn get_latest_version_ids(conn: &mut PgConnection) -> Result<HashMap<i32, i32>, db::Error> {
+    let latest = exploit_versions::table
+        .group_by(exploit_versions::exploit_id)
+        .select((
+            exploit_versions::exploit_id,
+            diesel::dsl::max(exploit_versions::id),
+        ))
+        .load::<(i32, Option<i32>)>(conn)?;
+    Ok(latest
+        .into_iter()
+        .filter_map(|(exploit_id, version_id)| Some((exploit_id, version_id?)))
+        .collect())
+}
+
+/// Maximum number of runs returned at once.
+pub const MAX_RUNS_PER_PAGE: i64 = 500;
+
+pub fn find_run_by_id(
+    conn: &mut PgConnection,
+    run_id: i64,
+) -> Result<Option<ExploitRun>, db::Error> {
+    Ok(exploit_runs::table
+        .find(run_id)
+        .first::<ExploitRun>(conn)
+        .optional()?)
+}
+
+pub fn get_runs(conn: &mut PgConnection, filter: &RunFilter) -> Result<Vec<ExploitRun>, db::Error> {
+    let mut query = exploit_runs::table
+        .inner_join(exploits::table)
+        .select(exploit_runs::all_columns)
+        .order(exploit_runs::id.desc())
+        .limit(filter.limit.unwrap_or(50).clamp(1, MAX_RUNS_PER_PAGE))
+        .into_boxed();
+
+    if let Some(exploit_id) = filter.exploit_id {
+        query = query.filter(exploit_runs::exploit_id.eq(exploit_id));
+    }
+    if let Some(team_id) = filter.team_id {
+        query = query.filter(exploit_runs::team_id.eq(team_id));
+    }
+    if let Some(target_challenge) = &filter.target_challenge {
+        query = query.filter(exploits::target_challenge.eq(target_challenge.clone()));
+    }
+    if let Some(tick_from) = filter.tick_from {
+        query = query.filter(exploit_runs::tick.ge(tick_from));
+    }
+    if let Some(tick_to) = filter.tick_to {
+        query = query.filter(exploit_runs::tick.le(tick_to));
+    }
+    if let Some(exit_code) = filter.exit_code {
+        query = query.filter(exploit_runs::exit_code.eq(exit_code));
+    }
+    if let Some(timed_out) = filter.timed_out {
+        query = query.filter(exploit_runs::timed_out.eq(timed_out));
+    }
+    if let Some(killed) = filter.killed {
+        query = query.filter(exploit_runs::killed.eq(killed));
+    }
+    if let Some(new_flags) = filter.new_flags {
+        let found_new_flag =
+            exists(flags::table.filter(flags::exploit_run_id.eq(exploit_runs::id.nullable())));
+        if new_flags {
+            query = query.filter(found_new_flag);
+        } else {
+            query = query.filter(diesel::dsl::not(found_new_flag));
+        }
+    }
+    if let Some(before) = filter.before {
+        query = query.filter(exploit_runs::id.lt(before));
+    }
+
+    Ok(query.load::<ExploitRun>(conn)?)
+}
+
+pub fn add_run(conn: &mut PgConnection, run: NewExploitRun) -> Result<ExploitRun, db::Error> {
+    Ok(diesel::insert_into(exploit_runs::table)
+        .values(&run)
+        .get_result(conn)?)
+}
+
+/// Record how the exploit process ended.
+pub fn finish_run(
+    conn: &mut PgConnection,
+    run_id: i64,
+    exit_code: Option<i32>,
+    timed_out: bool,
+    killed: bool,
+    limits_hit: &[ResourceLimit],
+) -> Result<ExploitRun, db::Error> {
+    Ok(diesel::update(exploit_runs::table.find(run_id))
+        .set((
+            exploit_runs::end_time.eq(Utc::now()),
+            exploit_runs::exit_code.eq(exit_code),
+            exploit_runs::timed_out.eq(timed_out),
+            exploit_runs::killed.eq(killed),
+            exploit_runs::limits_hit.eq(limits_hit),
+        ))
+        .get_result(conn)?)
+}
+
+pub fn add_run_output(conn: &mut PgConnection, output: &ExploitRunOutput) -> Result<(), db::Error> {
+    diesel::insert_into(exploit_run_outputs::table)
+        .values(output)
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn find_run_output(
+    conn: &mut PgConnection,
+    run_id: i64,
+) -> Result<Option<ExploitRunOutput>, db::Error> {
+    Ok(exploit_run_outputs::table
+        .find(run_id)
+        .first::<ExploitRunOutput>(conn)
+        .optional()?)
+}
+
+/// Delete the output of all runs started before the given time.
+/// Returns the number of deleted outputs.
+pub fn prune_run_outputs(
+    conn: &mut PgConnection,
+    started_before: DateTime<Utc>,
+) -> Result<usize, db::Error> {
+    let old_runs = exploit_runs::table
+        .filter(exploit_runs::start_time.lt(started_before))
+        .select(exploit_runs::id);
+    Ok(diesel::delete(
+        exploit_run_outputs::table.filter(exploit_run_outputs::exploit_run_id.eq_any(old_runs)),
+    )
+    .execute(conn)?)
+}
@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::versions::partial_name;
+use crate::db;
+
+/// Held while adding a worktree.
+static WORKTREE_LOCK: Mutex<()> = Mutex::new(());
+
+/// How long a resolved ref is used before asking git again.
+const REF_CACHE_DURATION: Duration = Duration::from_secs(10);
+
+/// Run git in the repository and return what it printed.
+fn git(repository: &Path, args: &[&str]) -> Result<String, db::Error> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repository)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Hash of the commit the branch, tag or commit currently points to.
+pub fn resolve_ref(repository: &str, git_ref: &str) -> Result<String, db::Error> {
+    git(
+        Path::new(repository),
+        &[
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            "--end-of-options",
+            &format!("{git_ref}^{{commit}}"),
+        ],
+    )
+    .map_err(|_| format!("Ref {git_ref} not found in git repository {repository}").into())
+}
+
+/// Recently resolved refs, so scheduling passes don't run git for every exploit every second.
+#[derive(Default)]
+pub struct RefCache {
+    refs: HashMap<(String, String), (Result<String, String>, Instant)>,
+}
+
+impl RefCache {
+    /// Like `resolve_ref`, but the result is reused for `REF_CACHE_DURATION`.
+    pub fn resolve(&mut self, repository: &str, git_ref: &str) -> Result<String, String> {
+        self.refs
+            .retain(|_, (_, resolved_at)| resolved_at.elapsed() < REF_CACHE_DURATION);
+        let key = (repository.to_string(), git_ref.to_string());
+        let (commit, _) = self.refs.entry(key).or_insert_with(|| {
+            let commit = resolve_ref(repository, git_ref).map_err(|err| err.to_string());
+            (commit, Instant::now())
+        });
+        commit.clone()
+    }
+}
+
+/// Directory with the commit checked out. A detached worktree of the repository
+/// is added in `<storage>/<exploit id>/git-<commit>` the first time it's needed.
+pub fn ensure_worktree(
+    storage: &Path,
+    exploit_id: i32,
+    repository: &str,
+    commit: &str,
+) -> Result<PathBuf, db::Error> {
+    let exploit_dir = storage.join(exploit_id.to_string());
+    let worktree = exploit_dir.join(format!("git-{commit}"));
+    if worktree.is_dir() {
+        return Ok(worktree.canonicalize()?);
+    }
+
+    // Several teams are attacked with the same commit at once, and git doesn't cope
+    // well with worktrees of a repository being added at the same time.
+    let _lock = WORKTREE_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+
+    // git runs inside the repository, so it needs absolute paths.
+    fs::create_dir_all(&exploit_dir)?;
+    let exploit_dir = exploit_dir.canonicalize()?;
+    let worktree = exploit_dir.join(format!("git-{commit}"));
+    if worktree.is_dir() {
+        return Ok(worktree);
+    }
+    let partial_worktree = exploit_dir.join(partial_name(&format!("git-{commit}")));
+    let repository = Path::new(repository);
+
+    // Check out next to the final directory first, so a half written worktree is never run.
+    git(
+        repository,
+        &[
+            "worktree",
+            "add",
+            "--detach",
+            &partial_worktree.to_string_lossy(),
+            commit,
+        ],
+    )?;
+    // `git worktree move` would move it into a worktree another anthill process
+    // added in the meantime. A plain rename fails then.
+    if let Err(err) = fs::rename(&partial_worktree, &worktree) {
+        let _ = git(
+            repository,
+            &[
+                "worktree",
+                "remove",
+                "--force",
+                &partial_worktree.to_string_lossy(),
+            ],
+        );
+        if worktree.is_dir() {
+            return Ok(worktree);
+        }
+        return Err(err.into());
+    }
+    git(
+        repository,
+        &["worktree", "repair", &worktree.to_string_lossy()],
+    )?;
+    log::info!(
+        "Checked out commit {} of exploit {} to {}",
+        commit,
+        exploit_id,
+        worktree.display()
+    );
+    Ok(worktree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn concurrent_checkouts_share_one_worktree() {
+        let dir = std::env::temp_dir().join(format!("anthill-git-test-{}", std::process::id()));
+        let repository = dir.join("repository");
+        fs::create_dir_all(&repository).unwrap();
+        git(&repository, &["init", "--quiet"]).unwrap();
+        fs::write(repository.join("exploit.sh"), "echo FLAG{test}\n").unwrap();
+        git(&repository, &["add", "exploit.sh"]).unwrap();
+        git(
+            &repository,
+            &[
+                "-c",
+                "user.name=test",
+                "-c",
+                "user.email=test@example.com",
+                "commit",
+                "--quiet",
+                "-m",
+                "Add exploit",
+            ],
+        )
+        .unwrap();
+        let repository = repository.to_string_lossy().into_owned();
+        let commit = resolve_ref(&repository, "HEAD").unwrap();
+        let storage = dir.join("storage");
+
+        let worktrees = std::thread::scope(|scope| {
+            let checkouts = (0..4)
+                .map(|_| scope.spawn(|| ensure_worktree(&storage, 1, &repository, &commit)))
+                .collect::<Vec<_>>();
+            checkouts
+                .into_iter()
+                .map(|checkout| checkout.join().unwrap().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        assert!(worktrees.iter().all(|worktree| *worktree == worktrees[0]));
+        assert!(worktrees[0].join("exploit.sh").is_file());
+        let entries = fs::read_dir(storage.join("1")).unwrap().count();
+        assert_eq!(entries, 1);
+        let list = git(Path::new(&repository), &["worktree", "list", "--porcelain"]).unwrap();
+        assert_eq!(list.matches("worktree ").count(), 2);
+        assert!(list.contains(&format!("worktree {}\n", worktrees[0].display())));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,670 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use actix::prelude::*;
+use chrono::Utc;
+use diesel::PgConnection;
+use regex::Regex;
+use tokio::sync::oneshot;
+
+use crate::challenge;
+use crate::db;
+use crate::environment::{self, EnvironmentVariable};
+use crate::events::{self, Event, EventSender};
+use crate::exploit::{self, ExploitSource, OverrunPolicy};
+use crate::flag_submitter::FlagSubmissionResult;
+use crate::metrics::Metrics;
+use crate::settings;
+use crate::team::{self, TeamState};
+use crate::DbPool;
+
+mod git;
+mod output;
+mod run;
+mod sandbox;
+mod template;
+mod versions;
+
+pub use git::resolve_ref;
+pub use output::{output_channel, OutputLine, OutputSender};
+use run::RunRequest;
+pub use run::{TestRun, TestRunResult};
+pub use versions::check_upload;
+
+/// How often to check for exploits which are due to run again.
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to delete exploit output older than the configured retention time.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Starts all enabled exploits against all targeted teams according to their policies.
+///
+/// Exploits, policies and teams are reloaded from the database on every pass,
+/// so changes apply on the next run without restarting anything.
+pub struct ExploitRunner {
+    pool: DbPool,
+    /// Output of all runs is published here for live subscribers.
+    live_output: OutputSender,
+    events: EventSender,
+    metrics: Metrics,
+    /// Uploaded exploit versions are unpacked into this directory.
+    storage_path: PathBuf,
+    /// Commits the git refs of exploits pointed to recently.
+    refs: Arc<Mutex<git::RefCache>>,
+    /// Tick of the last scheduling pass to notice when a new one starts.
+    current_tick: Option<i32>,
+    /// Is a scheduling pass currently loading the targets?
+    scheduling: bool,
+    /// Start time of the last run for every (exploit, team) pair.
+    last_start: HashMap<(i32, i32), Instant>,
+    /// Currently running exploit processes for every (exploit, team) pair.
+    running: HashMap<(i32, i32), Vec<RunningExploit>>,
+    next_handle: u64,
+    /// Number of runs allowed at once, from the last scheduling pass.
+    parallel_runs: usize,
+    /// Test runs waiting for a free run slot.
+    test_runs: VecDeque<QueuedTestRun>,
+}
+
+/// Run an exploit once outside of its schedule. It takes one of the parallel run slots
+/// like scheduled runs and waits for one if they're all taken.
+#[derive(Message)]
+#[rtype(result = "Result<TestRunResult, oneshot::error::RecvError>")]
+pub struct StartTestRun {
+    pub test_run: TestRun,
+    pub live_output: OutputSender,
+}
+
+struct QueuedTestRun {
+    test_run: TestRun,
+    live_output: OutputSender,
+    result: oneshot::Sender<TestRunResult>,
+}
+
+struct RunningExploit {
+    handle: u64,
+    /// Kill the process before it finishes on its own.
+    stop: Option<oneshot::Sender<()>>,
+}
+
+/// Where the code of an exploit is run from.
+struct ExploitCode {
+    working_directory: String,
+    version_id: Option<i32>,
+    commit_hash: Option<String>,
+}
+
+/// All runs which could be started right now.
+struct Schedule {
+    tick: i32,
+    parallel_runs: usize,
+    requests: Vec<RunRequest>,
+    /// Teams which stopped responding to a challenge in the previous ticks.
+    newly_skipped: Vec<team::SkippedTarget>,
+}
+
+impl ExploitRunner {
+    pub fn new(
+        pool: DbPool,
+        live_output: OutputSender,
+        events: EventSender,
+        metrics: Metrics,
+        storage_path: PathBuf,
+    ) -> Self {
+        Self {
+            pool,
+            live_output,
+            events,
+            metrics,
+            storage_path,
+            refs: Arc::default(),
+            current_tick: None,
+            scheduling: false,
+            last_start: HashMap::new(),
+            running: HashMap::new(),
+            next_handle: 0,
+            parallel_runs: 0,
+            test_runs: VecDeque::new(),
+        }
+    }
+
+    fn schedule(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if self.scheduling {
+            return;
+        }
+        self.scheduling = true;
+
+        let pool = self.pool.clone();
+        let storage_path = self.storage_path.clone();
+        let refs = self.refs.clone();
+        let last_tick = self.current_tick;
+        ctx.spawn(
+            tokio::task::spawn_blocking(move || {
+                let refs = &mut refs.lock().unwrap_or_else(PoisonError::into_inner);
+                load_schedule(&pool, &storage_path, refs, last_tick)
+            })
+            .into_actor(self)
+            .map(|schedule, act, ctx| {
+                act.scheduling = false;
+                match schedule {
+                    Ok(Ok(schedule)) => act.start_due_runs(ctx, schedule),
+                    Ok(Err(err)) => log::error!("Failed to load exploit targets: {}", err),
+                    Err(err) => log::error!("Failed to load exploit targets: {}", err),
+                }
+            }),
+        );
+    }
+
+    fn start_due_runs(&mut self, ctx: &mut <Self as Actor>::Context, schedule: Schedule) {
+        if self.current_tick != Some(schedule.tick) {
+            self.current_tick = Some(schedule.tick);
+            events::publish(&self.events, Event::TickChanged(schedule.tick));
+        }
+        for target in schedule.newly_skipped {
+            log::info!(
+                "Skipping team {} for {} until it responds again",
+                target.team_id,
+                target.target_challenge
+            );
+            events::publish(&self.events, Event::TargetSkipped(target));
+        }
+
+        // Waiting test runs get the free slots first.
+        self.parallel_runs = schedule.parallel_runs;
+        self.start_test_runs(ctx);
+
+        let now = Instant::now();
+        let mut due = schedule
+            .requests
+            .into_iter()
+            .filter(|request| match self.last_start.get(&request.key()) {
+                Some(last_start) => now.duration_since(*last_start) >= request.repeat_interval,
+                None => true,
+            })
+            .collect::<Vec<_>>();
+        // Start the targets which waited the longest first.
+        due.sort_by_key(|request| self.last_start.get(&request.key()).copied());
+
+        for request in due {
+            // Checked before stopping old runs, so they aren't stopped without starting a new one.
+            if !self.has_free_slot() {
+                break;
+            }
+            let key = request.key();
+            self.last_start.insert(key, now);
+            if self.handle_overrun(key, request.overrun_policy) {
+                self.start_run(ctx, request);
+            }
+        }
+    }
+
+    fn has_free_slot(&self) -> bool {
+        self.running.values().map(Vec::len).sum::<usize>() < self.parallel_runs
+    }
+
+    /// Apply the overrun policy to the runs of the pair which are still running.
+    /// Returns whether the new run may start.
+    fn handle_overrun(&mut self, key: (i32, i32), overrun_policy: OverrunPolicy) -> bool {
+        let Some(running) = self.running.get_mut(&key).filter(|r| !r.is_empty()) else {
+            return true;
+        };
+        match overrun_policy {
+            OverrunPolicy::StopOld => {
+                for old_run in running.iter_mut() {
+                    if let Some(stop) = old_run.stop.take() {
+                        let _ = stop.send(());
+                    }
+                }
+                true
+            }
+            OverrunPolicy::KeepOldOnly => false,
+            OverrunPolicy::KeepOldAndStartNew => true,
+        }
+    }
+
+    /// Start the queued test runs in order while there are free slots. Test runs of
+    /// exploits with the `KeepOldOnly` policy wait until the pair's old runs finished.
+    fn start_test_runs(&mut self, ctx: &mut <Self as Actor>::Context) {
+        // Nobody waits for the result anymore.
+        self.test_runs.retain(|queued| !queued.result.is_closed());
+
+        let mut index = 0;
+        while index < self.test_runs.len() && self.has_free_slot() {
+            let request = self.test_runs[index].test_run.request();
+            if !self.handle_overrun(request.key(), request.overrun_policy) {
+                index += 1;
+                continue;
+            }
+            if let Some(queued) = self.test_runs.remove(index) {
+                self.start_test_run(ctx, queued);
+            }
+        }
+    }
+
+    fn start_run(&mut self, ctx: &mut <Self as Actor>::Context, request: RunRequest) {
+        let key = request.key();
+        let pool = self.pool.clone();
+        let live_output = self.live_output.clone();
+        let events = self.events.clone();
+        let metrics = self.metrics.clone();
+        self.track_run(ctx, key, move |stop| {
+            run::execute(pool, request, stop, live_output, events, metrics)
+        });
+    }
+
+    fn start_test_run(&mut self, ctx: &mut <Self as Actor>::Context, queued: QueuedTestRun) {
+        let key = queued.test_run.request().key();
+        self.track_run(ctx, key, move |stop| async move {
+            let QueuedTestRun {
+                test_run,
+                live_output,
+                mut result,
+            } = queued;
+            // The process is killed when the run is dropped because nobody waits for it anymore.
+            tokio::select! {
+                test_result = test_run.execute(live_output, stop) => {
+                    let _ = result.send(test_result);
+                }
+                _ = result.closed() => (),
+            }
+        });
+    }
+
+    /// Count the run against the pair's running processes until it finishes.
+    fn track_run<F, R>(&mut self, ctx: &mut <Self as Actor>::Context, key: (i32, i32), run: R)
+    where
+        R: FnOnce(oneshot::Receiver<()>) -> F,
+        F: Future<Output = ()> + 'static,
+    {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+
+        let (stop_tx, stop_rx) = oneshot::channel();
+        self.running.entry(key).or_default().push(RunningExploit {
+            handle,
+            stop: Some(stop_tx),
+        });
+
+        ctx.spawn(run(stop_rx).into_actor(self).map(move |_, act, ctx| {
+            if let Some(running) = act.running.get_mut(&key) {
+                running.retain(|run| run.handle != handle);
+                if running.is_empty() {
+                    act.running.remove(&key);
+                }
+            }
+            act.start_test_runs(ctx);
+        }));
+    }
+}
+
+impl Handler<StartTestRun> for ExploitRunner {
+    type Result = ResponseFuture<Result<TestRunResult, oneshot::error::RecvError>>;
+
+    fn handle(&mut self, msg: StartTestRun, ctx: &mut Self::Context) -> Self::Result {
+        let (result_tx, result_rx) = oneshot::channel();
+        self.test_runs.push_back(QueuedTestRun {
+            test_run: msg.test_run,
+            live_output: msg.live_output,
+            result: result_tx,
+        });
+        self.start_test_runs(ctx);
+        Box::pin(result_rx)
+    }
+}
+
+impl Actor for ExploitRunner {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(SCHEDULE_INTERVAL, |act, ctx| act.schedule(ctx));
+        ctx.run_interval(PRUNE_INTERVAL, |act, _| {
+            let pool = act.pool.clone();
+            tokio::task::spawn_blocking(move || {
+                if let Err(err) = prune_run_outputs(&pool) {
+                    log::error!("Failed to prune exploit output: {}", err);
+                }
+            });
+        });
+    }
+}
+
+fn prune_run_outputs(pool: &DbPool) -> Result<(), db::Error> {
+    let conn = &mut pool.get()?;
+    let settings = settings::get_settings(conn)?;
+    if settings.run_output_retention <= 0 {
+        return Ok(());
+    }
+
+    let started_before =
+        Utc::now() - chrono::Duration::seconds(settings.run_output_retention as i64);
+    let pruned = exploit::prune_run_outputs(conn, started_before)?;
+    if pruned > 0 {
+        log::info!("Deleted output of {} old exploit runs", pruned);
+    }
+    Ok(())
+}
+
+/// Settings shared by all runs prepared at once.
+struct RunContext {
+    settings: settings::Settings,
+    flag_regex: Regex,
+    tick: i32,
+    /// Global and exploit specific environment variables.
+    environment: Vec<EnvironmentVariable>,
+}
+
+impl RunContext {
+    fn load(conn: &mut PgConnection) -> Result<Self, db::Error> {
+        let settings = settings::get_settings(conn)?;
+        let flag_regex = Regex::new(&settings.flag_regex)?;
+        let tick = settings.current_tick();
+        let environment = environment::get_all_variables(conn)?;
+        Ok(Self {
+            settings,
+            flag_regex,
+            tick,
+            environment,
+        })
+    }
+
+    /// Environment of a run against the team. Exploit specific variables override
+    /// the global ones, the automatic variables override both.
+    fn environment(
+        &self,
+        exploit: &exploit::Exploit,
+        team: &team::Team,
+        variables: &template::Variables,
+    ) -> (Vec<(String, String)>, Vec<String>) {
+        let configured = self
+            .environment
+            .iter()
+            .filter(|variable| variable.exploit_id.is_none())
+            .chain(
+                self.environment
+                    .iter()
+                    .filter(|variable| variable.exploit_id == Some(exploit.id)),
+            );
+        let mut environment = Vec::new();
+        let mut secrets = Vec::new();
+        for variable in configured {
+            environment.push((variable.name.clone(), variable.value.clone()));
+            if variable.secret && !variable.value.is_empty() {
+                secrets.push(variable.value.clone());
+            }
+        }
+
+        if let Some(ip) = variables.get("team.ip") {
+            environment.push(("TARGET_IP".to_string(), ip.clone()));
+        }
+        environment.push(("TARGET_TEAM_ID".to_string(), team.id.to_string()));
+        environment.push(("TICK".to_string(), self.tick.to_string()));
+        environment.push(("FLAG_REGEX".to_string(), self.settings.flag_regex.clone()));
+        (environment, secrets)
+    }
+
+    /// Values of the template placeholders for a run against the team.
+    fn variables(
+        &self,
+        exploit: &exploit::Exploit,
+        challenge: &challenge::Challenge,
+        team: &team::Team,
+        exploit_meta: Option<&template::Variables>,
+        team_meta: Option<&template::Variables>,
+    ) -> template::Variables {
+        let mut variables = exploit_meta.cloned().unwrap_or_default();
+        if let Some(team_meta) = team_meta {
+            variables.extend(team_meta.clone());
+        }
+        variables.insert("exploit.id".to_string(), exploit.id.to_string());
+        variables.insert("exploit.command".to_string(), exploit.command.clone());
+        variables.insert("exploit.author".to_string(), exploit.author.clone());
+        variables.insert("exploit.vuln_title".to_string(), exploit.vuln_title.clone());
+        variables.insert(
+            "exploit.target_challenge".to_string(),
+            exploit.target_challenge.clone(),
+        );
+        variables.extend(challenge.template_variables());
+        variables.insert("team.id".to_string(), team.id.to_string());
+        variables.insert(
+            "team.name".to_string(),
+            team.name.clone().unwrap_or_default(),
+        );
+        variables.insert("tick".to_string(), self.tick.to_string());
+        variables
+    }
+
+    fn request(
+        &self,
+        exploit: &exploit::Exploit,
+        team: &team::Team,
+        policy: &exploit::Policy,
+        code: &Result<ExploitCode, String>,
+        variables: &template::Variables,
+    ) -> RunRequest {
+        let flag_submission_result = if Some(team.id) == self.settings.nop_team_id
+            && !self.settings.nop_team_grants_points
+        {
+            FlagSubmissionResult::NOPTeam
+        } else {
+            FlagSubmissionResult::Pending
+        };
+        let (environment, secrets) = self.environment(exploit, team, variables);
+
+        RunRequest {
+            exploit_id: exploit.id,
+            team_id: team.id,
+            target_challenge: exploit.target_challenge.clone(),
+            tick: self.tick,
+            argv: code.as_ref().map_err(Clone::clone).and_then(|_| {
+                template::expand_argv(&policy.argv_pattern, &exploit.command, variables)
+            }),
+            working_directory: code
+                .as_ref()
+                .map(|code| code.working_directory.clone())
+                .unwrap_or_default(),
+            version_id: code.as_ref().ok().and_then(|code| code.version_id),
+            commit_hash: code.as_ref().ok().and_then(|code| code.commit_hash.clone()),
+            environment,
+            secrets,
+            timeout: exploit.script_timeout(),
+            limits: sandbox::Limits::new(&self.settings, exploit),
+            repeat_interval: policy.repeat_interval(),
+            overrun_policy: exploit.overrun_policy,
+            flag_regex: self.flag_regex.clone(),
+            output_max_bytes: self.settings.run_output_max_bytes.max(0) as usize,
+            flag_submission_result,
+        }
+    }
+}
+
+/// Collect the runs of the current tick. When a new tick started,
+/// teams which didn't respond in the last ticks are skipped first.
+fn load_schedule(
+    pool: &DbPool,
+    storage_path: &Path,
+    refs: &mut git::RefCache,
+    last_tick: Option<i32>,
+) -> Result<Schedule, db::Error> {
+    let conn = &mut pool.get()?;
+    let context = RunContext::load(conn)?;
+    let settings = &context.settings;
+    let tick = context.tick;
+
+    let newly_skipped = if last_tick != Some(tick) && settings.skip_unresponsive_after_ticks > 0 {
+        team::skip_unresponsive_targets(conn, tick, settings.skip_unresponsive_after_ticks)?
+    } else {
+        Vec::new()
+    };
+    let challenges = challenge::get_challenges(conn)?
+        .into_iter()
+        .map(|challenge| (challenge.name.clone(), challenge))
+        .collect::<HashMap<_, _>>();
+    let skipped_targets = team::get_skipped_targets(conn)?
+        .into_iter()
+        .map(|target| ((target.team_id, target.target_challenge.clone()), target))
+        .collect::<HashMap<_, _>>();
+
+    let policies = exploit::get_policies(conn)?
+        .into_iter()
+        .map(|policy| (policy.id, policy))
+        .collect::<HashMap<_, _>>();
+    let targets = exploit::get_all_targets(conn)?
+        .into_iter()
+        .map(|target| ((target.exploit_id, target.team_id), target.policy_id))
+        .collect::<HashMap<_, _>>();
+    let latest_versions = exploit::get_latest_version_ids(conn)?;
+
+    let mut exploit_variables = HashMap::<i32, template::Variables>::new();
+    for meta in exploit::get_all_meta_data(conn)? {
+        exploit_variables
+            .entry(meta.exploit_id)
+            .or_default()
+            .insert(format!("exploit.{}", meta.key), meta.value);
+    }
+    let mut team_variables = HashMap::<i32, template::Variables>::new();
+    for meta in team::get_all_meta_data(conn)? {
+        team_variables
+            .entry(meta.team_id)
+            .or_default()
+            .insert(format!("team.{}", meta.key), meta.value);
+    }
+
+    let teams = team::get_teams(conn)?
+        .into_iter()
+        .filter(|team| team.state != TeamState::Deleted && Some(team.id) != settings.own_team_id)
+        .collect::<Vec<_>>();
+
+    let mut requests = Vec::new();
+    for exploit in exploit::get_enabled_exploits(conn)? {
+        let challenge = match challenges.get(&exploit.target_challenge) {
+            Some(challenge) if !challenge.disabled => challenge,
+            _ => continue,
+        };
+        let code = prepare_code(conn, storage_path, refs, &exploit, &latest_versions);
+        for team in &teams {
+            let policy_id = match targets.get(&(exploit.id, team.id)) {
+                Some(policy_id) => *policy_id,
+                None if team.should_attack() => exploit.policy_id,
+                None => continue,
+            };
+            let policy = match policies.get(&policy_id) {
+                Some(policy) if !policy.disabled => policy,
+                _ => continue,
+            };
+            if let Some(skipped) = skipped_targets.get(&(team.id, exploit.target_challenge.clone()))
+            {
+                if !skipped.should_retry(tick, settings.skipped_target_retry_ticks) {
+                    continue;
+                }
+            }
+
+            let variables = context.variables(
+                &exploit,
+                challenge,
+                team,
+                exploit_variables.get(&exploit.id),
+                team_variables.get(&team.id),
+            );
+            requests.push(context.request(&exploit, team, policy, &code, &variables));
+        }
+    }
+
+    Ok(Schedule {
+        tick,
+        parallel_runs: settings.number_of_parallel_exploit_runs.max(1) as usize,
+        requests,
+        newly_skipped,
+    })
+}
+
+/// Prepare a single run of the exploit against the team to try it out.
+/// It's prepared like a scheduled run, even if the exploit, its challenge or its policy are disabled.
+pub fn prepare_test_run(
+    conn: &mut PgConnection,
+    storage_path: &Path,
+    exploit: &exploit::Exploit,
+    team: &team::Team,
+) -> Result<TestRun, db::Error> {
+    let context = RunContext::load(conn)?;
+    let challenge = challenge::find_challenge_by_name(conn, &exploit.target_challenge)?
+        .ok_or_else(|| format!("Challenge {} doesn't exist", exploit.target_challenge))?;
+    let policy_id = exploit
+        .get_targets(conn)?
+        .into_iter()
+        .find(|target| target.team_id == team.id)
+        .map_or(exploit.policy_id, |target| target.policy_id);
+    let policy = exploit::find_policy_by_id(conn, policy_id)?
+        .ok_or_else(|| format!("Policy {policy_id} doesn't exist"))?;
+    let latest_versions = exploit::get_latest_version_ids(conn)?;
+    // Test runs always use the commit the ref points to right now.
+    let mut refs = git::RefCache::default();
+    let code = prepare_code(conn, storage_path, &mut refs, exploit, &latest_versions);
+
+    let exploit_meta = exploit
+        .get_meta_data(conn)?
+        .into_iter()
+        .map(|meta| (format!("exploit.{}", meta.key), meta.value))
+        .collect::<template::Variables>();
+    let team_meta = team
+        .get_meta_data(conn)?
+        .into_iter()
+        .map(|meta| (format!("team.{}", meta.key), meta.value))
+        .collect::<template::Variables>();
+    let variables = context.variables(
+        exploit,
+        &challenge,
+        team,
+        Some(&exploit_meta),
+        Some(&team_meta),
+    );
+    Ok(TestRun::new(
+        context.request(exploit, team, &policy, &code, &variables),
+    ))
+}
+
+/// Unpack the uploaded version or check out the git ref the exploit should run.
+fn prepare_code(
+    conn: &mut PgConnection,
+    storage_path: &Path,
+    refs: &mut git::RefCache,
+    exploit: &exploit::Exploit,
+    latest_versions: &HashMap<i32, i32>,
+) -> Result<ExploitCode, String> {
+    match exploit.source {
+        ExploitSource::Directory => Ok(ExploitCode {
+            working_directory: exploit.working_directory.clone(),
+            version_id: None,
+            commit_hash: None,
+        }),
+        ExploitSource::Upload => {
+            let version_id = exploit
+                .pinned_version_id
+                .or_else(|| latest_versions.get(&exploit.id).copied())
+                .ok_or_else(|| "No version of the exploit was uploaded yet".to_string())?;
+            let path = versions::ensure_unpacked(conn, storage_path, exploit.id, version_id)
+                .map_err(|err| format!("Failed to unpack version {version_id}: {err}"))?;
+            Ok(ExploitCode {
+                working_directory: path.to_string_lossy().into_owned(),
+                version_id: Some(version_id),
+                commit_hash: None,
+            })
+        }
+        ExploitSource::Git => {
+            let (repository, git_ref) = match (&exploit.git_repository, &exploit.git_ref) {
+                (Some(repository), Some(git_ref)) => (repository, git_ref),
+                _ => return Err("No git repository and ref configured".to_string()),
+            };
+            let commit = refs.resolve(repository, git_ref)?;
+            let path = git::ensure_worktree(storage_path, exploit.id, repository, &commit)
+                .map_err(|err| format!("Failed to check out commit {commit}: {err}"))?;
+            Ok(ExploitCode {
+                working_directory: path.to_string_lossy().into_owned(),
+                version_id: None,
+                commit_hash: Some(commit),
+            })
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use std::io;
+use std::mem;
+use std::sync::Arc;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::sync::broadcast;
+
+use crate::environment::MASKED_VALUE;
+
+/// Number of output lines buffered for live subscribers before the slowest one starts missing lines.
+const LIVE_OUTPUT_CAPACITY: usize = 4096;
+
+/// Longer lines of output are split, so a process printing without newlines can't fill the memory.
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Publishes the output of all running exploits as it's read.
+pub type OutputSender = broadcast::Sender<Arc<OutputLine>>;
+
+pub fn output_channel() -> OutputSender {
+    broadcast::channel(LIVE_OUTPUT_CAPACITY).0
+}
+
+/// Where a line of output came from.
+#[derive(Clone, Copy, Serialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+    /// Messages about the run itself like start errors or timeouts.
+    Anthill,
+}
+
+impl Stream {
+    fn marker(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+            Stream::Anthill => "anthill",
+        }
+    }
+}
+
+/// A single line of output of a currently running exploit.
+#[derive(Serialize, Debug)]
+pub struct OutputLine {
+    /// 0 for test runs, which aren't stored.
+    pub exploit_run_id: i64,
+    pub exploit_id: i32,
+    pub team_id: i32,
+    /// Number of the line in the output of the run, starting at 0.
+    /// Gaps show that a subscriber missed lines.
+    pub seq: u64,
+    pub stream: Stream,
+    pub time: DateTime<Utc>,
+    pub line: String,
+}
+
+/// Collects the combined output of a run until the size limit is reached
+/// and publishes every line to live subscribers.
+///
+/// Every line is prefixed with the time it was read and the stream it was written to.
+/// Messages of anthill itself are always kept, even after the limit was reached.
+pub struct OutputBuffer {
+    data: Vec<u8>,
+    max_bytes: usize,
+    truncated: bool,
+    live: OutputSender,
+    exploit_run_id: i64,
+    exploit_id: i32,
+    team_id: i32,
+    /// Number of the next line published to live subscribers.
+    next_seq: u64,
+    /// Values of secret environment variables to hide in the output.
+    secrets: Vec<String>,
+}
+
+impl OutputBuffer {
+    pub fn new(
+        max_bytes: usize,
+        live: OutputSender,
+        exploit_run_id: i64,
+        exploit_id: i32,
+        team_id: i32,
+        secrets: Vec<String>,
+    ) -> Self {
+        Self {
+            data: Vec::new(),
+            max_bytes,
+            truncated: false,
+            live,
+            exploit_run_id,
+            exploit_id,
+            team_id,
+            next_seq: 0,
+            secrets,
+        }
+    }
+
+    pub fn push_line(&mut self, stream: Stream, line: &[u8]) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let time = Utc::now();
+        let masked = self.mask_secrets(line);
+        let line = masked.as_ref().map_or(line, |masked| masked.as_bytes());
+
+        // Nobody listening is fine.
+        let _ = self.live.send(Arc::new(OutputLine {
+            exploit_run_id: self.exploit_run_id,
+            exploit_id: self.exploit_id,
+            team_id: self.team_id,
+            seq: self.next_seq,
+            stream,
+            time,
+            line: String::from_utf8_lossy(line).into_owned(),
+        }));
+        self.next_seq += 1;
+
+        self.store(stream, time, line);
+    }
+
+    /// The line with all secret values replaced, if it contains any.
+    fn mask_secrets(&self, line: &[u8]) -> Option<String> {
+        let text = String::from_utf8_lossy(line);
+        if !self
+            .secrets
+            .iter()
+            .any(|secret| text.contains(secret.as_str()))
+        {
+            return None;
+        }
+        let mut masked = text.into_owned();
+        for secret in &self.secrets {
+            masked = masked.replace(secret.as_str(), MASKED_VALUE);
+        }
+        Some(masked)
+    }
+
+    pub fn push_message(&mut self, message: &str) {
+        self.push_line(Stream::Anthill, message.as_bytes());
+    }
+
+    fn store(&mut self, stream: Stream, time: DateTime<Utc>, line: &[u8]) {
+        let mut formatted = format!(
+            "{} [{}] ",
+            time.to_rfc3339_opts(SecondsFormat::Millis, true),
+            stream.marker()
+        )
+        .into_bytes();
+        formatted.extend_from_slice(line);
+        formatted.push(b'\n');
+
+        if let Stream::Anthill = stream {
+            self.data.extend_from_slice(&formatted);
+            return;
+        }
+        if self.truncated {
+            return;
+        }
+        let remaining = self.max_bytes.saturating_sub(self.data.len());
+        if formatted.len() > remaining {
+            self.data.extend_from_slice(&formatted[..remaining]);
+            if !self.data.is_empty() && !self.data.ends_with(b"\n") {
+                self.data.push(b'\n');
+            }
+            self.truncated = true;
+            let notice = format!("Output truncated after {} bytes", self.max_bytes);
+            self.store(Stream::Anthill, Utc::now(), notice.as_bytes());
+        } else {
+            self.data.extend_from_slice(&formatted);
+        }
+    }
+
+    pub fn truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+/// Reads the output of a process line by line. Lines longer than `MAX_LINE_LENGTH` bytes
+/// are returned in multiple parts.
+pub struct LineReader<R> {
+    reader: BufReader<R>,
+    /// Part of the current line read so far. Kept here, so `next_line` is cancel safe.
+    line: Vec<u8>,
+}
+
+impl<R: AsyncRead + Unpin> LineReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            line: Vec::new(),
+        }
+    }
+
+    /// The next line without the newline or `None` at the end of the output.
+    pub async fn next_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let available = self.reader.fill_buf().await?;
+            if available.is_empty() {
+                if self.line.is_empty() {
+                    return Ok(None);
+                }
+                return Ok(Some(mem::take(&mut self.line)));
+            }
+
+            let room = MAX_LINE_LENGTH - self.line.len();
+            let chunk = &available[..available.len().min(room)];
+            if let Some(end) = chunk.iter().position(|byte| *byte == b'\n') {
+                self.line.extend_from_slice(&chunk[..end]);
+                self.reader.consume(end + 1);
+                return Ok(Some(mem::take(&mut self.line)));
+            }
+            let read = chunk.len();
+            self.line.extend_from_slice(chunk);
+            self.reader.consume(read);
+            if self.line.len() >= MAX_LINE_LENGTH {
+                return Ok(Some(mem::take(&mut self.line)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(max_bytes: usize, live: OutputSender, secrets: &[&str]) -> OutputBuffer {
+        let secrets = secrets.iter().map(|secret| secret.to_string()).collect();
+        OutputBuffer::new(max_bytes, live, 7, 1, 2, secrets)
+    }
+
+    fn lines(buffer: &OutputBuffer) -> Vec<String> {
+        String::from_utf8_lossy(buffer.data())
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn output_buffer_keeps_lines_with_stream() {
+        let mut output = buffer(1024, output_channel(), &[]);
+        output.push_line(Stream::Stdout, b"found FLAG{a}\r");
+        output.push_line(Stream::Stderr, b"warning");
+        output.push_message("Process exited");
+        assert!(!output.truncated());
+        assert_eq!(
+            lines(&output),
+            [
+                "[stdout] found FLAG{a}",
+                "[stderr] warning",
+                "[anthill] Process exited"
+            ]
+        );
+    }
+
+    #[test]
+    fn output_buffer_truncates_but_keeps_messages() {
+        // Every stored line is prefixed with 24 bytes of time and " [stdout] ".
+        let mut output = buffer(80, output_channel(), &[]);
+        output.push_line(Stream::Stdout, &[b'a'; 20]);
+        output.push_line(Stream::Stdout, &[b'b'; 20]);
+        output.push_line(Stream::Stdout, &[b'c'; 20]);
+        output.push_message("Process exited");
+
+        assert!(output.truncated());
+        let text = String::from_utf8_lossy(output.data()).into_owned();
+        assert!(text.contains(&"a".repeat(20)));
+        assert!(!text.contains(&"b".repeat(20)));
+        assert!(!text.contains(&"c".repeat(20)));
+        let lines = lines(&output);
+        assert_eq!(
+            lines[lines.len() - 2..],
+            [
+                "[anthill] Output truncated after 80 bytes",
+                "[anthill] Process exited"
+            ]
+        );
+    }
+
+    #[test]
+    fn output_buffer_masks_secrets() {
+        let live = output_channel();
+        let mut receiver = live.subscribe();
+        let mut output = buffer(1024, live, &["hunter2", "s3cret"]);
+        output.push_line(Stream::Stdout, b"login hunter2 s3cret hunter2");
+        output.push_line(Stream::Stdout, b"nothing secret");
+
+        assert_eq!(
+            lines(&output),
+            [
+                "[stdout] login ******** ******** ********",
+                "[stdout] nothing secret"
+            ]
+        );
+        let line = receiver.try_recv().unwrap();
+        assert_eq!(line.line, "login ******** ******** ********");
+        assert_eq!((line.exploit_run_id, line.seq), (7, 0));
+        assert_eq!(receiver.try_recv().unwrap().seq, 1);
+    }
+
+    #[tokio::test]
+    async fn line_reader_splits_long_lines() {
+        let mut input = b"short\r\n".to_vec();
+        input.extend(vec![b'a'; MAX_LINE_LENGTH + 10]);
+        input.extend_from_slice(b"\nlast");
+        let mut reader = LineReader::new(input.as_slice());
+
+        assert_eq!(reader.next_line().await.unwrap().unwrap(), b"short\r");
+        assert_eq!(
+            reader.next_line().await.unwrap().unwrap().len(),
+            MAX_LINE_LENGTH
+        );
+        assert_eq!(reader.next_line().await.unwrap().unwrap(), vec![b'a'; 10]);
+        assert_eq!(reader.next_line().await.unwrap().unwrap(), b"last");
+        assert!(reader.next_line().await.unwrap().is_none());
+    }
+}
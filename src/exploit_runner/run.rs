@@ -0,0 +1,585 @@
+use std::os::unix::process::ExitStatusExt;
+use std::process::Stdio;
+use std::time::Duration;
+
+use chrono::Utc;
+use regex::Regex;
+use serde::Serialize;
+use tokio::process::Command;
+use tokio::sync::oneshot;
+
+use super::output::{LineReader, OutputBuffer, OutputSender, Stream};
+use super::sandbox::{self, Limits, ProcessGroup, RunCgroup};
+use crate::db;
+use crate::events::{self, Event, EventSender};
+use crate::exploit::{self, ExploitRunOutput, NewExploitRun, OverrunPolicy, ResourceLimit};
+use crate::flag_submitter::{self, FlagSubmissionResult};
+use crate::metrics::Metrics;
+use crate::team;
+use crate::DbPool;
+
+/// Everything needed to start the exploit against one team.
+pub struct RunRequest {
+    pub exploit_id: i32,
+    pub team_id: i32,
+    pub target_challenge: String,
+    pub tick: i32,
+    /// Expanded command line or the reason why the exploit can't be started.
+    pub argv: Result<Vec<String>, String>,
+    pub working_directory: String,
+    /// Uploaded version of the exploit to run in the working directory.
+    pub version_id: Option<i32>,
+    /// Git commit of the exploit checked out in the working directory.
+    pub commit_hash: Option<String>,
+    /// Variables added to the environment of the process.
+    pub environment: Vec<(String, String)>,
+    /// Values of secret variables which are masked in the output.
+    pub secrets: Vec<String>,
+    pub timeout: Duration,
+    pub limits: Limits,
+    pub repeat_interval: Duration,
+    pub overrun_policy: OverrunPolicy,
+    pub flag_regex: Regex,
+    /// Maximum number of bytes of output to store.
+    pub output_max_bytes: usize,
+    /// Initial state of the flags found by this run.
+    pub flag_submission_result: FlagSubmissionResult,
+}
+
+impl RunRequest {
+    pub fn key(&self) -> (i32, i32) {
+        (self.exploit_id, self.team_id)
+    }
+
+    fn command(&self) -> String {
+        match &self.argv {
+            Ok(argv) => {
+                shlex::try_join(argv.iter().map(String::as_str)).unwrap_or_else(|_| argv.join(" "))
+            }
+            Err(err) => err.clone(),
+        }
+    }
+}
+
+/// A run of the exploit against a single team to try it out.
+/// Neither the run nor its output are stored.
+pub struct TestRun {
+    request: RunRequest,
+}
+
+/// What happened during a test run.
+#[derive(Serialize, Debug)]
+pub struct TestRunResult {
+    pub exploit_id: i32,
+    pub team_id: i32,
+    /// Expanded command line or the reason why the exploit couldn't be started.
+    pub command: String,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    /// Stopped because a scheduled run of the exploit against the team replaced it.
+    pub killed: bool,
+    /// Resource limits the run ran into.
+    pub limits_hit: Vec<ResourceLimit>,
+    /// Flags found in the output.
+    pub flags: Vec<String>,
+    /// Combined output in the format of stored run output.
+    pub output: String,
+    /// The output was cut off after `Settings::run_output_max_bytes`.
+    pub truncated: bool,
+}
+
+impl TestRun {
+    pub(super) fn new(request: RunRequest) -> Self {
+        Self { request }
+    }
+
+    pub fn target_challenge(&self) -> &str {
+        &self.request.target_challenge
+    }
+
+    /// Expanded command line or the reason why the exploit can't be started.
+    pub fn command(&self) -> String {
+        self.request.command()
+    }
+
+    pub(super) fn request(&self) -> &RunRequest {
+        &self.request
+    }
+
+    /// Run the exploit once. Output lines are published to `live_output` as they're read,
+    /// with an `exploit_run_id` of 0. The process is killed when `stop` fires or is dropped.
+    pub(super) async fn execute(
+        self,
+        live_output: OutputSender,
+        stop: oneshot::Receiver<()>,
+    ) -> TestRunResult {
+        let request = self.request;
+        let mut output = OutputBuffer::new(
+            request.output_max_bytes,
+            live_output,
+            0,
+            request.exploit_id,
+            request.team_id,
+            request.secrets.clone(),
+        );
+        let outcome = run_or_report(&request, stop, &mut output).await;
+        TestRunResult {
+            exploit_id: request.exploit_id,
+            team_id: request.team_id,
+            command: request.command(),
+            exit_code: outcome.exit_code,
+            timed_out: outcome.timed_out,
+            killed: outcome.killed,
+            limits_hit: outcome.limits_hit,
+            flags: outcome.flags,
+            output: String::from_utf8_lossy(output.data()).into_owned(),
+            truncated: output.truncated(),
+        }
+    }
+}
+
+/// How the exploit process ended.
+#[derive(Default)]
+struct RunOutcome {
+    exit_code: Option<i32>,
+    timed_out: bool,
+    killed: bool,
+    /// The other rlimits only make calls fail in the exploit. They're guessed from
+    /// the error messages in the output if the process failed.
+    limits_hit: Vec<ResourceLimit>,
+    flags: Vec<String>,
+}
+
+/// Run the exploit once and store the results.
+/// The process is killed when `stop` fires or is dropped.
+pub async fn execute(
+    pool: DbPool,
+    request: RunRequest,
+    stop: oneshot::Receiver<()>,
+    live_output: OutputSender,
+    events: EventSender,
+    metrics: Metrics,
+) {
+    let new_run = NewExploitRun {
+        exploit_id: request.exploit_id,
+        team_id: request.team_id,
+        command: request.command(),
+        tick: request.tick,
+        start_time: Utc::now(),
+        version_id: request.version_id,
+        commit_hash: request.commit_hash.clone(),
+    };
+    let insert_pool = pool.clone();
+    let run = tokio::task::spawn_blocking(move || -> Result<_, db::Error> {
+        let conn = &mut insert_pool.get()?;
+        exploit::add_run(conn, new_run)
+    })
+    .await;
+    let run = match run {
+        Ok(Ok(run)) => {
+            metrics
+                .runs_started
+                .with_label_values(&[&run.exploit_id.to_string()])
+                .inc();
+            events::publish(&events, Event::RunStarted(run.clone()));
+            run
+        }
+        Ok(Err(err)) => {
+            log::error!("Failed to store exploit run: {}", err);
+            return;
+        }
+        Err(err) => {
+            log::error!("Failed to store exploit run: {}", err);
+            return;
+        }
+    };
+
+    let mut output = OutputBuffer::new(
+        request.output_max_bytes,
+        live_output,
+        run.id,
+        run.exploit_id,
+        run.team_id,
+        request.secrets.clone(),
+    );
+    let outcome = run_or_report(&request, stop, &mut output).await;
+
+    let run_id = run.id;
+    let result = tokio::task::spawn_blocking(move || -> Result<_, db::Error> {
+        let conn = &mut pool.get()?;
+        let run_output = ExploitRunOutput::compress(run.id, output.data(), output.truncated())?;
+        exploit::add_run_output(conn, &run_output)?;
+        let finished_run = exploit::finish_run(
+            conn,
+            run.id,
+            outcome.exit_code,
+            outcome.timed_out,
+            outcome.killed,
+            &outcome.limits_hit,
+        )?;
+        let new_flags = flag_submitter::add_found_flags(
+            conn,
+            run.id,
+            run.tick,
+            &outcome.flags,
+            Utc::now(),
+            request.flag_submission_result,
+        )?;
+        let restored = if finished_run.is_successful() {
+            team::restore_target(conn, run.team_id, &request.target_challenge)?
+        } else {
+            None
+        };
+        log::debug!(
+            "Exploit {} against team {} found {} flags ({} new)",
+            run.exploit_id,
+            run.team_id,
+            outcome.flags.len(),
+            new_flags.len()
+        );
+        Ok((finished_run, new_flags, restored))
+    })
+    .await;
+    match result {
+        Ok(Ok((finished_run, new_flags, restored))) => {
+            let exploit_id = finished_run.exploit_id.to_string();
+            metrics
+                .runs_finished
+                .with_label_values(&[&exploit_id])
+                .inc();
+            if finished_run.timed_out {
+                metrics
+                    .runs_timed_out
+                    .with_label_values(&[&exploit_id])
+                    .inc();
+            }
+            if let Some(end_time) = finished_run.end_time {
+                let duration = (end_time - finished_run.start_time)
+                    .to_std()
+                    .unwrap_or_default();
+                metrics
+                    .run_duration
+                    .with_label_values(&[&exploit_id])
+                    .observe(duration.as_secs_f64());
+            }
+            metrics
+                .flags_found
+                .with_label_values(&[&exploit_id])
+                .inc_by(new_flags.len() as u64);
+            events::publish(&events, Event::RunFinished(finished_run));
+            if !new_flags.is_empty() {
+                events::publish(
+                    &events,
+                    Event::FlagsFound {
+                        exploit_run_id: run_id,
+                        flags: new_flags,
+                    },
+                );
+            }
+            if let Some(target) = restored {
+                log::info!(
+                    "Team {} responds to {} again",
+                    target.team_id,
+                    target.target_challenge
+                );
+                events::publish(&events, Event::TargetRestored(target));
+            }
+        }
+        Ok(Err(err)) => log::error!("Failed to store result of exploit run {}: {}", run_id, err),
+        Err(err) => log::error!("Failed to store result of exploit run {}: {}", run_id, err),
+    }
+}
+
+/// Start the process if the command could be prepared, otherwise note why not in the output.
+async fn run_or_report(
+    request: &RunRequest,
+    stop: oneshot::Receiver<()>,
+    output: &mut OutputBuffer,
+) -> RunOutcome {
+    match &request.argv {
+        Ok(argv) => match run_process(argv, request, stop, output).await {
+            Ok(outcome) => outcome,
+            Err(err) => {
+                log::warn!(
+                    "Failed to run exploit {} against team {}: {}",
+                    request.exploit_id,
+                    request.team_id,
+                    err
+                );
+                output.push_message(&format!("Failed to run exploit: {err}"));
+                RunOutcome::default()
+            }
+        },
+        Err(err) => {
+            log::warn!(
+                "Failed to prepare exploit {} for team {}: {}",
+                request.exploit_id,
+                request.team_id,
+                err
+            );
+            output.push_message(&format!("Failed to prepare exploit: {err}"));
+            RunOutcome::default()
+        }
+    }
+}
+
+/// Run the process in its own cgroup if one is configured and note the limits it ran into.
+async fn run_process(
+    argv: &[String],
+    request: &RunRequest,
+    stop: oneshot::Receiver<()>,
+    output: &mut OutputBuffer,
+) -> Result<RunOutcome, std::io::Error> {
+    let cgroup = RunCgroup::create(&request.limits, request.exploit_id, request.team_id)?;
+    let mut result = run_limited(argv, request, cgroup.as_ref(), stop, output).await;
+    if let Some(cgroup) = cgroup {
+        if let Ok(outcome) = &mut result {
+            outcome.limits_hit.extend(cgroup.limits_hit());
+        }
+        cgroup.remove().await;
+    }
+    if let Ok(outcome) = &result {
+        for limit in &outcome.limits_hit {
+            output.push_message(&request.limits.describe(*limit));
+        }
+    }
+    result
+}
+
+/// Variables exploits get from the environment of anthill. Everything else, like the
+/// `DATABASE_URL`, is kept from them.
+const INHERITED_ENVIRONMENT: [&str; 3] = ["PATH", "HOME", "LANG"];
+
+async fn run_limited(
+    argv: &[String],
+    request: &RunRequest,
+    cgroup: Option<&RunCgroup>,
+    mut stop: oneshot::Receiver<()>,
+    output: &mut OutputBuffer,
+) -> Result<RunOutcome, std::io::Error> {
+    let mut command = Command::new(&argv[0]);
+    request.limits.apply(&mut command, cgroup);
+    let mut child = command
+        .args(&argv[1..])
+        .current_dir(&request.working_directory)
+        .env_clear()
+        .envs(
+            INHERITED_ENVIRONMENT
+                .iter()
+                .filter_map(|name| Some((name, std::env::var_os(name)?))),
+        )
+        .envs(
+            request
+                .environment
+                .iter()
+                .map(|(name, value)| (name, value)),
+        )
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let pid = child.id().expect("the process wasn't waited for yet");
+    let process_group = ProcessGroup::of(pid);
+    let mut stdout = LineReader::new(child.stdout.take().expect("stdout is piped"));
+    let mut stderr = LineReader::new(child.stderr.take().expect("stderr is piped"));
+    let mut stdout_open = true;
+    let mut stderr_open = true;
+    let mut exited = false;
+    let mut cpu_used = None;
+    let mut rlimit_errors = Vec::new();
+
+    let deadline = tokio::time::sleep(request.timeout);
+    tokio::pin!(deadline);
+    let exit = sandbox::wait_for_exit(pid);
+    tokio::pin!(exit);
+
+    let mut outcome = RunOutcome::default();
+    let status = loop {
+        tokio::select! {
+            line = stdout.next_line(), if stdout_open => match line? {
+                Some(line) => {
+                    scan_line(request, &line, &mut outcome.flags, &mut rlimit_errors);
+                    output.push_line(Stream::Stdout, &line);
+                }
+                None => stdout_open = false,
+            },
+            line = stderr.next_line(), if stderr_open => match line? {
+                Some(line) => {
+                    scan_line(request, &line, &mut outcome.flags, &mut rlimit_errors);
+                    output.push_line(Stream::Stderr, &line);
+                }
+                None => stderr_open = false,
+            },
+            _ = &mut exit, if !exited => {
+                exited = true;
+                // The CPU time is gone after the process is reaped by waiting for it.
+                cpu_used = sandbox::cpu_time_used(pid);
+                // Processes left behind would keep the output open until the timeout.
+                process_group.kill();
+            }
+            status = child.wait(), if exited && !stdout_open && !stderr_open => break Some(status?),
+            _ = &mut deadline => {
+                outcome.timed_out = true;
+                output.push_message(&format!("Timed out after {:?}", request.timeout));
+                break None;
+            }
+            _ = &mut stop => {
+                outcome.killed = true;
+                output.push_message("Stopped to start the next run");
+                break None;
+            }
+        }
+    };
+
+    let status = match status {
+        Some(status) => {
+            if request.limits.cpu_time_exceeded(status.signal(), cpu_used) {
+                outcome.limits_hit.push(ResourceLimit::CpuTime);
+            }
+            if !status.success() {
+                for limit in rlimit_errors {
+                    if !outcome.limits_hit.contains(&limit) {
+                        outcome.limits_hit.push(limit);
+                    }
+                }
+            }
+            status
+        }
+        None => {
+            process_group.kill();
+            child.wait().await?
+        }
+    };
+    outcome.exit_code = status.code();
+    output.push_message(&format!("Process exited with {status}"));
+    Ok(outcome)
+}
+
+/// Collect the flags and the error messages of rlimits in a line of output.
+fn scan_line(
+    request: &RunRequest,
+    line: &[u8],
+    flags: &mut Vec<String>,
+    rlimit_errors: &mut Vec<ResourceLimit>,
+) {
+    let line = String::from_utf8_lossy(line);
+    flag_submitter::extract_flags(&request.flag_regex, &line, flags);
+    if let Some(limit) = request.limits.rlimit_error(&line) {
+        if !rlimit_errors.contains(&limit) {
+            rlimit_errors.push(limit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exploit_runner::output::output_channel;
+    use std::fs;
+    use std::time::Instant;
+
+    fn request(script: &str, timeout: Duration, limits: Limits) -> RunRequest {
+        RunRequest {
+            exploit_id: 1,
+            team_id: 1,
+            target_challenge: "web".to_string(),
+            tick: 1,
+            argv: Ok(vec!["sh".to_string(), "-c".to_string(), script.to_string()]),
+            working_directory: ".".to_string(),
+            version_id: None,
+            commit_hash: None,
+            environment: Vec::new(),
+            secrets: Vec::new(),
+            timeout,
+            limits,
+            repeat_interval: Duration::from_secs(10),
+            overrun_policy: OverrunPolicy::KeepOldAndStartNew,
+            flag_regex: Regex::new("FLAG\\{[a-z]+\\}").unwrap(),
+            output_max_bytes: 4096,
+            flag_submission_result: FlagSubmissionResult::Pending,
+        }
+    }
+
+    async fn run(request: &RunRequest) -> (RunOutcome, String) {
+        let mut output = OutputBuffer::new(4096, output_channel(), 0, 1, 1, Vec::new());
+        let (_stop_tx, stop) = oneshot::channel();
+        let outcome = run_or_report(request, stop, &mut output).await;
+        (outcome, String::from_utf8_lossy(output.data()).into_owned())
+    }
+
+    /// Is the process gone within a second? Left behind processes may stay zombies
+    /// if nothing reaps them.
+    async fn is_gone(pid: &str) -> bool {
+        for _ in 0..50 {
+            let stat = fs::read_to_string(format!("/proc/{pid}/stat")).unwrap_or_default();
+            let state = stat.rsplit_once(')').map(|(_, rest)| rest.trim_start());
+            if state.is_none_or(|state| state.starts_with('Z')) {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    fn background_pid(output: &str) -> &str {
+        output
+            .lines()
+            .find_map(|line| line.split_once("[stdout] pid="))
+            .unwrap()
+            .1
+    }
+
+    #[tokio::test]
+    async fn left_behind_processes_are_killed_when_the_exploit_exits() {
+        let request = request(
+            "sleep 30 & echo pid=$!; echo FLAG{found}",
+            Duration::from_secs(10),
+            Limits::default(),
+        );
+        let start = Instant::now();
+        let (outcome, output) = run(&request).await;
+        assert!(start.elapsed() < Duration::from_secs(5), "{output}");
+        assert!(!outcome.timed_out);
+        assert_eq!(outcome.exit_code, Some(0));
+        assert_eq!(outcome.flags, ["FLAG{found}"]);
+        assert!(is_gone(background_pid(&output)).await);
+    }
+
+    #[tokio::test]
+    async fn timeout_kills_the_process_group() {
+        let request = request(
+            "sleep 30 & echo pid=$!; wait",
+            Duration::from_millis(500),
+            Limits::default(),
+        );
+        let (outcome, output) = run(&request).await;
+        assert!(outcome.timed_out);
+        assert!(is_gone(background_pid(&output)).await);
+    }
+
+    #[tokio::test]
+    async fn rlimit_errors_are_noted_for_failed_runs() {
+        let limits = Limits {
+            open_files: 64,
+            ..Limits::default()
+        };
+        let failed = request(
+            "echo 'open: Too many open files' >&2; exit 1",
+            Duration::from_secs(10),
+            limits.clone(),
+        );
+        let (outcome, output) = run(&failed).await;
+        assert_eq!(outcome.limits_hit, [ResourceLimit::OpenFiles]);
+        assert!(output.contains("open files limit of 64"), "{output}");
+
+        let succeeded = request(
+            "echo 'open: Too many open files' >&2",
+            Duration::from_secs(10),
+            limits,
+        );
+        let (outcome, _) = run(&succeeded).await;
+        assert!(outcome.limits_hit.is_empty());
+    }
+}
@@ -0,0 +1,464 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use tokio::process::Command;
+
+use crate::exploit::{Exploit, ResourceLimit};
+use crate::settings::Settings;
+
+/// Length of a cgroup CPU period in microseconds. The quota is a share of it.
+const CPU_PERIOD: u64 = 100_000;
+
+/// Used to give every run cgroup a unique name.
+static NEXT_CGROUP: AtomicU64 = AtomicU64::new(0);
+
+/// Parts of the error messages of calls which failed because of an rlimit,
+/// like the ones of ENOMEM, EAGAIN and EMFILE.
+const RLIMIT_ERRORS: [(ResourceLimit, &str); 9] = [
+    (ResourceLimit::AddressSpace, "Cannot allocate memory"),
+    (ResourceLimit::AddressSpace, "MemoryError"),
+    (ResourceLimit::AddressSpace, "out of memory"),
+    (ResourceLimit::AddressSpace, "memory allocation of"),
+    (ResourceLimit::AddressSpace, "std::bad_alloc"),
+    (ResourceLimit::Processes, "Resource temporarily unavailable"),
+    (ResourceLimit::Processes, "can't start new thread"),
+    (ResourceLimit::Processes, "Cannot fork"),
+    (ResourceLimit::OpenFiles, "Too many open files"),
+];
+
+/// Resource limits of the processes of one run. 0 is unlimited.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// CPU time in seconds.
+    pub cpu_time: u64,
+    /// Virtual memory in MiB.
+    pub memory: u64,
+    pub processes: u64,
+    pub open_files: u64,
+    /// Delegated cgroup v2 directory to create the cgroup of the run in.
+    pub cgroup_parent: Option<PathBuf>,
+    /// Memory in MiB of all processes in the cgroup.
+    pub cgroup_memory_max: u64,
+    /// Percent of one CPU all processes in the cgroup may use.
+    pub cgroup_cpu_max: u64,
+}
+
+impl Limits {
+    /// Limits of the settings with the overrides of the exploit applied.
+    pub fn new(settings: &Settings, exploit: &Exploit) -> Self {
+        let limit = |value: Option<i32>, default: i32| value.unwrap_or(default).max(0) as u64;
+        Self {
+            cpu_time: limit(exploit.cpu_time_limit, settings.run_cpu_time_limit),
+            memory: limit(exploit.memory_limit, settings.run_memory_limit),
+            processes: limit(exploit.process_limit, settings.run_process_limit),
+            open_files: limit(exploit.open_files_limit, settings.run_open_files_limit),
+            cgroup_parent: Some(settings.run_cgroup_path.trim())
+                .filter(|path| !path.is_empty())
+                .map(PathBuf::from),
+            cgroup_memory_max: limit(exploit.cgroup_memory_max, settings.run_cgroup_memory_max),
+            cgroup_cpu_max: limit(exploit.cgroup_cpu_max, settings.run_cgroup_cpu_max),
+        }
+    }
+
+    /// Limit the process started by the command. It's moved into the cgroup before
+    /// the exploit is executed, so all processes it starts are accounted to the cgroup.
+    /// It gets its own process group, so the processes it starts can be killed with it.
+    pub fn apply(&self, command: &mut Command, cgroup: Option<&RunCgroup>) {
+        command.process_group(0);
+
+        // The cgroup limits the processes of the whole run, which is more accurate than
+        // RLIMIT_NPROC counting all processes of the user.
+        let processes = if cgroup.is_some() { 0 } else { self.processes };
+        let rlimits = [
+            // The process gets SIGXCPU at the soft limit and SIGKILL one second later.
+            (libc::RLIMIT_CPU, self.cpu_time, self.cpu_time + 1),
+            (libc::RLIMIT_AS, self.memory << 20, self.memory << 20),
+            (libc::RLIMIT_NPROC, processes, processes),
+            (libc::RLIMIT_NOFILE, self.open_files, self.open_files),
+        ];
+        let procs_fd = cgroup.map(|cgroup| cgroup.procs.as_raw_fd());
+        if procs_fd.is_none() && rlimits.iter().all(|(_, soft, _)| *soft == 0) {
+            return;
+        }
+
+        // SAFETY: The closure runs between fork and exec and only calls
+        // async-signal-safe functions without allocating.
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(fd) = procs_fd {
+                    let pid = b"0";
+                    if libc::write(fd, pid.as_ptr().cast(), pid.len()) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                for (resource, soft, hard) in rlimits {
+                    if soft == 0 {
+                        continue;
+                    }
+                    let mut limit = libc::rlimit {
+                        rlim_cur: 0,
+                        rlim_max: 0,
+                    };
+                    if libc::getrlimit(resource, &mut limit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                    // Only root may raise the hard limit.
+                    let limit = libc::rlimit {
+                        rlim_cur: soft.min(limit.rlim_max),
+                        rlim_max: hard.min(limit.rlim_max),
+                    };
+                    if libc::setrlimit(resource, &limit) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Did the process use up its CPU time? It gets SIGXCPU at the soft limit.
+    /// If it handles that, the SIGKILL at the hard limit is only told apart from
+    /// other kills by the CPU time it used.
+    pub fn cpu_time_exceeded(&self, signal: Option<i32>, cpu_used: Option<Duration>) -> bool {
+        match signal {
+            Some(libc::SIGXCPU) => true,
+            Some(libc::SIGKILL) => {
+                self.cpu_time > 0
+                    && cpu_used.is_some_and(|used| used >= Duration::from_secs(self.cpu_time))
+            }
+            _ => false,
+        }
+    }
+
+    /// Rlimit an error message in the output of a failed run probably comes from.
+    /// Running into an rlimit only makes calls fail, so there's nothing else to notice it by.
+    pub fn rlimit_error(&self, line: &str) -> Option<ResourceLimit> {
+        RLIMIT_ERRORS
+            .iter()
+            .find(|(_, message)| line.contains(message))
+            .map(|(limit, _)| *limit)
+            .filter(|limit| match limit {
+                ResourceLimit::AddressSpace => self.memory > 0,
+                // The cgroup enforces the process limit instead of the rlimit.
+                ResourceLimit::Processes => self.processes > 0 && self.cgroup_parent.is_none(),
+                ResourceLimit::OpenFiles => self.open_files > 0,
+                _ => false,
+            })
+    }
+
+    /// Message added to the output of a run which ran into the limit.
+    ///
+    /// The limits of the cgroup may also be hit because of the limits of its parent,
+    /// so they're only named if they're configured.
+    pub fn describe(&self, limit: ResourceLimit) -> String {
+        match limit {
+            ResourceLimit::CpuTime => format!("CPU time limit of {}s exceeded", self.cpu_time),
+            ResourceLimit::Memory if self.cgroup_memory_max > 0 => format!(
+                "Memory limit of {} MiB of the run reached",
+                self.cgroup_memory_max
+            ),
+            ResourceLimit::Memory => "Memory limit of the cgroup reached".to_string(),
+            ResourceLimit::Processes if self.processes > 0 => {
+                format!("Process limit of {} reached", self.processes)
+            }
+            ResourceLimit::Processes => "Process limit of the cgroup reached".to_string(),
+            ResourceLimit::CpuQuota if self.cgroup_cpu_max > 0 => format!(
+                "Throttled to the CPU limit of {}% of one CPU",
+                self.cgroup_cpu_max
+            ),
+            ResourceLimit::CpuQuota => "Throttled by the CPU limit of the cgroup".to_string(),
+            ResourceLimit::AddressSpace => format!(
+                "Probably failed because of the address space limit of {} MiB",
+                self.memory
+            ),
+            ResourceLimit::OpenFiles => format!(
+                "Probably failed because of the open files limit of {}",
+                self.open_files
+            ),
+        }
+    }
+}
+
+/// The cgroup holding all processes of one run. Remove it with `remove` after the run.
+pub struct RunCgroup {
+    path: PathBuf,
+    /// Opened before starting the process, so it can move itself in without allocating.
+    procs: File,
+}
+
+impl RunCgroup {
+    /// Create a new cgroup for a run if the limits ask for one.
+    pub fn create(limits: &Limits, exploit_id: i32, team_id: i32) -> io::Result<Option<Self>> {
+        let Some(parent) = &limits.cgroup_parent else {
+            return Ok(None);
+        };
+        let path = parent.join(format!(
+            "anthill-{}-exploit-{}-team-{}-{}",
+            std::process::id(),
+            exploit_id,
+            team_id,
+            NEXT_CGROUP.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir(&path).map_err(|err| cgroup_error(&path, "create", err))?;
+        match Self::configure(path.clone(), limits) {
+            Ok(cgroup) => Ok(Some(cgroup)),
+            Err(err) => {
+                let _ = fs::remove_dir(&path);
+                Err(err)
+            }
+        }
+    }
+
+    fn configure(path: PathBuf, limits: &Limits) -> io::Result<Self> {
+        let mut controllers = Vec::new();
+        if limits.cgroup_memory_max > 0 {
+            controllers.push("+memory");
+        }
+        if limits.cgroup_cpu_max > 0 {
+            controllers.push("+cpu");
+        }
+        if limits.processes > 0 {
+            controllers.push("+pids");
+        }
+        if !controllers.is_empty() {
+            // Usually enabled once by whoever delegated the cgroup. Writing the limits
+            // below fails with a clear error if a controller is still missing.
+            let parent = path.parent().expect("run cgroups have a parent");
+            let _ = fs::write(parent.join("cgroup.subtree_control"), controllers.join(" "));
+        }
+
+        if limits.cgroup_memory_max > 0 {
+            write_control(
+                &path,
+                "memory.max",
+                &(limits.cgroup_memory_max << 20).to_string(),
+            )?;
+        }
+        if limits.cgroup_cpu_max > 0 {
+            let quota = limits.cgroup_cpu_max * CPU_PERIOD / 100;
+            write_control(&path, "cpu.max", &format!("{quota} {CPU_PERIOD}"))?;
+        }
+        if limits.processes > 0 {
+            write_control(&path, "pids.max", &limits.processes.to_string())?;
+        }
+
+        let procs = OpenOptions::new()
+            .write(true)
+            .open(path.join("cgroup.procs"))
+            .map_err(|err| cgroup_error(&path, "open cgroup.procs of", err))?;
+        Ok(Self { path, procs })
+    }
+
+    /// Limits of the cgroup the processes ran into so far.
+    pub fn limits_hit(&self) -> Vec<ResourceLimit> {
+        let mut limits = Vec::new();
+        if self.counter("memory.events", "max") > 0 || self.counter("memory.events", "oom_kill") > 0
+        {
+            limits.push(ResourceLimit::Memory);
+        }
+        if self.counter("pids.events", "max") > 0 {
+            limits.push(ResourceLimit::Processes);
+        }
+        if self.counter("cpu.stat", "nr_throttled") > 0 {
+            limits.push(ResourceLimit::CpuQuota);
+        }
+        limits
+    }
+
+    /// Value of a counter in a flat keyed file of the cgroup. 0 if the file doesn't exist.
+    fn counter(&self, file: &str, key: &str) -> u64 {
+        fs::read_to_string(self.path.join(file))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .find(|(name, _)| *name == key)
+            .and_then(|(_, value)| value.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Kill the processes the exploit left behind and remove the cgroup.
+    pub async fn remove(self) {
+        drop(self.procs);
+        if fs::write(self.path.join("cgroup.kill"), "1").is_err() {
+            // cgroup.kill is only available since Linux 5.14.
+            for pid in fs::read_to_string(self.path.join("cgroup.procs"))
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|pid| pid.parse::<libc::pid_t>().ok())
+            {
+                // SAFETY: Sending a signal has no memory safety requirements.
+                unsafe { libc::kill(pid, libc::SIGKILL) };
+            }
+        }
+        // The cgroup can only be removed once the killed processes are gone.
+        let mut result = fs::remove_dir(&self.path);
+        for _ in 0..50 {
+            if result.is_ok() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            result = fs::remove_dir(&self.path);
+        }
+        if let Err(err) = result {
+            log::warn!("Failed to remove cgroup {}: {}", self.path.display(), err);
+        }
+    }
+}
+
+/// The process group of a run. All processes in it are killed when it's dropped,
+/// also the ones the exploit started and left behind.
+pub struct ProcessGroup(libc::pid_t);
+
+impl ProcessGroup {
+    /// Group of the process started with `Limits::apply`.
+    pub fn of(pid: u32) -> Self {
+        Self(pid as libc::pid_t)
+    }
+
+    pub fn kill(&self) {
+        // SAFETY: Sending a signal has no memory safety requirements.
+        unsafe { libc::killpg(self.0, libc::SIGKILL) };
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// Wait until the process exited without reaping it, so its CPU time can still be read.
+pub async fn wait_for_exit(pid: u32) {
+    let _ = tokio::task::spawn_blocking(move || {
+        // SAFETY: siginfo_t is plain data for which all zeros is valid.
+        let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+        loop {
+            // SAFETY: waitid only writes to the siginfo_t it gets.
+            let result = unsafe {
+                libc::waitid(
+                    libc::P_PID,
+                    pid as libc::id_t,
+                    &mut info,
+                    libc::WEXITED | libc::WNOWAIT,
+                )
+            };
+            if result == 0 || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                return;
+            }
+        }
+    })
+    .await;
+}
+
+/// CPU time the process used so far. Still available after it exited until it's reaped.
+pub fn cpu_time_used(pid: u32) -> Option<Duration> {
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The name of the process in parentheses may contain spaces. utime and stime
+    // are the 14th and 15th field.
+    let mut fields = stat.rsplit_once(')')?.1.split_whitespace().skip(11);
+    let utime = fields.next()?.parse::<u64>().ok()?;
+    let stime = fields.next()?.parse::<u64>().ok()?;
+    // SAFETY: sysconf has no memory safety requirements.
+    let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_second <= 0 {
+        return None;
+    }
+    Some(Duration::from_secs_f64(
+        (utime + stime) as f64 / ticks_per_second as f64,
+    ))
+}
+
+fn write_control(cgroup: &Path, file: &str, value: &str) -> io::Result<()> {
+    fs::write(cgroup.join(file), value)
+        .map_err(|err| cgroup_error(cgroup, &format!("set {file} of"), err))
+}
+
+fn cgroup_error(cgroup: &Path, action: &str, err: io::Error) -> io::Error {
+    io::Error::new(
+        err.kind(),
+        format!("Failed to {action} cgroup {}: {err}", cgroup.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_time_exceeded_by_signal_and_usage() {
+        let limits = Limits {
+            cpu_time: 2,
+            ..Limits::default()
+        };
+        let used = |secs| Some(Duration::from_secs_f64(secs));
+        assert!(limits.cpu_time_exceeded(Some(libc::SIGXCPU), None));
+        assert!(limits.cpu_time_exceeded(Some(libc::SIGKILL), used(2.99)));
+        assert!(!limits.cpu_time_exceeded(Some(libc::SIGKILL), used(0.5)));
+        assert!(!limits.cpu_time_exceeded(Some(libc::SIGKILL), None));
+        assert!(!limits.cpu_time_exceeded(None, used(3.0)));
+        assert!(!Limits::default().cpu_time_exceeded(Some(libc::SIGKILL), used(3.0)));
+    }
+
+    #[test]
+    fn describe_names_configured_limits_only() {
+        let limits = Limits {
+            memory: 512,
+            cgroup_cpu_max: 50,
+            ..Limits::default()
+        };
+        assert_eq!(
+            limits.describe(ResourceLimit::Memory),
+            "Memory limit of the cgroup reached"
+        );
+        assert_eq!(
+            limits.describe(ResourceLimit::CpuQuota),
+            "Throttled to the CPU limit of 50% of one CPU"
+        );
+        let limits = Limits {
+            cgroup_memory_max: 256,
+            ..limits
+        };
+        assert_eq!(
+            limits.describe(ResourceLimit::Memory),
+            "Memory limit of 256 MiB of the run reached"
+        );
+    }
+
+    #[test]
+    fn rlimit_error_of_configured_limits_only() {
+        let limits = Limits {
+            memory: 512,
+            processes: 64,
+            ..Limits::default()
+        };
+        assert_eq!(
+            limits.rlimit_error("bash: fork: retry: Resource temporarily unavailable"),
+            Some(ResourceLimit::Processes)
+        );
+        assert_eq!(
+            limits.rlimit_error("MemoryError"),
+            Some(ResourceLimit::AddressSpace)
+        );
+        assert_eq!(limits.rlimit_error("socket: Too many open files"), None);
+        assert_eq!(limits.rlimit_error("Connection refused"), None);
+
+        let limits = Limits {
+            cgroup_parent: Some(PathBuf::from("/sys/fs/cgroup/anthill")),
+            ..limits
+        };
+        assert_eq!(
+            limits.rlimit_error("bash: fork: retry: Resource temporarily unavailable"),
+            None
+        );
+    }
+
+    #[test]
+    fn cpu_time_used_of_running_process() {
+        assert!(cpu_time_used(std::process::id()).is_some());
+        assert!(cpu_time_used(u32::MAX).is_none());
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+/// Values available as `{name}` placeholders in the policy `argv_pattern` and the exploit command.
+pub type Variables = HashMap<String, String>;
+
+/// Split the pattern into arguments and replace all `{name}` placeholders.
+///
+/// An argument consisting only of `{exploit.command}` is replaced by all
+/// arguments of the (expanded) exploit command.
+pub fn expand_argv(
+    pattern: &str,
+    command: &str,
+    variables: &Variables,
+) -> Result<Vec<String>, String> {
+    let words = shlex::split(pattern).ok_or_else(|| format!("Invalid quoting in: {pattern}"))?;
+
+    let mut argv = Vec::new();
+    for word in words {
+        if word == "{exploit.command}" {
+            let command_words =
+                shlex::split(command).ok_or_else(|| format!("Invalid quoting in: {command}"))?;
+            for command_word in command_words {
+                argv.push(expand(&command_word, variables)?);
+            }
+        } else {
+            argv.push(expand(&word, variables)?);
+        }
+    }
+
+    if argv.is_empty() {
+        return Err("Expanded command is empty".to_string());
+    }
+    Ok(argv)
+}
+
+/// Replace all `{name}` placeholders in the template. Use `{{` and `}}` for literal braces.
+pub fn expand(template: &str, variables: &Variables) -> Result<String, String> {
+    let mut result = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                result.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                result.push('}');
+            }
+            '{' => {
+                let name = chars.by_ref().take_while(|c| *c != '}').collect::<String>();
+                match variables.get(&name) {
+                    Some(value) => result.push_str(value),
+                    None => return Err(format!("Unknown template variable: {{{name}}}")),
+                }
+            }
+            c => result.push(c),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> Variables {
+        HashMap::from([
+            ("team.ip".to_string(), "10.0.0.2".to_string()),
+            ("service.port".to_string(), "8080".to_string()),
+            ("flag_id".to_string(), "user 42".to_string()),
+        ])
+    }
+
+    #[test]
+    fn expand_replaces_placeholders() {
+        assert_eq!(
+            expand("http://{team.ip}:{service.port}/", &variables()).unwrap(),
+            "http://10.0.0.2:8080/"
+        );
+    }
+
+    #[test]
+    fn expand_keeps_escaped_braces() {
+        assert_eq!(
+            expand("{{\"ip\": \"{team.ip}\"}}", &variables()).unwrap(),
+            "{\"ip\": \"10.0.0.2\"}"
+        );
+    }
+
+    #[test]
+    fn expand_rejects_unknown_variables() {
+        assert_eq!(
+            expand("{team.name}", &variables()).unwrap_err(),
+            "Unknown template variable: {team.name}"
+        );
+    }
+
+    #[test]
+    fn expand_argv_splits_pattern_and_command() {
+        let argv = expand_argv(
+            "timeout 10 {exploit.command} --port {service.port}",
+            "python3 exploit.py {team.ip} '{flag_id}'",
+            &variables(),
+        )
+        .unwrap();
+        assert_eq!(
+            argv,
+            [
+                "timeout",
+                "10",
+                "python3",
+                "exploit.py",
+                "10.0.0.2",
+                "user 42",
+                "--port",
+                "8080"
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_argv_keeps_values_in_one_argument() {
+        let argv = expand_argv("{exploit.command}", "./x.sh {flag_id}", &variables()).unwrap();
+        assert_eq!(argv, ["./x.sh", "user 42"]);
+    }
+
+    #[test]
+    fn expand_argv_rejects_invalid_commands() {
+        assert!(expand_argv("{exploit.command}", "./x.sh 'open", &variables()).is_err());
+        assert!(expand_argv("{exploit.command}", "", &variables()).is_err());
+    }
+}
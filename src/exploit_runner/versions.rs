@@ -0,0 +1,435 @@
+use std::fs;
+use std::io::{self, Cursor};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use diesel::PgConnection;
+use flate2::read::GzDecoder;
+
+use crate::db;
+use crate::exploit;
+
+/// Used to give every partially unpacked directory a unique name.
+static NEXT_PARTIAL: AtomicU64 = AtomicU64::new(0);
+
+/// How an uploaded file is unpacked. Decided by its file name.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+enum UploadKind {
+    Zip,
+    Tar,
+    TarGz,
+    /// A single script which is made executable.
+    Script,
+}
+
+impl UploadKind {
+    fn from_filename(filename: &str) -> Self {
+        let filename = filename.to_ascii_lowercase();
+        if filename.ends_with(".zip") {
+            UploadKind::Zip
+        } else if filename.ends_with(".tar") {
+            UploadKind::Tar
+        } else if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
+            UploadKind::TarGz
+        } else {
+            UploadKind::Script
+        }
+    }
+}
+
+/// Most bytes an archive may unpack to, so a zip or tar bomb can't fill the disk.
+const MAX_UNPACKED_SIZE: u64 = 512 * 1024 * 1024;
+/// Most files and directories an archive may contain.
+const MAX_UNPACKED_FILES: usize = 10_000;
+
+/// What's left of the files and bytes an archive may unpack to.
+struct UnpackBudget {
+    files: usize,
+    bytes: u64,
+}
+
+impl UnpackBudget {
+    fn new() -> Self {
+        UnpackBudget {
+            files: MAX_UNPACKED_FILES,
+            bytes: MAX_UNPACKED_SIZE,
+        }
+    }
+
+    /// Account for the next entry of the archive.
+    fn take(&mut self, size: u64) -> Result<(), String> {
+        if self.files == 0 {
+            return Err(format!(
+                "Archives may contain at most {MAX_UNPACKED_FILES} files"
+            ));
+        }
+        if size > self.bytes {
+            return Err(format!(
+                "Archives may unpack to at most {MAX_UNPACKED_SIZE} bytes"
+            ));
+        }
+        self.files -= 1;
+        self.bytes -= size;
+        Ok(())
+    }
+}
+
+/// Make sure the uploaded file can be unpacked before storing it.
+pub fn check_upload(filename: &str, content: &[u8]) -> Result<(), String> {
+    check_archive(filename, content, &mut UnpackBudget::new())
+}
+
+fn check_archive(filename: &str, content: &[u8], budget: &mut UnpackBudget) -> Result<(), String> {
+    match UploadKind::from_filename(filename) {
+        UploadKind::Zip => {
+            let mut archive = zip::ZipArchive::new(Cursor::new(content))
+                .map_err(|err| format!("Invalid zip archive: {err}"))?;
+            for index in 0..archive.len() {
+                let file = archive
+                    .by_index(index)
+                    .map_err(|err| format!("Invalid zip archive: {err}"))?;
+                if file.enclosed_name().is_none() {
+                    return Err(format!("Invalid path in zip archive: {}", file.name()));
+                }
+                budget.take(file.size())?;
+            }
+            Ok(())
+        }
+        UploadKind::Tar => check_tar(tar::Archive::new(content), budget),
+        UploadKind::TarGz => check_tar(tar::Archive::new(GzDecoder::new(content)), budget),
+        UploadKind::Script => Ok(()),
+    }
+}
+
+fn check_tar<R: io::Read>(
+    mut archive: tar::Archive<R>,
+    budget: &mut UnpackBudget,
+) -> Result<(), String> {
+    let entries = archive
+        .entries()
+        .map_err(|err| format!("Invalid tar archive: {err}"))?;
+    for entry in entries {
+        let entry = entry.map_err(|err| format!("Invalid tar archive: {err}"))?;
+        check_tar_entry(&entry, budget)?;
+    }
+    Ok(())
+}
+
+fn check_tar_entry<R: io::Read>(
+    entry: &tar::Entry<R>,
+    budget: &mut UnpackBudget,
+) -> Result<(), String> {
+    let path = entry
+        .path()
+        .map_err(|err| format!("Invalid tar archive: {err}"))?;
+    if path.is_absolute()
+        || path
+            .components()
+            .any(|component| component == std::path::Component::ParentDir)
+    {
+        return Err(format!("Invalid path in tar archive: {}", path.display()));
+    }
+    // Sparse files unpack to more than their size in the archive.
+    if entry.header().entry_type() == tar::EntryType::GNUSparse {
+        return Err(format!(
+            "Sparse files aren't supported in tar archives: {}",
+            path.display()
+        ));
+    }
+    budget.take(entry.size())
+}
+
+fn unpack(
+    filename: &str,
+    content: &[u8],
+    destination: &Path,
+    budget: &mut UnpackBudget,
+) -> Result<(), db::Error> {
+    match UploadKind::from_filename(filename) {
+        UploadKind::Zip => unpack_zip(content, destination, budget)?,
+        UploadKind::Tar => unpack_tar(tar::Archive::new(content), destination, budget)?,
+        UploadKind::TarGz => unpack_tar(
+            tar::Archive::new(GzDecoder::new(content)),
+            destination,
+            budget,
+        )?,
+        UploadKind::Script => {
+            let path = destination.join(filename);
+            fs::write(&path, content)?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+    }
+    Ok(())
+}
+
+fn unpack_zip(
+    content: &[u8],
+    destination: &Path,
+    budget: &mut UnpackBudget,
+) -> Result<(), db::Error> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(content))?;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let path = file
+            .enclosed_name()
+            .ok_or_else(|| format!("Invalid path in zip archive: {}", file.name()))?;
+        let path = destination.join(path);
+        budget.take(file.size())?;
+
+        if file.is_dir() {
+            fs::create_dir_all(&path)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // The size in the header was taken from the budget, don't write more than that.
+            let size = file.size();
+            let written = io::copy(
+                &mut io::Read::take(&mut file, size + 1),
+                &mut fs::File::create(&path)?,
+            )?;
+            if written > size {
+                return Err(
+                    format!("File in zip archive is larger than stated: {}", file.name()).into(),
+                );
+            }
+        }
+        if let Some(mode) = file.unix_mode() {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
+fn unpack_tar<R: io::Read>(
+    mut archive: tar::Archive<R>,
+    destination: &Path,
+    budget: &mut UnpackBudget,
+) -> Result<(), db::Error> {
+    // Directories are unpacked last and deepest first like tar::Archive::unpack does,
+    // so their permissions don't keep the files in them from being written.
+    let mut directories = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        check_tar_entry(&entry, budget)?;
+        if entry.header().entry_type() == tar::EntryType::Directory {
+            directories.push(entry);
+        } else {
+            entry.unpack_in(destination)?;
+        }
+    }
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut directory in directories {
+        directory.unpack_in(destination)?;
+    }
+    Ok(())
+}
+
+/// Unique name for a temporary directory next to the final directory `name`,
+/// so runs preparing the same code at the same time don't get in each other's way.
+pub fn partial_name(name: &str) -> String {
+    format!(
+        ".{}.{}-{}.partial",
+        name,
+        std::process::id(),
+        NEXT_PARTIAL.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Directory containing the unpacked version. It's unpacked from the database
+/// into `<storage>/<exploit id>/<version id>` the first time it's needed.
+pub fn ensure_unpacked(
+    conn: &mut PgConnection,
+    storage: &Path,
+    exploit_id: i32,
+    version_id: i32,
+) -> Result<PathBuf, db::Error> {
+    let exploit_dir = storage.join(exploit_id.to_string());
+    let version_dir = exploit_dir.join(version_id.to_string());
+    if version_dir.is_dir() {
+        return Ok(version_dir.canonicalize()?);
+    }
+
+    let version = exploit::find_version_by_id(conn, version_id)?
+        .filter(|version| version.exploit_id == exploit_id)
+        .ok_or_else(|| format!("Version {version_id} of exploit {exploit_id} doesn't exist"))?;
+    let content = exploit::load_version_content(conn, version_id)?;
+
+    let version_dir = unpack_version(
+        &version.filename,
+        &content,
+        &exploit_dir,
+        &version_id.to_string(),
+    )?;
+    log::info!(
+        "Unpacked version {} of exploit {} to {}",
+        version_id,
+        exploit_id,
+        version_dir.display()
+    );
+    Ok(version_dir.canonicalize()?)
+}
+
+/// Unpack the uploaded file into `<directory>/<name>`. It's unpacked next to it first,
+/// so a half written version is never run.
+fn unpack_version(
+    filename: &str,
+    content: &[u8],
+    directory: &Path,
+    name: &str,
+) -> Result<PathBuf, db::Error> {
+    let version_dir = directory.join(name);
+    let partial_dir = directory.join(partial_name(name));
+    fs::create_dir_all(&partial_dir)?;
+    if let Err(err) = unpack(filename, content, &partial_dir, &mut UnpackBudget::new()) {
+        let _ = fs::remove_dir_all(&partial_dir);
+        return Err(err);
+    }
+    if let Err(err) = fs::rename(&partial_dir, &version_dir) {
+        let _ = fs::remove_dir_all(&partial_dir);
+        // Another run of the version unpacked it at the same time.
+        if version_dir.is_dir() {
+            return Ok(version_dir);
+        }
+        return Err(err.into());
+    }
+    Ok(version_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn zip_archive(files: &[(&str, u32, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, mode, data) in files {
+            let options = zip::write::FileOptions::default().unix_permissions(*mode);
+            writer.start_file(*name, options).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// Writes the paths as they are, tar::Builder refuses to add unsafe ones.
+    fn tar_archive(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_ustar();
+            header.as_ustar_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "anthill-versions-test-{}-{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn check_upload_rejects_unsafe_paths() {
+        for path in ["../exploit.sh", "/tmp/exploit.sh", "lib/../../exploit.sh"] {
+            let zip = zip_archive(&[(path, 0o644, b"echo")]);
+            assert!(check_upload("exploit.zip", &zip).is_err(), "{path}");
+            let tar = tar_archive(&[(path, b"echo")]);
+            assert!(check_upload("exploit.tar", &tar).is_err(), "{path}");
+            assert!(check_upload("exploit.tgz", &gzip(&tar)).is_err(), "{path}");
+        }
+
+        let zip = zip_archive(&[("lib/exploit.sh", 0o644, b"echo")]);
+        assert_eq!(check_upload("exploit.zip", &zip), Ok(()));
+        let tar = tar_archive(&[("lib/exploit.sh", b"echo")]);
+        assert_eq!(check_upload("exploit.tar", &tar), Ok(()));
+        assert_eq!(check_upload("exploit.tar.gz", &gzip(&tar)), Ok(()));
+    }
+
+    #[test]
+    fn check_upload_limits_unpacked_size() {
+        let zip = zip_archive(&[("a", 0o644, b"1234"), ("b", 0o644, b"1234")]);
+        let tar = tar_archive(&[("a", b"1234"), ("b", b"1234")]);
+        for (filename, content) in [("exploit.zip", &zip), ("exploit.tar", &tar)] {
+            let mut budget = UnpackBudget { files: 2, bytes: 8 };
+            assert_eq!(check_archive(filename, content, &mut budget), Ok(()));
+            let mut budget = UnpackBudget { files: 1, bytes: 8 };
+            assert!(check_archive(filename, content, &mut budget).is_err());
+            let mut budget = UnpackBudget { files: 2, bytes: 7 };
+            assert!(check_archive(filename, content, &mut budget).is_err());
+        }
+    }
+
+    #[test]
+    fn unpack_rejects_unsafe_paths() {
+        let dir = test_dir("unsafe");
+        let destination = dir.join("version");
+        fs::create_dir_all(&destination).unwrap();
+
+        let zip = zip_archive(&[("../exploit.sh", 0o644, b"echo")]);
+        let tar = tar_archive(&[("../exploit.sh", b"echo")]);
+        for (filename, content) in [("exploit.zip", zip), ("exploit.tar", tar)] {
+            let mut budget = UnpackBudget::new();
+            assert!(unpack(filename, &content, &destination, &mut budget).is_err());
+            assert!(!dir.join("exploit.sh").exists());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unpack_version_keeps_modes() {
+        let dir = test_dir("modes");
+        let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let version = unpack_version("exploit.py", b"print()", &dir, "1").unwrap();
+        assert_eq!(fs::read(version.join("exploit.py")).unwrap(), b"print()");
+        assert_eq!(mode(version.join("exploit.py")), 0o755);
+
+        let zip = zip_archive(&[
+            ("lib/exploit.sh", 0o755, b"echo"),
+            ("lib/data.txt", 0o644, b"data"),
+        ]);
+        let version = unpack_version("exploit.zip", &zip, &dir, "2").unwrap();
+        assert_eq!(mode(version.join("lib/exploit.sh")), 0o755);
+        assert_eq!(mode(version.join("lib/data.txt")), 0o644);
+
+        let tar = tar_archive(&[("lib/data.txt", b"data")]);
+        let version = unpack_version("exploit.tar.gz", &gzip(&tar), &dir, "3").unwrap();
+        assert_eq!(fs::read(version.join("lib/data.txt")).unwrap(), b"data");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unpack_version_leaves_no_partial_directory() {
+        let dir = test_dir("partial");
+
+        let tar = tar_archive(&[("../exploit.sh", b"echo")]);
+        assert!(unpack_version("exploit.tar", &tar, &dir, "1").is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+
+        // Unpacked by another run at the same time.
+        fs::create_dir_all(dir.join("2")).unwrap();
+        fs::write(dir.join("2/exploit.sh"), "echo").unwrap();
+        let version = unpack_version("exploit.sh", b"echo", &dir, "2").unwrap();
+        assert_eq!(version, dir.join("2"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
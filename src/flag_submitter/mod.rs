@@ -1,8 +1,40 @@
-use std::time::{Instant};
-use crate::exploit::ExploitRun;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
 
-#[derive(PartialEq, Debug)]
-enum FlagSubmissionResult {
+use crate::db;
+use crate::events::FlagSubmission;
+use crate::schema::{
+    exploit_runs, flag_occurrences, flag_response_rules, flags, unknown_flag_responses,
+};
+use diesel::deserialize::{self, FromSql};
+use diesel::dsl;
+use diesel::pg::{Pg, PgValue};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::*;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+mod submitter;
+pub use submitter::FlagSubmitter;
+
+pub const MAX_FLAGS_PER_PAGE: i64 = 500;
+
+#[derive(
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Copy,
+    Clone,
+    Debug,
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+)]
+#[diesel(sql_type = SmallInt)]
+pub enum FlagSubmissionResult {
     /// A valid flag that gained points.
     Valid,
     /// This flag was submitted previously.
@@ -19,54 +51,665 @@ enum FlagSubmissionResult {
     Error,
     /// The flag wasn't sent to the submission server yet.
     Pending,
+    /// The answer of the submission server didn't match any response rule.
+    Unknown,
+    /// The submission server refused the flag because we submitted too many.
+    RateLimited,
 }
 
-#[derive(Debug)]
-struct Flag {
+impl ToSql<SmallInt, Pg> for FlagSubmissionResult {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        let v = match *self {
+            FlagSubmissionResult::Valid => 1,
+            FlagSubmissionResult::AlreadySubmitted => 2,
+            FlagSubmissionResult::Invalid => 3,
+            FlagSubmissionResult::Expired => 4,
+            FlagSubmissionResult::Own => 5,
+            FlagSubmissionResult::NOPTeam => 6,
+            FlagSubmissionResult::Error => 7,
+            FlagSubmissionResult::Pending => 8,
+            FlagSubmissionResult::Unknown => 9,
+            FlagSubmissionResult::RateLimited => 10,
+        };
+        <i16 as ToSql<SmallInt, Pg>>::to_sql(&v, &mut out.reborrow())
+    }
+}
+
+impl FromSql<SmallInt, Pg> for FlagSubmissionResult
+where
+    i16: FromSql<SmallInt, Pg>,
+{
+    fn from_sql(bytes: PgValue) -> deserialize::Result<Self> {
+        let v = i16::from_sql(bytes)?;
+        Ok(match v {
+            1 => FlagSubmissionResult::Valid,
+            2 => FlagSubmissionResult::AlreadySubmitted,
+            3 => FlagSubmissionResult::Invalid,
+            4 => FlagSubmissionResult::Expired,
+            5 => FlagSubmissionResult::Own,
+            6 => FlagSubmissionResult::NOPTeam,
+            7 => FlagSubmissionResult::Error,
+            8 => FlagSubmissionResult::Pending,
+            9 => FlagSubmissionResult::Unknown,
+            10 => FlagSubmissionResult::RateLimited,
+            id => return Err(format!("invalid flag submission result id {}", id).into()),
+        })
+    }
+}
+
+impl FlagSubmissionResult {
+    /// Results of flags which are handed to the submitter (again).
+    pub const UNSUBMITTED: [FlagSubmissionResult; 3] = [
+        FlagSubmissionResult::Pending,
+        FlagSubmissionResult::Error,
+        FlagSubmissionResult::RateLimited,
+    ];
+}
+
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+#[diesel(table_name = flags)]
+pub struct Flag {
+    pub id: i64,
     /// Plain flag value that was seen.
-    flag: String,
+    pub flag: String,
+    /// Tick in which the flag was seen first.
+    pub tick: i32,
+    /// The exploit run which found this flag first.
+    pub exploit_run_id: Option<i64>,
     /// Submission time of when it was handed to the flag submission endpoint.
-    submission_time: Instant,
+    pub submission_time: Option<DateTime<Utc>>,
     /// Mapped answer of the submission endpoint if the flag was valid or not.
+    pub submission_result: FlagSubmissionResult,
+    /// The user who added the flag by hand.
+    pub user_id: Option<i32>,
+    /// The team the flag was stolen from if it was added by hand.
+    pub team_id: Option<i32>,
+    /// The challenge the flag belongs to if it was added by hand.
+    pub target_challenge: Option<String>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = flags)]
+struct NewFlag<'a> {
+    flag: &'a str,
+    tick: i32,
+    exploit_run_id: Option<i64>,
     submission_result: FlagSubmissionResult,
+    user_id: Option<i32>,
+    team_id: Option<i32>,
+    target_challenge: Option<&'a str>,
 }
 
-impl Flag {
-    fn create(flag: String) -> Flag {
-        Flag {
-            flag,
-            submission_time: Instant::now(),
-            submission_result: FlagSubmissionResult::Pending,
-        }
-    }
+/// A flag together with where it came from.
+#[derive(Queryable, Serialize, Debug)]
+pub struct FlagListEntry {
+    pub flag: Flag,
+    /// The exploit of the run which found the flag first.
+    pub exploit_id: Option<i32>,
+    /// Uploaded version of the exploit which found the flag first.
+    pub exploit_version_id: Option<i32>,
+    /// The team the flag was stolen from, if known.
+    pub team_id: Option<i32>,
+    /// When the run which found the flag first saw it. Not set for flags added by hand.
+    pub collection_time: Option<DateTime<Utc>>,
+}
 
-    fn is_valid(&self) -> bool {
-        self.submission_result == FlagSubmissionResult::Valid
-    }
+/// Filter flags. Flags are returned newest first.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct FlagFilter {
+    pub submission_result: Option<FlagSubmissionResult>,
+    pub team_id: Option<i32>,
+    pub exploit_id: Option<i32>,
+    /// First tick to include.
+    pub tick_from: Option<i32>,
+    /// Last tick to include.
+    pub tick_to: Option<i32>,
+    /// Only flags containing this text.
+    pub search: Option<String>,
+    /// Cursor to continue after. Only flags with a smaller id are returned.
+    pub before: Option<i64>,
+    pub limit: Option<i64>,
+}
 
-    fn is_pending(&self) -> bool {
-        match self.submission_result {
-            FlagSubmissionResult::Error => true,
-            FlagSubmissionResult::Pending => true,
-            _ => false
-        }
-    }
+/// The first valid flag we got for a challenge.
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct FirstBlood {
+    #[diesel(sql_type = Text)]
+    pub challenge: String,
+    #[diesel(sql_type = BigInt)]
+    pub flag_id: i64,
+    #[diesel(sql_type = Text)]
+    pub flag: String,
+    /// Not set for flags added by hand.
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub exploit_id: Option<i32>,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub team_id: Option<i32>,
 }
 
+/// Flags added by hand instead of found by an exploit run.
 #[derive(Debug)]
-pub struct FlagOccurence<'c> {
+pub struct ManualFlags<'a> {
+    pub user_id: i32,
+    pub team_id: Option<i32>,
+    pub target_challenge: Option<&'a str>,
+    pub tick: i32,
+    pub submission_result: FlagSubmissionResult,
+}
+
+#[derive(Identifiable, Insertable, Queryable, Associations, Serialize, Debug)]
+#[diesel(table_name = flag_occurrences)]
+#[diesel(primary_key(flag_id, exploit_run_id))]
+#[diesel(belongs_to(Flag))]
+pub struct FlagOccurence {
     /// The flag that was seen (again).
-    flag: &'c Flag,
-    /// Time of when the flag was stolen.
-    collection_time: Instant,
+    pub flag_id: i64,
     /// The exploit run which got this flag.
-    exploit_run: &'c ExploitRun<'c>,
+    pub exploit_run_id: i64,
+    /// Time of when the flag was stolen.
+    pub collection_time: DateTime<Utc>,
 }
 
-#[derive(Debug)]
-pub struct UnknownFlagResponse<'c> {
+#[derive(Identifiable, Insertable, Queryable, Associations, Debug)]
+#[diesel(table_name = unknown_flag_responses)]
+#[diesel(primary_key(flag_id))]
+#[diesel(belongs_to(Flag))]
+pub struct UnknownFlagResponse {
     /// The flag that was submitted.
-    flag: &'c Flag,
+    pub flag_id: i64,
     /// Whatever we got from the submission server.
-    raw_submission_result: [u8],
+    pub raw_submission_result: Vec<u8>,
+}
+
+/// Maps the answer of the submission server for a flag to a `FlagSubmissionResult`.
+/// Rules are tried in order of their position and the first matching one wins.
+#[derive(Identifiable, Queryable, Serialize, Debug)]
+#[diesel(table_name = flag_response_rules)]
+pub struct FlagResponseRule {
+    pub id: i32,
+    pub position: i32,
+    /// Regex matched against the response of the submission server.
+    pub pattern: String,
+    pub submission_result: FlagSubmissionResult,
+}
+
+#[derive(Insertable, Deserialize, Debug)]
+#[diesel(table_name = flag_response_rules)]
+pub struct NewFlagResponseRule {
+    #[serde(skip)]
+    pub position: i32,
+    pub pattern: String,
+    pub submission_result: FlagSubmissionResult,
+}
+
+/// Compiled response rules ready to classify submission server answers.
+pub struct ResponseMapper {
+    rules: Vec<(Regex, FlagSubmissionResult)>,
+}
+
+impl ResponseMapper {
+    pub fn new(rules: &[FlagResponseRule]) -> Self {
+        let rules = rules
+            .iter()
+            .filter_map(|rule| match Regex::new(&rule.pattern) {
+                Ok(regex) => Some((regex, rule.submission_result)),
+                Err(err) => {
+                    log::warn!("Ignoring invalid flag response rule {}: {}", rule.id, err);
+                    None
+                }
+            })
+            .collect();
+        Self { rules }
+    }
+
+    pub fn classify(&self, response: &str) -> Option<FlagSubmissionResult> {
+        self.rules
+            .iter()
+            .find(|(regex, _)| regex.is_match(response))
+            .map(|(_, result)| *result)
+    }
+}
+
+/// Collect all matches of the flag regex in the text which aren't in the list yet.
+pub fn extract_flags(flag_regex: &Regex, text: &str, flags: &mut Vec<String>) {
+    for flag in flag_regex.find_iter(text) {
+        if !flags.iter().any(|known| known == flag.as_str()) {
+            flags.push(flag.as_str().to_string());
+        }
+    }
+}
+
+/// Store the flags extracted from the output of an exploit run.
+/// Returns the flags which weren't seen before.
+pub fn add_found_flags(
+    conn: &mut PgConnection,
+    exploit_run_id: i64,
+    tick: i32,
+    found_flags: &[String],
+    collection_time: DateTime<Utc>,
+    submission_result: FlagSubmissionResult,
+) -> Result<Vec<Flag>, db::Error> {
+    conn.transaction(|conn| {
+        let mut new_flags = Vec::new();
+        for found_flag in found_flags {
+            let new_flag = diesel::insert_into(flags::table)
+                .values(&NewFlag {
+                    flag: found_flag,
+                    tick,
+                    exploit_run_id: Some(exploit_run_id),
+                    submission_result,
+                    user_id: None,
+                    team_id: None,
+                    target_challenge: None,
+                })
+                .on_conflict(flags::flag)
+                .do_nothing()
+                .get_result::<Flag>(conn)
+                .optional()?;
+            let flag_id = match new_flag {
+                Some(new_flag) => {
+                    let flag_id = new_flag.id;
+                    new_flags.push(new_flag);
+                    flag_id
+                }
+                None => flags::table
+                    .filter(flags::flag.eq(found_flag))
+                    .select(flags::id)
+                    .first::<i64>(conn)?,
+            };
+            diesel::insert_into(flag_occurrences::table)
+                .values(&FlagOccurence {
+                    flag_id,
+                    exploit_run_id,
+                    collection_time,
+                })
+                .on_conflict_do_nothing()
+                .execute(conn)?;
+        }
+        Ok(new_flags)
+    })
+}
+
+/// Store flags somebody got by hand. They're queued for submission like the ones of exploit runs.
+/// Returns the flags which weren't seen before.
+pub fn add_manual_flags(
+    conn: &mut PgConnection,
+    manual_flags: &ManualFlags,
+    found_flags: &[String],
+) -> Result<Vec<Flag>, db::Error> {
+    let new_flags = found_flags
+        .iter()
+        .map(|found_flag| NewFlag {
+            flag: found_flag,
+            tick: manual_flags.tick,
+            exploit_run_id: None,
+            submission_result: manual_flags.submission_result,
+            user_id: Some(manual_flags.user_id),
+            team_id: manual_flags.team_id,
+            target_challenge: manual_flags.target_challenge,
+        })
+        .collect::<Vec<_>>();
+    Ok(diesel::insert_into(flags::table)
+        .values(&new_flags)
+        .on_conflict(flags::flag)
+        .do_nothing()
+        .get_results::<Flag>(conn)?)
+}
+
+/// All flags extracted from the output of the given run.
+pub fn get_flags_of_run(
+    conn: &mut PgConnection,
+    exploit_run_id: i64,
+) -> Result<Vec<Flag>, db::Error> {
+    Ok(flags::table
+        .inner_join(flag_occurrences::table)
+        .filter(flag_occurrences::exploit_run_id.eq(exploit_run_id))
+        .select(flags::all_columns)
+        .order(flags::id)
+        .load::<Flag>(conn)?)
+}
+
+define_sql_function! {
+    fn coalesce(x: Nullable<Integer>, y: Nullable<Integer>) -> Nullable<Integer>;
+}
+
+/// Flags joined with the run which found them first, so they can be filtered by exploit and team.
+macro_rules! joined_flags {
+    () => {
+        flags::table.left_join(exploit_runs::table).left_join(
+            flag_occurrences::table.on(flag_occurrences::flag_id.eq(flags::id).and(
+                flag_occurrences::exploit_run_id
+                    .nullable()
+                    .eq(flags::exploit_run_id),
+            )),
+        )
+    };
+}
+
+/// Apply everything of the filter except the submission result and the pagination.
+macro_rules! filter_flags {
+    ($query:expr, $filter:expr) => {{
+        let mut query = $query;
+        let filter: &FlagFilter = $filter;
+        if let Some(team_id) = filter.team_id {
+            query = query.filter(
+                exploit_runs::team_id
+                    .eq(team_id)
+                    .or(flags::team_id.eq(team_id)),
+            );
+        }
+        if let Some(exploit_id) = filter.exploit_id {
+            query = query.filter(exploit_runs::exploit_id.eq(exploit_id));
+        }
+        if let Some(tick_from) = filter.tick_from {
+            query = query.filter(flags::tick.ge(tick_from));
+        }
+        if let Some(tick_to) = filter.tick_to {
+            query = query.filter(flags::tick.le(tick_to));
+        }
+        if let Some(search) = &filter.search {
+            let pattern = search
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            query = query.filter(flags::flag.like(format!("%{pattern}%")));
+        }
+        query
+    }};
+}
+
+pub fn get_flags(
+    conn: &mut PgConnection,
+    filter: &FlagFilter,
+) -> Result<Vec<FlagListEntry>, db::Error> {
+    let mut query = filter_flags!(
+        joined_flags!()
+            .select((
+                flags::all_columns,
+                exploit_runs::exploit_id.nullable(),
+                exploit_runs::version_id.nullable(),
+                coalesce(exploit_runs::team_id.nullable(), flags::team_id),
+                flag_occurrences::collection_time.nullable(),
+            ))
+            .order(flags::id.desc())
+            .limit(filter.limit.unwrap_or(50).clamp(1, MAX_FLAGS_PER_PAGE))
+            .into_boxed(),
+        filter
+    );
+    if let Some(submission_result) = filter.submission_result {
+        query = query.filter(flags::submission_result.eq(submission_result));
+    }
+    if let Some(before) = filter.before {
+        query = query.filter(flags::id.lt(before));
+    }
+
+    Ok(query.load::<FlagListEntry>(conn)?)
+}
+
+/// Number of flags matching the filter for every submission result.
+/// The submission result and the pagination of the filter are ignored.
+pub fn count_flags_by_result(
+    conn: &mut PgConnection,
+    filter: &FlagFilter,
+) -> Result<BTreeMap<FlagSubmissionResult, i64>, db::Error> {
+    let query = filter_flags!(
+        joined_flags!()
+            .group_by(flags::submission_result)
+            .select((flags::submission_result, diesel::dsl::count_star()))
+            .into_boxed(),
+        filter
+    );
+    Ok(query
+        .load::<(FlagSubmissionResult, i64)>(conn)?
+        .into_iter()
+        .collect())
+}
+
+/// Queue the flags matching the filter for submission again. The pagination is ignored.
+/// Returns the number of requeued flags.
+pub fn requeue_flags(conn: &mut PgConnection, filter: &FlagFilter) -> Result<usize, db::Error> {
+    let mut query = filter_flags!(joined_flags!().select(flags::id).into_boxed(), filter);
+    if let Some(submission_result) = filter.submission_result {
+        query = query.filter(flags::submission_result.eq(submission_result));
+    }
+    conn.transaction(|conn| {
+        let flag_ids = query.load::<i64>(conn)?;
+        diesel::delete(
+            unknown_flag_responses::table.filter(unknown_flag_responses::flag_id.eq_any(&flag_ids)),
+        )
+        .execute(conn)?;
+        Ok(
+            diesel::update(flags::table.filter(flags::id.eq_any(&flag_ids)))
+                .set((
+                    flags::submission_result.eq(FlagSubmissionResult::Pending),
+                    flags::submission_time.eq(None::<DateTime<Utc>>),
+                ))
+                .execute(conn)?,
+        )
+    })
+}
+
+/// Classify the stored unknown answers of the submission server again, e.g. after the rules changed.
+/// Returns the flags which match a rule now.
+pub fn remap_unknown_responses(
+    conn: &mut PgConnection,
+    mapper: &ResponseMapper,
+) -> Result<Vec<FlagSubmission>, db::Error> {
+    let responses = unknown_flag_responses::table
+        .inner_join(flags::table)
+        .select((
+            flags::all_columns,
+            unknown_flag_responses::raw_submission_result,
+        ))
+        .order(flags::id)
+        .load::<(Flag, Vec<u8>)>(conn)?;
+
+    conn.transaction(|conn| {
+        let mut submissions = Vec::new();
+        for (flag, raw_response) in responses {
+            let Some(submission_result) = mapper.classify(&String::from_utf8_lossy(&raw_response))
+            else {
+                continue;
+            };
+            set_submission_result(conn, flag.id, submission_result)?;
+            diesel::delete(unknown_flag_responses::table.find(flag.id)).execute(conn)?;
+            submissions.push(FlagSubmission {
+                flag_id: flag.id,
+                flag: flag.flag,
+                submission_result,
+            });
+        }
+        Ok(submissions)
+    })
+}
+
+pub fn count_unknown_responses(conn: &mut PgConnection) -> Result<i64, db::Error> {
+    Ok(unknown_flag_responses::table.count().get_result(conn)?)
+}
+
+/// Challenges for which one of the given flags is the first valid one.
+/// Flags without a known challenge are ignored.
+pub fn find_first_bloods(
+    conn: &mut PgConnection,
+    flag_ids: &[i64],
+) -> Result<Vec<FirstBlood>, db::Error> {
+    Ok(diesel::sql_query(
+        "SELECT DISTINCT ON (submitted.challenge) submitted.*
+        FROM (
+            SELECT flags.id AS flag_id, flags.flag, exploit_runs.exploit_id,
+                COALESCE(exploit_runs.team_id, flags.team_id) AS team_id,
+                COALESCE(exploits.target_challenge, flags.target_challenge) AS challenge
+            FROM flags
+            LEFT JOIN exploit_runs ON exploit_runs.id = flags.exploit_run_id
+            LEFT JOIN exploits ON exploits.id = exploit_runs.exploit_id
+            WHERE flags.id = ANY($1) AND flags.submission_result = $2
+        ) AS submitted
+        WHERE submitted.challenge IS NOT NULL AND NOT EXISTS (
+            SELECT 1 FROM flags
+            LEFT JOIN exploit_runs ON exploit_runs.id = flags.exploit_run_id
+            LEFT JOIN exploits ON exploits.id = exploit_runs.exploit_id
+            WHERE flags.submission_result = $2 AND flags.id <> ALL($1)
+                AND COALESCE(exploits.target_challenge, flags.target_challenge) = submitted.challenge
+        )
+        ORDER BY submitted.challenge, submitted.flag_id",
+    )
+    .bind::<Array<BigInt>, _>(flag_ids)
+    .bind::<SmallInt, _>(FlagSubmissionResult::Valid)
+    .load::<FirstBlood>(conn)?)
+}
+
+/// Flags which still have to be submitted. Flags whose submission failed or was rate limited
+/// are retried after the ones that weren't submitted yet, the longest waiting first.
+pub fn get_pending_flags(conn: &mut PgConnection, limit: i64) -> Result<Vec<Flag>, db::Error> {
+    Ok(pending_flags(limit).load::<Flag>(conn)?)
+}
+
+#[dsl::auto_type(no_type_alias)]
+fn pending_flags(limit: i64) -> _ {
+    let unsubmitted: [FlagSubmissionResult; 3] = FlagSubmissionResult::UNSUBMITTED;
+    flags::table
+        .filter(flags::submission_result.eq_any(unsubmitted))
+        .order((flags::submission_time.asc().nulls_first(), flags::id))
+        .limit(limit)
+}
+
+pub fn set_submission_time(
+    conn: &mut PgConnection,
+    flag_ids: &[i64],
+    submission_time: DateTime<Utc>,
+) -> Result<(), db::Error> {
+    diesel::update(flags::table.filter(flags::id.eq_any(flag_ids)))
+        .set(flags::submission_time.eq(submission_time))
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn set_submission_result(
+    conn: &mut PgConnection,
+    flag_id: i64,
+    submission_result: FlagSubmissionResult,
+) -> Result<(), db::Error> {
+    diesel::update(flags::table.find(flag_id))
+        .set(flags::submission_result.eq(submission_result))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Remember the raw answer of the submission server which couldn't be mapped by any rule.
+pub fn add_unknown_response(
+    conn: &mut PgConnection,
+    flag_id: i64,
+    raw_submission_result: &[u8],
+) -> Result<(), db::Error> {
+    conn.transaction(|conn| {
+        set_submission_result(conn, flag_id, FlagSubmissionResult::Unknown)?;
+        diesel::insert_into(unknown_flag_responses::table)
+            .values((
+                unknown_flag_responses::flag_id.eq(flag_id),
+                unknown_flag_responses::raw_submission_result.eq(raw_submission_result),
+            ))
+            .on_conflict(unknown_flag_responses::flag_id)
+            .do_update()
+            .set(unknown_flag_responses::raw_submission_result.eq(raw_submission_result))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+pub fn get_response_rules(conn: &mut PgConnection) -> Result<Vec<FlagResponseRule>, db::Error> {
+    Ok(flag_response_rules::table
+        .order(flag_response_rules::position)
+        .load::<FlagResponseRule>(conn)?)
+}
+
+/// Replace all response rules. The order of the list is the order they're tried in.
+pub fn set_response_rules(
+    conn: &mut PgConnection,
+    mut rules: Vec<NewFlagResponseRule>,
+) -> Result<Vec<FlagResponseRule>, db::Error> {
+    for (position, rule) in rules.iter_mut().enumerate() {
+        rule.position = position as i32;
+    }
+    conn.transaction(|conn| {
+        diesel::delete(flag_response_rules::table).execute(conn)?;
+        diesel::insert_into(flag_response_rules::table)
+            .values(&rules)
+            .execute(conn)?;
+        get_response_rules(conn)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(id: i32, pattern: &str, submission_result: FlagSubmissionResult) -> FlagResponseRule {
+        FlagResponseRule {
+            id,
+            position: id,
+            pattern: pattern.to_string(),
+            submission_result,
+        }
+    }
+
+    #[test]
+    fn classify_uses_first_matching_rule() {
+        let mapper = ResponseMapper::new(&[
+            rule(1, "(?i)^accepted", FlagSubmissionResult::Valid),
+            rule(2, "already", FlagSubmissionResult::AlreadySubmitted),
+            rule(3, "too old|already", FlagSubmissionResult::Expired),
+        ]);
+        assert_eq!(
+            mapper.classify("ACCEPTED: 30 points"),
+            Some(FlagSubmissionResult::Valid)
+        );
+        assert_eq!(
+            mapper.classify("Denied: already submitted"),
+            Some(FlagSubmissionResult::AlreadySubmitted)
+        );
+        assert_eq!(
+            mapper.classify("Denied: too old"),
+            Some(FlagSubmissionResult::Expired)
+        );
+        assert_eq!(mapper.classify("Denied: no such flag"), None);
+    }
+
+    #[test]
+    fn classify_ignores_invalid_rules() {
+        let mapper = ResponseMapper::new(&[
+            rule(1, "(unclosed", FlagSubmissionResult::Valid),
+            rule(2, "unclosed", FlagSubmissionResult::Invalid),
+        ]);
+        assert_eq!(
+            mapper.classify("(unclosed"),
+            Some(FlagSubmissionResult::Invalid)
+        );
+    }
+
+    #[test]
+    fn extract_flags_skips_known_flags() {
+        let flag_regex = Regex::new("FLAG\\{[a-z0-9]+\\}").unwrap();
+        let mut flags = vec!["FLAG{known}".to_string()];
+        extract_flags(
+            &flag_regex,
+            "FLAG{b} FLAG{known} FLAG{a}FLAG{b} flag{c} FLAG{}",
+            &mut flags,
+        );
+        assert_eq!(flags, ["FLAG{known}", "FLAG{b}", "FLAG{a}"]);
+    }
+
+    #[test]
+    fn pending_flags_retries_failed_and_rate_limited() {
+        let query = diesel::debug_query::<Pg, _>(&pending_flags(10)).to_string();
+        assert!(
+            query.contains(
+                "ORDER BY \"flags\".\"submission_time\" ASC NULLS FIRST, \"flags\".\"id\""
+            ),
+            "{query}"
+        );
+        assert!(
+            query.ends_with("-- binds: [[Pending, Error, RateLimited], 10]"),
+            "{query}"
+        );
+    }
 }
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::time::Duration;
+
+use actix::prelude::*;
+use chrono::Utc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+
+use super::{Flag, FlagSubmissionResult, ResponseMapper};
+use crate::db;
+use crate::events::{self, Event, EventSender, FlagSubmission};
+use crate::metrics::Metrics;
+use crate::settings;
+use crate::DbPool;
+
+/// How long to wait before checking again if there is no submitter configured yet.
+const IDLE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long the submitter command may take to answer a batch of flags.
+const SUBMITTER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Hands pending flags in batches to the configured submitter command
+/// and stores the mapped responses.
+pub struct FlagSubmitter {
+    pool: DbPool,
+    events: EventSender,
+    metrics: Metrics,
+}
+
+/// Everything needed to submit the next batch of flags.
+struct SubmissionBatch {
+    command: Vec<String>,
+    flags: Vec<Flag>,
+    mapper: ResponseMapper,
+    interval: Duration,
+}
+
+impl FlagSubmitter {
+    pub fn new(pool: DbPool, events: EventSender, metrics: Metrics) -> Self {
+        Self {
+            pool,
+            events,
+            metrics,
+        }
+    }
+
+    fn schedule(&self, ctx: &mut <Self as Actor>::Context, delay: Duration) {
+        ctx.run_later(delay, |act, ctx| {
+            let pool = act.pool.clone();
+            let events = act.events.clone();
+            let metrics = act.metrics.clone();
+            ctx.spawn(
+                async move { submit_next_batch(pool, events, metrics).await }
+                    .into_actor(act)
+                    .map(|delay, act, ctx| act.schedule(ctx, delay)),
+            );
+        });
+    }
+}
+
+impl Actor for FlagSubmitter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.schedule(ctx, IDLE_INTERVAL);
+    }
+}
+
+/// Submit one batch of pending flags. Returns the time to wait until the next batch.
+async fn submit_next_batch(pool: DbPool, events: EventSender, metrics: Metrics) -> Duration {
+    let load_pool = pool.clone();
+    let batch = tokio::task::spawn_blocking(move || load_batch(&load_pool)).await;
+    let batch = match batch {
+        Ok(Ok(Some(batch))) => batch,
+        Ok(Ok(None)) => return IDLE_INTERVAL,
+        Ok(Err(err)) => {
+            log::error!("Failed to load pending flags: {}", err);
+            return IDLE_INTERVAL;
+        }
+        Err(err) => {
+            log::error!("Failed to load pending flags: {}", err);
+            return IDLE_INTERVAL;
+        }
+    };
+    if batch.flags.is_empty() {
+        return batch.interval;
+    }
+
+    let interval = batch.interval;
+    let timer = metrics.submitter_duration.start_timer();
+    let responses = run_submitter(&batch.command, &batch.flags).await;
+    timer.observe_duration();
+    let responses = match responses {
+        Ok(responses) => responses,
+        Err(err) => {
+            metrics.submitter_errors.inc();
+            log::error!("Flag submitter failed: {}", err);
+            events::publish(
+                &events,
+                Event::SubmitterFailed {
+                    error: err.to_string(),
+                },
+            );
+            HashMap::new()
+        }
+    };
+
+    let result =
+        tokio::task::spawn_blocking(move || store_responses(&pool, batch, responses)).await;
+    match result {
+        Ok(Ok(submissions)) => events::publish(&events, Event::FlagsSubmitted(submissions)),
+        Ok(Err(err)) => log::error!("Failed to store flag submission results: {}", err),
+        Err(err) => log::error!("Failed to store flag submission results: {}", err),
+    }
+    interval
+}
+
+fn load_batch(pool: &DbPool) -> Result<Option<SubmissionBatch>, db::Error> {
+    let conn = &mut pool.get()?;
+    let settings = settings::get_settings(conn)?;
+    let command = match settings.submitter_command.as_deref().map(shlex::split) {
+        Some(Some(command)) if !command.is_empty() => command,
+        Some(_) => {
+            log::warn!("Invalid flag submitter command configured");
+            return Ok(None);
+        }
+        None => return Ok(None),
+    };
+
+    let flags = super::get_pending_flags(conn, settings.flag_submission_batch_size.max(1) as i64)?;
+    let flag_ids = flags.iter().map(|flag| flag.id).collect::<Vec<_>>();
+    super::set_submission_time(conn, &flag_ids, Utc::now())?;
+    let mapper = ResponseMapper::new(&super::get_response_rules(conn)?);
+
+    Ok(Some(SubmissionBatch {
+        command,
+        flags,
+        mapper,
+        interval: settings.flag_submission_interval(),
+    }))
+}
+
+/// Pass the flags line by line to the submitter command and collect the
+/// `<flag> <response>` lines it prints.
+async fn run_submitter(
+    command: &[String],
+    flags: &[Flag],
+) -> Result<HashMap<String, Vec<u8>>, db::Error> {
+    let mut child = Command::new(&command[0])
+        .args(&command[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let input = flags
+        .iter()
+        .map(|flag| format!("{}\n", flag.flag))
+        .collect::<String>();
+    let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped")).split(b'\n');
+
+    let communicate = async {
+        stdin.write_all(input.as_bytes()).await?;
+        drop(stdin);
+
+        let mut responses = HashMap::new();
+        while let Some(line) = stdout.next_segment().await? {
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim();
+            if let Some((flag, response)) = line.split_once(char::is_whitespace) {
+                responses.insert(flag.to_string(), response.trim().as_bytes().to_vec());
+            }
+        }
+        let status = child.wait().await?;
+        if !status.success() {
+            log::warn!("Flag submitter exited with {}", status);
+        }
+        Ok::<_, std::io::Error>(responses)
+    };
+
+    Ok(tokio::time::timeout(SUBMITTER_TIMEOUT, communicate).await??)
+}
+
+fn store_responses(
+    pool: &DbPool,
+    batch: SubmissionBatch,
+    mut responses: HashMap<String, Vec<u8>>,
+) -> Result<Vec<FlagSubmission>, db::Error> {
+    let conn = &mut pool.get()?;
+    let mut submissions = Vec::with_capacity(batch.flags.len());
+    for flag in batch.flags {
+        let submission_result = match responses.remove(&flag.flag) {
+            Some(raw_response) => {
+                match batch
+                    .mapper
+                    .classify(&String::from_utf8_lossy(&raw_response))
+                {
+                    Some(result) => {
+                        super::set_submission_result(conn, flag.id, result)?;
+                        result
+                    }
+                    None => {
+                        super::add_unknown_response(conn, flag.id, &raw_response)?;
+                        FlagSubmissionResult::Unknown
+                    }
+                }
+            }
+            None => {
+                super::set_submission_result(conn, flag.id, FlagSubmissionResult::Error)?;
+                FlagSubmissionResult::Error
+            }
+        };
+        submissions.push(FlagSubmission {
+            flag_id: flag.id,
+            flag: flag.flag,
+            submission_result,
+        });
+    }
+    Ok(submissions)
+}
@@ -2,20 +2,32 @@
 extern crate diesel;
 extern crate diesel_migrations;
 
-use actix_web::{middleware::Logger, web, App, HttpServer};
+use actix::Actor;
+use actix_web::middleware::{from_fn, Logger};
+use actix_web::{web, App, HttpServer};
 use diesel::pg::PgConnection;
 use diesel::r2d2::{self, ConnectionManager};
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 
+mod alerts;
+mod audit;
+mod challenge;
 mod db;
+mod environment;
+mod events;
+mod exploit;
+mod exploit_runner;
+mod flag_submitter;
+mod metrics;
 mod schema;
-mod webserver;
-//mod flag_submitter;
-//mod exploit;
+mod settings;
+mod stats;
 mod team;
-//mod settings;
+mod user;
+mod webhook;
+mod webserver;
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
@@ -37,6 +49,40 @@ struct Args {
     /// Path to the frontend files
     #[clap(short, long, value_parser, default_value = "./dist")]
     frontend_path: String,
+
+    /// Directory to unpack uploaded exploit versions into
+    #[clap(long, value_parser, default_value = "./exploit_versions")]
+    exploit_storage_path: std::path::PathBuf,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Create an admin account to log in with. The password is read from stdin.
+    CreateAdmin {
+        #[clap(value_parser)]
+        username: String,
+    },
+}
+
+fn create_admin(pool: &DbPool, username: &str) -> Result<(), db::Error> {
+    eprint!("Password for {username}: ");
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        return Err("The password must not be empty".into());
+    }
+
+    let conn = &mut pool.get()?;
+    if user::find_user_by_name(conn, username)?.is_some() {
+        return Err(format!("User {username} already exists").into());
+    }
+    let user = user::add_user(conn, username, password, user::Role::Admin)?;
+    log::info!("Created user {} with id {}", user.username, user.id);
+    Ok(())
 }
 
 pub fn do_database_migration(
@@ -64,6 +110,30 @@ async fn main() -> std::io::Result<()> {
         .expect("Failed to create pool.");
     do_database_migration(&pool).expect("Failed to migrate the database.");
 
+    if let Some(Command::CreateAdmin { username }) = &args.command {
+        if let Err(err) = create_admin(&pool, username) {
+            eprintln!("Failed to create user: {err}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let live_output = exploit_runner::output_channel();
+    let events = events::event_channel();
+    let metrics = metrics::Metrics::new().expect("Failed to register metrics.");
+    let exploit_runner = exploit_runner::ExploitRunner::new(
+        pool.clone(),
+        live_output.clone(),
+        events.clone(),
+        metrics.clone(),
+        args.exploit_storage_path.clone(),
+    )
+    .start();
+    let _alert_monitor = alerts::AlertMonitor::new(pool.clone(), events.clone()).start();
+    let _webhook_notifier = webhook::WebhookNotifier::new(pool.clone(), events.clone()).start();
+    let _flag_submitter =
+        flag_submitter::FlagSubmitter::new(pool.clone(), events.clone(), metrics.clone()).start();
+
     log::info!(
         "starting HTTP server at http://{}:{}",
         args.address,
@@ -72,7 +142,17 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(live_output.clone()))
+            .app_data(web::Data::new(events.clone()))
+            .app_data(web::Data::new(metrics.clone()))
+            .app_data(web::Data::new(webserver::Service::new(
+                pool.clone(),
+                events.clone(),
+                exploit_runner.clone(),
+                args.exploit_storage_path.clone(),
+            )))
             .configure(|cfg: &mut web::ServiceConfig| webserver::config(cfg, &args.frontend_path))
+            .wrap(from_fn(webserver::authenticate))
             .wrap(Logger::default())
     })
     .bind((args.address, args.port))?
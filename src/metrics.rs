@@ -0,0 +1,146 @@
+//! Prometheus metrics served on `/metrics`.
+
+use prometheus::{
+    core::Collector, exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec,
+    IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::db;
+use crate::flag_submitter::{self, FlagFilter, FlagSubmissionResult};
+use crate::DbPool;
+
+/// All metrics of anthill. Cheap to clone, the clones update the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    pub runs_started: IntCounterVec,
+    pub runs_finished: IntCounterVec,
+    pub runs_timed_out: IntCounterVec,
+    pub run_duration: HistogramVec,
+    pub flags_found: IntCounterVec,
+    pub submitter_duration: Histogram,
+    pub submitter_errors: IntCounter,
+    pub websocket_sessions: IntGauge,
+    // Read from the database when scraped.
+    flags: IntGaugeVec,
+    submission_queue_length: IntGauge,
+    db_connections: IntGaugeVec,
+    db_max_connections: IntGauge,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("anthill".to_string()), None)?;
+        let metrics = Self {
+            runs_started: IntCounterVec::new(
+                Opts::new("exploit_runs_started_total", "Started exploit runs"),
+                &["exploit_id"],
+            )?,
+            runs_finished: IntCounterVec::new(
+                Opts::new("exploit_runs_finished_total", "Finished exploit runs"),
+                &["exploit_id"],
+            )?,
+            runs_timed_out: IntCounterVec::new(
+                Opts::new(
+                    "exploit_runs_timed_out_total",
+                    "Exploit runs killed after their timeout",
+                ),
+                &["exploit_id"],
+            )?,
+            run_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "exploit_run_duration_seconds",
+                    "Time from start to end of exploit runs",
+                )
+                .buckets(exponential_buckets(0.1, 2.0, 12)?),
+                &["exploit_id"],
+            )?,
+            flags_found: IntCounterVec::new(
+                Opts::new(
+                    "flags_found_total",
+                    "Flags found by exploit runs which weren't seen before",
+                ),
+                &["exploit_id"],
+            )?,
+            submitter_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "submitter_duration_seconds",
+                    "Time the submitter command took for a batch of flags",
+                )
+                .buckets(exponential_buckets(0.01, 2.0, 12)?),
+            )?,
+            submitter_errors: IntCounter::new(
+                "submitter_errors_total",
+                "Batches of flags the submitter command failed to submit",
+            )?,
+            websocket_sessions: IntGauge::new("websocket_sessions", "Connected websocket clients")?,
+            flags: IntGaugeVec::new(
+                Opts::new("flags", "Flags by submission result"),
+                &["submission_result"],
+            )?,
+            submission_queue_length: IntGauge::new(
+                "submission_queue_length",
+                "Flags waiting to be submitted",
+            )?,
+            db_connections: IntGaugeVec::new(
+                Opts::new("db_connections", "Connections of the database pool"),
+                &["state"],
+            )?,
+            db_max_connections: IntGauge::new(
+                "db_max_connections",
+                "Maximum size of the database pool",
+            )?,
+            registry,
+        };
+
+        let collectors: Vec<Box<dyn Collector>> = vec![
+            Box::new(metrics.runs_started.clone()),
+            Box::new(metrics.runs_finished.clone()),
+            Box::new(metrics.runs_timed_out.clone()),
+            Box::new(metrics.run_duration.clone()),
+            Box::new(metrics.flags_found.clone()),
+            Box::new(metrics.submitter_duration.clone()),
+            Box::new(metrics.submitter_errors.clone()),
+            Box::new(metrics.websocket_sessions.clone()),
+            Box::new(metrics.flags.clone()),
+            Box::new(metrics.submission_queue_length.clone()),
+            Box::new(metrics.db_connections.clone()),
+            Box::new(metrics.db_max_connections.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector)?;
+        }
+        Ok(metrics)
+    }
+
+    /// Update the metrics kept in the database and encode all of them in the text format.
+    pub fn render(&self, pool: &DbPool) -> Result<String, db::Error> {
+        let state = pool.state();
+        self.db_connections
+            .with_label_values(&["idle"])
+            .set(state.idle_connections.into());
+        self.db_connections
+            .with_label_values(&["in_use"])
+            .set((state.connections - state.idle_connections).into());
+        self.db_max_connections.set(pool.max_size().into());
+
+        let conn = &mut pool.get()?;
+        let counts = flag_submitter::count_flags_by_result(conn, &FlagFilter::default())?;
+        self.flags.reset();
+        for (submission_result, count) in &counts {
+            self.flags
+                .with_label_values(&[&format!("{submission_result:?}")])
+                .set(*count);
+        }
+        self.submission_queue_length.set(
+            counts
+                .get(&FlagSubmissionResult::Pending)
+                .copied()
+                .unwrap_or(0),
+        );
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
@@ -1,3 +1,224 @@
+table! {
+    alerts (id) {
+        id -> Int8,
+        kind -> Int2,
+        exploit_id -> Int4,
+        team_id -> Nullable<Int4>,
+        tick -> Int4,
+        message -> Text,
+        created_at -> Timestamptz,
+        acknowledged_by -> Nullable<Int4>,
+        acknowledged_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    api_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        name -> Text,
+        token_hash -> Text,
+        created_at -> Timestamptz,
+        last_used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    audit_log (id) {
+        id -> Int8,
+        user_id -> Nullable<Int4>,
+        username -> Text,
+        action -> Text,
+        object_type -> Text,
+        object_id -> Nullable<Int8>,
+        before -> Nullable<Jsonb>,
+        after -> Nullable<Jsonb>,
+        created_at -> Timestamptz,
+    }
+}
+
+table! {
+    challenges (id) {
+        id -> Int4,
+        name -> Text,
+        ports -> Array<Int4>,
+        flag_id_key -> Nullable<Text>,
+        disabled -> Bool,
+    }
+}
+
+table! {
+    environment_variables (id) {
+        id -> Int4,
+        exploit_id -> Nullable<Int4>,
+        name -> Text,
+        value -> Text,
+        secret -> Bool,
+    }
+}
+
+table! {
+    exploit_key_values (exploit_id, key) {
+        exploit_id -> Int4,
+        key -> Text,
+        value -> Text,
+    }
+}
+
+table! {
+    exploit_run_outputs (exploit_run_id) {
+        exploit_run_id -> Int8,
+        output -> Bytea,
+        size -> Int4,
+        truncated -> Bool,
+    }
+}
+
+table! {
+    exploit_runs (id) {
+        id -> Int8,
+        exploit_id -> Int4,
+        team_id -> Int4,
+        command -> Text,
+        tick -> Int4,
+        start_time -> Timestamptz,
+        end_time -> Nullable<Timestamptz>,
+        exit_code -> Nullable<Int4>,
+        timed_out -> Bool,
+        killed -> Bool,
+        version_id -> Nullable<Int4>,
+        commit_hash -> Nullable<Text>,
+        limits_hit -> Array<Int2>,
+    }
+}
+
+table! {
+    exploit_versions (id) {
+        id -> Int4,
+        exploit_id -> Int4,
+        filename -> Text,
+        sha256 -> Text,
+        size -> Int4,
+        author -> Text,
+        created_at -> Timestamptz,
+        content -> Bytea,
+    }
+}
+
+table! {
+    exploit_target_teams (exploit_id, team_id) {
+        exploit_id -> Int4,
+        team_id -> Int4,
+        policy_id -> Int4,
+    }
+}
+
+table! {
+    exploits (id) {
+        id -> Int4,
+        command -> Text,
+        author -> Text,
+        vuln_title -> Text,
+        target_challenge -> Text,
+        policy_id -> Int4,
+        script_timeout -> Int4,
+        overrun_policy -> Int2,
+        working_directory -> Text,
+        disabled -> Bool,
+        source -> Int2,
+        pinned_version_id -> Nullable<Int4>,
+        git_repository -> Nullable<Text>,
+        git_ref -> Nullable<Text>,
+        cpu_time_limit -> Nullable<Int4>,
+        memory_limit -> Nullable<Int4>,
+        process_limit -> Nullable<Int4>,
+        open_files_limit -> Nullable<Int4>,
+        cgroup_memory_max -> Nullable<Int4>,
+        cgroup_cpu_max -> Nullable<Int4>,
+    }
+}
+
+table! {
+    flag_occurrences (flag_id, exploit_run_id) {
+        flag_id -> Int8,
+        exploit_run_id -> Int8,
+        collection_time -> Timestamptz,
+    }
+}
+
+table! {
+    flag_response_rules (id) {
+        id -> Int4,
+        position -> Int4,
+        pattern -> Text,
+        submission_result -> Int2,
+    }
+}
+
+table! {
+    flags (id) {
+        id -> Int8,
+        flag -> Text,
+        tick -> Int4,
+        exploit_run_id -> Nullable<Int8>,
+        submission_time -> Nullable<Timestamptz>,
+        submission_result -> Int2,
+        user_id -> Nullable<Int4>,
+        team_id -> Nullable<Int4>,
+        target_challenge -> Nullable<Text>,
+    }
+}
+
+table! {
+    policies (id) {
+        id -> Int4,
+        name -> Text,
+        argv_pattern -> Text,
+        repeat_interval -> Int4,
+        disabled -> Bool,
+    }
+}
+
+table! {
+    settings (id) {
+        id -> Int2,
+        flag_regex -> Text,
+        tick_length -> Int4,
+        exploit_timeout -> Int4,
+        exploit_working_dir -> Text,
+        default_policy_id -> Nullable<Int4>,
+        own_team_id -> Nullable<Int4>,
+        nop_team_id -> Nullable<Int4>,
+        nop_team_grants_points -> Bool,
+        flag_submission_batch_size -> Int4,
+        number_of_parallel_exploit_runs -> Int4,
+        game_start -> Timestamptz,
+        submitter_command -> Nullable<Text>,
+        flag_submission_interval -> Int4,
+        run_output_max_bytes -> Int4,
+        run_output_retention -> Int4,
+        alert_missing_flag_ticks -> Int4,
+        skip_unresponsive_after_ticks -> Int4,
+        skipped_target_retry_ticks -> Int4,
+        run_cpu_time_limit -> Int4,
+        run_memory_limit -> Int4,
+        run_process_limit -> Int4,
+        run_open_files_limit -> Int4,
+        run_cgroup_path -> Text,
+        run_cgroup_memory_max -> Int4,
+        run_cgroup_cpu_max -> Int4,
+    }
+}
+
+table! {
+    skipped_targets (team_id, target_challenge) {
+        team_id -> Int4,
+        target_challenge -> Text,
+        tick -> Int4,
+        created_at -> Timestamptz,
+    }
+}
+
 table! {
     team_key_values (team_id, key) {
         team_id -> Int4,
@@ -14,6 +235,91 @@ table! {
     }
 }
 
+table! {
+    unknown_flag_responses (flag_id) {
+        flag_id -> Int8,
+        raw_submission_result -> Bytea,
+    }
+}
+
+table! {
+    user_sessions (token_hash) {
+        token_hash -> Text,
+        user_id -> Int4,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+    }
+}
+
+table! {
+    users (id) {
+        id -> Int4,
+        username -> Text,
+        password_hash -> Text,
+        created_at -> Timestamptz,
+        role -> Int2,
+    }
+}
+
+table! {
+    webhooks (id) {
+        id -> Int4,
+        name -> Text,
+        url -> Text,
+        body_template -> Text,
+        events -> Array<Int2>,
+    }
+}
+
+joinable!(alerts -> exploits (exploit_id));
+joinable!(alerts -> teams (team_id));
+joinable!(alerts -> users (acknowledged_by));
+joinable!(api_tokens -> users (user_id));
+joinable!(audit_log -> users (user_id));
+joinable!(environment_variables -> exploits (exploit_id));
+joinable!(exploit_key_values -> exploits (exploit_id));
+joinable!(exploit_run_outputs -> exploit_runs (exploit_run_id));
+joinable!(exploit_runs -> exploit_versions (version_id));
+joinable!(exploit_runs -> exploits (exploit_id));
+joinable!(exploit_runs -> teams (team_id));
+joinable!(exploit_versions -> exploits (exploit_id));
+joinable!(exploit_target_teams -> exploits (exploit_id));
+joinable!(exploit_target_teams -> policies (policy_id));
+joinable!(exploit_target_teams -> teams (team_id));
+joinable!(exploits -> policies (policy_id));
+joinable!(flag_occurrences -> exploit_runs (exploit_run_id));
+joinable!(flag_occurrences -> flags (flag_id));
+joinable!(flags -> exploit_runs (exploit_run_id));
+joinable!(flags -> teams (team_id));
+joinable!(flags -> users (user_id));
+joinable!(settings -> policies (default_policy_id));
+joinable!(skipped_targets -> teams (team_id));
 joinable!(team_key_values -> teams (team_id));
+joinable!(unknown_flag_responses -> flags (flag_id));
+joinable!(user_sessions -> users (user_id));
 
-allow_tables_to_appear_in_same_query!(team_key_values, teams,);
+allow_tables_to_appear_in_same_query!(
+    alerts,
+    api_tokens,
+    audit_log,
+    challenges,
+    environment_variables,
+    exploit_key_values,
+    exploit_run_outputs,
+    exploit_runs,
+    exploit_target_teams,
+    exploit_versions,
+    exploits,
+    flag_occurrences,
+    flag_response_rules,
+    flags,
+    policies,
+    settings,
+    skipped_targets,
+    team_key_values,
+    teams,
+    unknown_flag_responses,
+    user_sessions,
+    users,
+    webhooks,
+);
@@ -1,29 +1,87 @@
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
 use std::time::Duration;
-use regex::Regex;
-use std::path::Path;
-use crate::team::Team;
-use crate::exploit::Policy;
 
-#[derive(Debug)]
-struct Settings<'a> {
+use crate::db;
+use crate::schema::settings;
+use serde::{Deserialize, Serialize};
+
+/// Global configuration. There is exactly one row of settings in the database.
+#[derive(Identifiable, Queryable, AsChangeset, Serialize, Deserialize, Clone, Debug)]
+#[diesel(table_name = settings)]
+pub struct Settings {
+    #[serde(skip)]
+    id: i16,
     /// Regex used to extract flags from the exploit output.
-    flag_regex: Regex,
-    /// Round/Tick time for drawing pretty plots.
-    tick_length: Duration,
-    /// Default exploit timeout prefilled when creating a new exploit.
-    exploit_timeout: Duration,
+    pub flag_regex: String,
+    /// Round/Tick time in seconds for drawing pretty plots.
+    pub tick_length: i32,
+    /// Default exploit timeout in seconds prefilled when creating a new exploit.
+    pub exploit_timeout: i32,
     /// Default current working directory (CWD) prefilled when creating a new exploit.
-    exploit_working_dir: &'a Path,
+    pub exploit_working_dir: String,
     /// Default policy which is pre-selected when creating a new exploit.
-    default_policy: Policy,
+    pub default_policy_id: Option<i32>,
     /// Our own team in the CTF.
-    own_team: &'a Team,
+    pub own_team_id: Option<i32>,
     /// NOP team by event organizers. Possibly unpatched or worth no points.
-    nop_team: &'a Team,
+    pub nop_team_id: Option<i32>,
     /// Do we get points for exploiting the NOP team?
-    nop_team_grants_points: bool,
+    pub nop_team_grants_points: bool,
     /// Number of flags we're allowed to submit at once.
-    flag_submission_batch_size: u64,
+    pub flag_submission_batch_size: i32,
     /// Number of concurrently running exploits to tune to the hardware.
-    number_of_parallel_exploit_runs: u64,
-}
\ No newline at end of file
+    pub number_of_parallel_exploit_runs: i32,
+    /// Start time of the first tick of the game.
+    pub game_start: DateTime<Utc>,
+    /// Command which receives flags line by line on stdin and prints `<flag> <response>` lines.
+    pub submitter_command: Option<String>,
+    /// Time in seconds to wait between flag submissions.
+    pub flag_submission_interval: i32,
+    /// Maximum number of bytes of exploit output to store per run.
+    pub run_output_max_bytes: i32,
+    /// Time in seconds after which the stored output of a run is deleted. 0 keeps it forever.
+    pub run_output_retention: i32,
+    /// Alert when an exploit got no flags from a team for this many ticks. 0 disables the alert.
+    pub alert_missing_flag_ticks: i32,
+    /// Skip a team for a challenge after all exploits of it failed against the team
+    /// for this many ticks while they worked against other teams. 0 never skips.
+    pub skip_unresponsive_after_ticks: i32,
+    /// Skipped teams are only attacked every this many ticks.
+    pub skipped_target_retry_ticks: i32,
+    /// CPU time in seconds an exploit process may use. 0 is unlimited.
+    pub run_cpu_time_limit: i32,
+    /// Virtual memory in MiB an exploit process may map. 0 is unlimited.
+    pub run_memory_limit: i32,
+    /// Number of processes the user running anthill may have while an exploit runs. 0 is unlimited.
+    pub run_process_limit: i32,
+    /// Number of files an exploit process may have open. 0 is unlimited.
+    pub run_open_files_limit: i32,
+    /// Delegated cgroup v2 directory in which every run gets its own cgroup. Empty disables cgroups.
+    pub run_cgroup_path: String,
+    /// Memory in MiB all processes of a run may use together. Needs a cgroup. 0 is unlimited.
+    pub run_cgroup_memory_max: i32,
+    /// Percent of one CPU all processes of a run may use together. Needs a cgroup. 0 is unlimited.
+    pub run_cgroup_cpu_max: i32,
+}
+
+impl Settings {
+    /// The tick we're currently in. Counted from `game_start`.
+    pub fn current_tick(&self) -> i32 {
+        let elapsed = Utc::now().signed_duration_since(self.game_start);
+        (elapsed.num_seconds() / self.tick_length.max(1) as i64) as i32
+    }
+
+    pub fn flag_submission_interval(&self) -> Duration {
+        Duration::from_secs(self.flag_submission_interval.max(1) as u64)
+    }
+
+    pub fn save(&self, conn: &mut PgConnection) -> Result<(), db::Error> {
+        diesel::update(settings::table).set(self).execute(conn)?;
+        Ok(())
+    }
+}
+
+pub fn get_settings(conn: &mut PgConnection) -> Result<Settings, db::Error> {
+    Ok(settings::table.first::<Settings>(conn)?)
+}
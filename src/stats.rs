@@ -0,0 +1,151 @@
+//! Aggregated numbers per tick for the statistics page.
+//! Everything is computed by the database, the tables can get large during a game.
+
+use diesel::prelude::*;
+use diesel::sql_types::*;
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::flag_submitter::FlagSubmissionResult;
+
+/// What to break the numbers of a tick down by.
+#[derive(Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StatsGroup {
+    #[default]
+    Exploit,
+    Team,
+    Challenge,
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct StatsFilter {
+    #[serde(default)]
+    pub group_by: StatsGroup,
+    /// First tick to include.
+    pub tick_from: Option<i32>,
+    /// Last tick to include.
+    pub tick_to: Option<i32>,
+}
+
+/// Flags of one group in one tick. Only the field of the grouping is set.
+/// Flags added by hand don't belong to an exploit.
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct FlagStats {
+    #[diesel(sql_type = Integer)]
+    pub tick: i32,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub exploit_id: Option<i32>,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub team_id: Option<i32>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub target_challenge: Option<String>,
+    /// Flags nobody saw before.
+    #[diesel(sql_type = BigInt)]
+    pub new: i64,
+    #[diesel(sql_type = BigInt)]
+    pub valid: i64,
+    #[diesel(sql_type = BigInt)]
+    pub duplicate: i64,
+    #[diesel(sql_type = BigInt)]
+    pub expired: i64,
+}
+
+/// Finished exploit runs of one group in one tick. Only the field of the grouping is set.
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct RunStats {
+    #[diesel(sql_type = Integer)]
+    pub tick: i32,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub exploit_id: Option<i32>,
+    #[diesel(sql_type = Nullable<Integer>)]
+    pub team_id: Option<i32>,
+    #[diesel(sql_type = Nullable<Text>)]
+    pub target_challenge: Option<String>,
+    #[diesel(sql_type = BigInt)]
+    pub runs: i64,
+    /// Runs which exited with 0 in time.
+    #[diesel(sql_type = BigInt)]
+    pub successful: i64,
+    #[diesel(sql_type = Double)]
+    pub success_rate: f64,
+    /// Median time from start to end of the runs in seconds.
+    #[diesel(sql_type = Double)]
+    pub median_duration: f64,
+}
+
+/// Columns identifying the group. The ones not grouped by are NULL.
+fn group_columns(group: StatsGroup, exploit_id: &str, team_id: &str, challenge: &str) -> String {
+    let (exploit_id, team_id, challenge) = match group {
+        StatsGroup::Exploit => (exploit_id, "NULL::INT", "NULL::TEXT"),
+        StatsGroup::Team => ("NULL::INT", team_id, "NULL::TEXT"),
+        StatsGroup::Challenge => ("NULL::INT", "NULL::INT", challenge),
+    };
+    format!("{exploit_id} AS exploit_id, {team_id} AS team_id, {challenge} AS target_challenge")
+}
+
+pub fn get_flag_stats(
+    conn: &mut PgConnection,
+    filter: &StatsFilter,
+) -> Result<Vec<FlagStats>, db::Error> {
+    let query = format!(
+        "SELECT flags.tick, {},
+            COUNT(*) AS new,
+            COUNT(*) FILTER (WHERE flags.submission_result = $3) AS valid,
+            COUNT(*) FILTER (WHERE flags.submission_result = $4) AS duplicate,
+            COUNT(*) FILTER (WHERE flags.submission_result = $5) AS expired
+        FROM flags
+        LEFT JOIN exploit_runs ON exploit_runs.id = flags.exploit_run_id
+        LEFT JOIN exploits ON exploits.id = exploit_runs.exploit_id
+        WHERE ($1 IS NULL OR flags.tick >= $1) AND ($2 IS NULL OR flags.tick <= $2)
+        GROUP BY 1, 2, 3, 4
+        ORDER BY 1, 2, 3, 4",
+        group_columns(
+            filter.group_by,
+            "exploit_runs.exploit_id",
+            "COALESCE(exploit_runs.team_id, flags.team_id)",
+            "COALESCE(exploits.target_challenge, flags.target_challenge)",
+        )
+    );
+    Ok(diesel::sql_query(query)
+        .bind::<Nullable<Integer>, _>(filter.tick_from)
+        .bind::<Nullable<Integer>, _>(filter.tick_to)
+        .bind::<SmallInt, _>(FlagSubmissionResult::Valid)
+        .bind::<SmallInt, _>(FlagSubmissionResult::AlreadySubmitted)
+        .bind::<SmallInt, _>(FlagSubmissionResult::Expired)
+        .load::<FlagStats>(conn)?)
+}
+
+pub fn get_run_stats(
+    conn: &mut PgConnection,
+    filter: &StatsFilter,
+) -> Result<Vec<RunStats>, db::Error> {
+    let query = format!(
+        "SELECT exploit_runs.tick, {},
+            COUNT(*) AS runs,
+            COUNT(*) FILTER (WHERE exploit_runs.exit_code = 0
+                AND NOT exploit_runs.timed_out AND NOT exploit_runs.killed) AS successful,
+            (COUNT(*) FILTER (WHERE exploit_runs.exit_code = 0
+                AND NOT exploit_runs.timed_out AND NOT exploit_runs.killed))::FLOAT8
+                / COUNT(*) AS success_rate,
+            percentile_cont(0.5) WITHIN GROUP (
+                ORDER BY EXTRACT(EPOCH FROM exploit_runs.end_time - exploit_runs.start_time)
+            ) AS median_duration
+        FROM exploit_runs
+        INNER JOIN exploits ON exploits.id = exploit_runs.exploit_id
+        WHERE exploit_runs.end_time IS NOT NULL
+            AND ($1 IS NULL OR exploit_runs.tick >= $1) AND ($2 IS NULL OR exploit_runs.tick <= $2)
+        GROUP BY 1, 2, 3, 4
+        ORDER BY 1, 2, 3, 4",
+        group_columns(
+            filter.group_by,
+            "exploit_runs.exploit_id",
+            "exploit_runs.team_id",
+            "exploits.target_challenge",
+        )
+    );
+    Ok(diesel::sql_query(query)
+        .bind::<Nullable<Integer>, _>(filter.tick_from)
+        .bind::<Nullable<Integer>, _>(filter.tick_to)
+        .load::<RunStats>(conn)?)
+}
@@ -1,7 +1,8 @@
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 
 use crate::db;
-use crate::schema::{team_key_values, teams};
+use crate::schema::{skipped_targets, team_key_values, teams};
 use diesel::deserialize::{self, FromSql};
 use diesel::pg::{Pg, PgValue};
 use diesel::serialize::{self, Output, ToSql};
@@ -46,12 +47,21 @@ where
 }
 
 #[derive(
-    Identifiable, Insertable, Queryable, AsChangeset, Serialize, Deserialize, Eq, PartialEq, Debug,
+    Identifiable,
+    Insertable,
+    Queryable,
+    AsChangeset,
+    Serialize,
+    Deserialize,
+    Eq,
+    PartialEq,
+    Clone,
+    Debug,
 )]
 #[diesel(table_name = teams)]
 pub struct Team {
     /// Team ID identifying the team in the CTF platform.
-    id: i32,
+    pub id: i32,
     /// Team name for pretty printing.
     pub name: Option<String>,
     /// Should the team be attacked by default?
@@ -59,14 +69,44 @@ pub struct Team {
 }
 
 /// Custom meta key/values which can be accessed in the template patterns.
-#[derive(Identifiable, Queryable, AsChangeset, Associations, Serialize, Eq, PartialEq, Debug)]
+#[derive(
+    Identifiable, Insertable, Queryable, AsChangeset, Associations, Serialize, Eq, PartialEq, Debug,
+)]
 #[diesel(table_name = team_key_values)]
 #[diesel(primary_key(team_id, key))]
 #[diesel(belongs_to(Team))]
 pub struct TeamMeta {
+    pub team_id: i32,
+    pub key: String,
+    pub value: String,
+}
+
+/// A team which didn't respond to any exploit of the challenge for a while.
+/// It's only attacked every few ticks until one of the runs succeeds.
+#[derive(Identifiable, Insertable, Queryable, Serialize, Clone, Debug)]
+#[diesel(table_name = skipped_targets)]
+#[diesel(primary_key(team_id, target_challenge))]
+pub struct SkippedTarget {
+    pub team_id: i32,
+    pub target_challenge: String,
+    /// The tick the team was skipped in.
+    pub tick: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(QueryableByName, Debug)]
+struct UnresponsiveTarget {
+    #[diesel(sql_type = Integer)]
     team_id: i32,
-    key: String,
-    value: String,
+    #[diesel(sql_type = Text)]
+    target_challenge: String,
+}
+
+impl SkippedTarget {
+    /// Should the team be attacked in the given tick?
+    pub fn should_retry(&self, tick: i32, retry_ticks: i32) -> bool {
+        tick > self.tick && (tick - self.tick) % retry_ticks.max(1) == 0
+    }
 }
 
 impl Team {
@@ -100,6 +140,33 @@ impl Team {
         self.state = state;
         self.save(conn)
     }
+
+    /// Add or replace the meta value with the given key.
+    pub fn set_meta_value(
+        &self,
+        conn: &mut PgConnection,
+        key: String,
+        value: String,
+    ) -> Result<TeamMeta, db::Error> {
+        let meta = TeamMeta {
+            team_id: self.id,
+            key,
+            value,
+        };
+        Ok(diesel::insert_into(team_key_values::table)
+            .values(&meta)
+            .on_conflict((team_key_values::team_id, team_key_values::key))
+            .do_update()
+            .set(team_key_values::value.eq(&meta.value))
+            .get_result(conn)?)
+    }
+
+    pub fn remove_meta_value(&self, conn: &mut PgConnection, key: &str) -> Result<bool, db::Error> {
+        let deleted =
+            diesel::delete(TeamMeta::belonging_to(self).filter(team_key_values::key.eq(key)))
+                .execute(conn)?;
+        Ok(deleted > 0)
+    }
 }
 
 pub fn find_team_by_id(conn: &mut PgConnection, team_id: i32) -> Result<Option<Team>, db::Error> {
@@ -118,9 +185,115 @@ pub fn get_teams(conn: &mut PgConnection) -> Result<Vec<Team>, db::Error> {
     Ok(teams.load::<Team>(conn)?)
 }
 
-pub fn add_team(conn: &mut PgConnection, team: Team) -> Result<(), db::Error> {
+/// Meta data of all teams at once.
+pub fn get_all_meta_data(conn: &mut PgConnection) -> Result<Vec<TeamMeta>, db::Error> {
+    Ok(team_key_values::table.load::<TeamMeta>(conn)?)
+}
+
+pub fn add_team(conn: &mut PgConnection, team: Team) -> Result<Team, db::Error> {
     use crate::schema::teams::dsl::*;
 
-    diesel::insert_into(teams).values(&team).execute(conn)?;
-    Ok(())
+    Ok(diesel::insert_into(teams).values(&team).get_result(conn)?)
+}
+
+pub fn get_skipped_targets(conn: &mut PgConnection) -> Result<Vec<SkippedTarget>, db::Error> {
+    Ok(skipped_targets::table
+        .order((skipped_targets::team_id, skipped_targets::target_challenge))
+        .load::<SkippedTarget>(conn)?)
+}
+
+/// Skip teams against which all finished runs of all exploits of a challenge failed
+/// in each of the `ticks` ticks before the given one, while runs of the challenge against
+/// other teams succeeded in the same tick. A broken exploit thus doesn't skip every team.
+/// Returns the newly skipped targets.
+pub fn skip_unresponsive_targets(
+    conn: &mut PgConnection,
+    tick: i32,
+    ticks: i32,
+) -> Result<Vec<SkippedTarget>, db::Error> {
+    let unresponsive = diesel::sql_query(
+        "WITH results AS (
+            SELECT exploit_runs.team_id, exploits.target_challenge, exploit_runs.tick,
+                bool_or(COALESCE(exploit_runs.exit_code = 0, FALSE) AND NOT exploit_runs.timed_out) AS succeeded
+            FROM exploit_runs
+            INNER JOIN exploits ON exploits.id = exploit_runs.exploit_id
+            WHERE exploit_runs.tick >= $1 - $2 AND exploit_runs.tick < $1
+                AND exploit_runs.end_time IS NOT NULL AND NOT exploit_runs.killed
+            GROUP BY exploit_runs.team_id, exploits.target_challenge, exploit_runs.tick
+        )
+        SELECT failed.team_id, failed.target_challenge
+        FROM results AS failed
+        WHERE NOT failed.succeeded AND EXISTS (
+            SELECT 1 FROM results AS other
+            WHERE other.target_challenge = failed.target_challenge AND other.tick = failed.tick
+                AND other.team_id <> failed.team_id AND other.succeeded
+        )
+        GROUP BY failed.team_id, failed.target_challenge
+        HAVING COUNT(*) = $2",
+    )
+    .bind::<Integer, _>(tick)
+    .bind::<Integer, _>(ticks)
+    .load::<UnresponsiveTarget>(conn)?;
+    if unresponsive.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let now = Utc::now();
+    let targets = unresponsive
+        .into_iter()
+        .map(|target| SkippedTarget {
+            team_id: target.team_id,
+            target_challenge: target.target_challenge,
+            tick,
+            created_at: now,
+        })
+        .collect::<Vec<_>>();
+    Ok(diesel::insert_into(skipped_targets::table)
+        .values(&targets)
+        .on_conflict_do_nothing()
+        .get_results(conn)?)
+}
+
+/// Attack the team normally again. Returns the target if it was skipped.
+pub fn restore_target(
+    conn: &mut PgConnection,
+    team_id: i32,
+    target_challenge: &str,
+) -> Result<Option<SkippedTarget>, db::Error> {
+    Ok(
+        diesel::delete(skipped_targets::table.find((team_id, target_challenge)))
+            .get_result::<SkippedTarget>(conn)
+            .optional()?,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn skipped_in(tick: i32) -> SkippedTarget {
+        SkippedTarget {
+            team_id: 1,
+            target_challenge: "web".to_string(),
+            tick,
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn should_retry_every_retry_ticks() {
+        let target = skipped_in(10);
+        let retried = (8..=20)
+            .filter(|tick| target.should_retry(*tick, 3))
+            .collect::<Vec<_>>();
+        assert_eq!(retried, [13, 16, 19]);
+    }
+
+    #[test]
+    fn should_retry_every_tick_without_retry_ticks() {
+        let target = skipped_in(10);
+        assert!(!target.should_retry(10, 0));
+        assert!(target.should_retry(11, 0));
+        assert!(target.should_retry(12, 1));
+    }
 }
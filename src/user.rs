@@ -0,0 +1,341 @@
+use std::sync::LazyLock;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::db;
+use crate::exploit::Exploit;
+use crate::schema::{api_tokens, user_sessions, users};
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::{Pg, PgValue};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::*;
+use serde::{Deserialize, Serialize};
+
+/// How long a login stays valid.
+pub const SESSION_LIFETIME_DAYS: i64 = 7;
+
+/// Length of generated session ids and API tokens.
+const TOKEN_LENGTH: usize = 40;
+
+/// Hash checked for unknown usernames, so logins take as long as for existing users.
+static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password(&generate_token()).expect("hashing a password works"));
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = SmallInt)]
+pub enum Role {
+    /// Can look at everything, but not change anything.
+    Viewer,
+    /// Can create exploits and change the ones they're the author of.
+    ExploitAuthor,
+    /// Can change everything including settings, teams and users.
+    Admin,
+}
+
+impl ToSql<SmallInt, Pg> for Role {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        let v = match *self {
+            Role::Viewer => 1,
+            Role::ExploitAuthor => 2,
+            Role::Admin => 3,
+        };
+        <i16 as ToSql<SmallInt, Pg>>::to_sql(&v, &mut out.reborrow())
+    }
+}
+
+impl FromSql<SmallInt, Pg> for Role
+where
+    i16: FromSql<SmallInt, Pg>,
+{
+    fn from_sql(bytes: PgValue) -> deserialize::Result<Self> {
+        let v = i16::from_sql(bytes)?;
+        Ok(match v {
+            1 => Role::Viewer,
+            2 => Role::ExploitAuthor,
+            3 => Role::Admin,
+            id => return Err(format!("invalid role id {}", id).into()),
+        })
+    }
+}
+
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+#[diesel(table_name = users)]
+pub struct User {
+    pub id: i32,
+    pub username: String,
+    /// Argon2 hash in PHC string format.
+    #[serde(skip)]
+    pub password_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub role: Role,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = users)]
+struct NewUser<'a> {
+    username: &'a str,
+    password_hash: &'a str,
+    role: Role,
+}
+
+/// A login of a user in the browser. Only the hash of the cookie value is stored.
+#[derive(Identifiable, Insertable, Queryable, Associations, Debug)]
+#[diesel(table_name = user_sessions)]
+#[diesel(primary_key(token_hash))]
+#[diesel(belongs_to(User))]
+pub struct UserSession {
+    pub token_hash: String,
+    pub user_id: i32,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Long lived token for scripts acting in the name of a user.
+/// Only the hash of the token is stored, so it's shown exactly once when created.
+#[derive(Identifiable, Queryable, Associations, Serialize, Debug)]
+#[diesel(table_name = api_tokens)]
+#[diesel(belongs_to(User))]
+pub struct ApiToken {
+    pub id: i32,
+    pub user_id: i32,
+    /// What the token is used for.
+    pub name: String,
+    #[serde(skip)]
+    pub token_hash: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = api_tokens)]
+struct NewApiToken<'a> {
+    user_id: i32,
+    name: &'a str,
+    token_hash: &'a str,
+    created_at: DateTime<Utc>,
+}
+
+impl User {
+    pub fn is_admin(&self) -> bool {
+        self.role == Role::Admin
+    }
+
+    /// Admins can edit all exploits, authors only their own.
+    pub fn can_edit_exploit(&self, exploit: &Exploit) -> bool {
+        match self.role {
+            Role::Admin => true,
+            Role::ExploitAuthor => exploit.author == self.username,
+            Role::Viewer => false,
+        }
+    }
+
+    pub fn set_role(&mut self, conn: &mut PgConnection, role: Role) -> Result<(), db::Error> {
+        diesel::update(&*self)
+            .set(users::role.eq(role))
+            .execute(conn)?;
+        self.role = role;
+        Ok(())
+    }
+
+    /// Changing the password logs the user out everywhere.
+    pub fn set_password(
+        &mut self,
+        conn: &mut PgConnection,
+        password: &str,
+    ) -> Result<(), db::Error> {
+        let password_hash = hash_password(password)?;
+        conn.transaction(|conn| {
+            diesel::update(&*self)
+                .set(users::password_hash.eq(&password_hash))
+                .execute(conn)?;
+            diesel::delete(user_sessions::table.filter(user_sessions::user_id.eq(self.id)))
+                .execute(conn)?;
+            Ok::<_, db::Error>(())
+        })?;
+        self.password_hash = password_hash;
+        Ok(())
+    }
+
+    pub fn verify_password(&self, password: &str) -> bool {
+        match PasswordHash::new(&self.password_hash) {
+            Ok(hash) => Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+            Err(err) => {
+                log::error!("Invalid password hash of user {}: {}", self.username, err);
+                false
+            }
+        }
+    }
+}
+
+/// Spend the time of verifying a password without a user, so failed logins
+/// don't reveal whether the username exists.
+pub fn verify_dummy_password(password: &str) {
+    if let Ok(hash) = PasswordHash::new(&DUMMY_PASSWORD_HASH) {
+        let _ = Argon2::default().verify_password(password.as_bytes(), &hash);
+    }
+}
+
+fn hash_password(password: &str) -> Result<String, db::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|err| format!("Failed to hash password: {err}"))?
+        .to_string())
+}
+
+fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Tokens are random enough that a plain hash is sufficient.
+fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+pub fn add_user(
+    conn: &mut PgConnection,
+    username: &str,
+    password: &str,
+    role: Role,
+) -> Result<User, db::Error> {
+    let password_hash = hash_password(password)?;
+    Ok(diesel::insert_into(users::table)
+        .values(&NewUser {
+            username,
+            password_hash: &password_hash,
+            role,
+        })
+        .get_result(conn)?)
+}
+
+pub fn find_user_by_id(conn: &mut PgConnection, user_id: i32) -> Result<Option<User>, db::Error> {
+    Ok(users::table.find(user_id).first::<User>(conn).optional()?)
+}
+
+pub fn get_users(conn: &mut PgConnection) -> Result<Vec<User>, db::Error> {
+    Ok(users::table.order(users::id).load::<User>(conn)?)
+}
+
+/// Sessions and API tokens of the user are deleted with it.
+pub fn delete_user(conn: &mut PgConnection, user_id: i32) -> Result<bool, db::Error> {
+    let deleted = diesel::delete(users::table.find(user_id)).execute(conn)?;
+    Ok(deleted > 0)
+}
+
+pub fn find_user_by_name(
+    conn: &mut PgConnection,
+    username: &str,
+) -> Result<Option<User>, db::Error> {
+    Ok(users::table
+        .filter(users::username.eq(username))
+        .first::<User>(conn)
+        .optional()?)
+}
+
+/// Start a new session for the user. Returns the session id to put in the cookie.
+pub fn add_session(conn: &mut PgConnection, user: &User) -> Result<String, db::Error> {
+    let now = Utc::now();
+    diesel::delete(user_sessions::table.filter(user_sessions::expires_at.lt(now))).execute(conn)?;
+
+    let token = generate_token();
+    diesel::insert_into(user_sessions::table)
+        .values(&UserSession {
+            token_hash: hash_token(&token),
+            user_id: user.id,
+            created_at: now,
+            expires_at: now + chrono::Duration::days(SESSION_LIFETIME_DAYS),
+        })
+        .execute(conn)?;
+    Ok(token)
+}
+
+pub fn find_user_by_session(
+    conn: &mut PgConnection,
+    token: &str,
+) -> Result<Option<User>, db::Error> {
+    Ok(user_sessions::table
+        .inner_join(users::table)
+        .filter(user_sessions::token_hash.eq(hash_token(token)))
+        .filter(user_sessions::expires_at.gt(Utc::now()))
+        .select(users::all_columns)
+        .first::<User>(conn)
+        .optional()?)
+}
+
+pub fn delete_session(conn: &mut PgConnection, token: &str) -> Result<(), db::Error> {
+    diesel::delete(user_sessions::table.find(hash_token(token))).execute(conn)?;
+    Ok(())
+}
+
+/// Create a new API token. Returns the token itself which can't be recovered later.
+pub fn add_api_token(
+    conn: &mut PgConnection,
+    user_id: i32,
+    name: &str,
+) -> Result<(ApiToken, String), db::Error> {
+    let token = generate_token();
+    let api_token = diesel::insert_into(api_tokens::table)
+        .values(&NewApiToken {
+            user_id,
+            name,
+            token_hash: &hash_token(&token),
+            created_at: Utc::now(),
+        })
+        .get_result(conn)?;
+    Ok((api_token, token))
+}
+
+pub fn get_api_tokens(conn: &mut PgConnection, user_id: i32) -> Result<Vec<ApiToken>, db::Error> {
+    Ok(api_tokens::table
+        .filter(api_tokens::user_id.eq(user_id))
+        .order(api_tokens::id)
+        .load::<ApiToken>(conn)?)
+}
+
+pub fn delete_api_token(
+    conn: &mut PgConnection,
+    user_id: i32,
+    token_id: i32,
+) -> Result<bool, db::Error> {
+    let deleted = diesel::delete(
+        api_tokens::table
+            .filter(api_tokens::id.eq(token_id))
+            .filter(api_tokens::user_id.eq(user_id)),
+    )
+    .execute(conn)?;
+    Ok(deleted > 0)
+}
+
+/// Look up the owner of the token and remember that it was used.
+pub fn find_user_by_api_token(
+    conn: &mut PgConnection,
+    token: &str,
+) -> Result<Option<User>, db::Error> {
+    let api_token = diesel::update(api_tokens::table)
+        .filter(api_tokens::token_hash.eq(hash_token(token)))
+        .set(api_tokens::last_used_at.eq(Utc::now()))
+        .get_result::<ApiToken>(conn)
+        .optional()?;
+    match api_token {
+        Some(api_token) => Ok(Some(
+            users::table.find(api_token.user_id).first::<User>(conn)?,
+        )),
+        None => Ok(None),
+    }
+}
@@ -0,0 +1,365 @@
+//! Notifications posted to chat webhooks when something noteworthy happens.
+
+use std::time::Duration;
+
+use diesel::prelude::*;
+
+use crate::db;
+use crate::schema::webhooks;
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::{Pg, PgValue};
+use diesel::serialize::{self, Output, ToSql};
+use diesel::sql_types::*;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+mod notifier;
+pub use notifier::WebhookNotifier;
+
+/// How long the webhook may take to accept a notification.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a notification is sent before giving up.
+const WEBHOOK_ATTEMPTS: u32 = 3;
+
+/// Time to wait before the first retry. Doubled for every further one.
+const WEBHOOK_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Understood by Mattermost and Slack incoming webhooks.
+pub const DEFAULT_BODY_TEMPLATE: &str = r#"{"text": "{message}"}"#;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Serialize, Deserialize, AsExpression, FromSqlRow)]
+#[diesel(sql_type = SmallInt)]
+pub enum WebhookEvent {
+    /// The first valid flag of a challenge was submitted.
+    FirstBlood,
+    /// An alert was raised because an exploit stopped working.
+    ExploitBroken,
+    /// The submitter command started failing or works again.
+    SubmitterDown,
+    /// The submission server started rejecting flags because of a rate limit.
+    FlagQuotaHit,
+}
+
+impl ToSql<SmallInt, Pg> for WebhookEvent {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        let v = match *self {
+            WebhookEvent::FirstBlood => 1,
+            WebhookEvent::ExploitBroken => 2,
+            WebhookEvent::SubmitterDown => 3,
+            WebhookEvent::FlagQuotaHit => 4,
+        };
+        <i16 as ToSql<SmallInt, Pg>>::to_sql(&v, &mut out.reborrow())
+    }
+}
+
+impl FromSql<SmallInt, Pg> for WebhookEvent
+where
+    i16: FromSql<SmallInt, Pg>,
+{
+    fn from_sql(bytes: PgValue) -> deserialize::Result<Self> {
+        let v = i16::from_sql(bytes)?;
+        Ok(match v {
+            1 => WebhookEvent::FirstBlood,
+            2 => WebhookEvent::ExploitBroken,
+            3 => WebhookEvent::SubmitterDown,
+            4 => WebhookEvent::FlagQuotaHit,
+            id => return Err(format!("invalid webhook event id {}", id).into()),
+        })
+    }
+}
+
+#[derive(Identifiable, Queryable, AsChangeset, Serialize, Deserialize, Clone, Debug)]
+#[diesel(table_name = webhooks)]
+pub struct Webhook {
+    pub id: i32,
+    pub name: String,
+    pub url: String,
+    /// JSON body with `{event}`, `{message}` and `{data}` placeholders.
+    pub body_template: String,
+    /// The events which are posted to the webhook.
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Insertable, AsChangeset, Deserialize, Debug)]
+#[diesel(table_name = webhooks)]
+pub struct NewWebhook {
+    pub name: String,
+    pub url: String,
+    #[serde(default = "default_body_template")]
+    pub body_template: String,
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+}
+
+fn default_body_template() -> String {
+    DEFAULT_BODY_TEMPLATE.to_string()
+}
+
+/// Something to tell the webhooks about.
+#[derive(Clone, Debug)]
+pub struct Notification {
+    pub event: WebhookEvent,
+    /// Human readable description.
+    pub message: String,
+    /// Details of the event, e.g. the alert.
+    pub data: serde_json::Value,
+}
+
+impl Notification {
+    pub fn new(event: WebhookEvent, message: String, data: serde_json::Value) -> Self {
+        Self {
+            event,
+            message,
+            data,
+        }
+    }
+}
+
+impl NewWebhook {
+    /// Make sure the webhook can be called and the template produces valid JSON.
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.url.starts_with("http://") && !self.url.starts_with("https://") {
+            return Err(format!("Invalid webhook URL {}", self.url));
+        }
+        let sample = Notification::new(
+            WebhookEvent::FirstBlood,
+            "Sample \"message\"".to_string(),
+            json!({ "sample": true }),
+        );
+        serde_json::from_str::<serde_json::Value>(&render_body(&self.body_template, &sample))
+            .map_err(|err| format!("The body template doesn't produce valid JSON: {err}"))?;
+        Ok(())
+    }
+}
+
+impl Webhook {
+    pub fn save(&self, conn: &mut PgConnection) -> Result<(), db::Error> {
+        diesel::update(self).set(self).execute(conn)?;
+        Ok(())
+    }
+
+    pub fn delete(self, conn: &mut PgConnection) -> Result<(), db::Error> {
+        diesel::delete(&self).execute(conn)?;
+        Ok(())
+    }
+
+    /// Post the notification, retrying a few times if the webhook is unreachable
+    /// or answers with a server error.
+    pub async fn send(&self, notification: &Notification) -> Result<(), String> {
+        let body = render_body(&self.body_template, notification);
+        let mut delay = WEBHOOK_RETRY_DELAY;
+        let mut attempt = 1;
+        loop {
+            let url = self.url.clone();
+            let body = body.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                ureq::post(&url)
+                    .timeout(WEBHOOK_TIMEOUT)
+                    .set("Content-Type", "application/json")
+                    .send_string(&body)
+                    .map(|_| ())
+                    .map_err(Box::new)
+            })
+            .await
+            .map_err(|err| err.to_string())?;
+
+            let err = match result {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            let retry = match err.as_ref() {
+                ureq::Error::Status(status, _) => *status >= 500 || *status == 429,
+                ureq::Error::Transport(_) => true,
+            };
+            if !retry || attempt >= WEBHOOK_ATTEMPTS {
+                return Err(err.to_string());
+            }
+            log::warn!(
+                "Webhook {} failed, retrying in {:?}: {}",
+                self.id,
+                delay,
+                err
+            );
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            attempt += 1;
+        }
+    }
+}
+
+/// Fill the placeholders of the template. Text is escaped so it can be placed in JSON strings,
+/// `{data}` is replaced by a JSON object.
+pub fn render_body(template: &str, notification: &Notification) -> String {
+    let placeholder = Regex::new(r"\{(event|message|data)\}").expect("valid regex");
+    placeholder
+        .replace_all(template, |captures: &Captures| match &captures[1] {
+            "event" => escape_json(&format!("{:?}", notification.event)),
+            "message" => escape_json(&notification.message),
+            _ => notification.data.to_string(),
+        })
+        .into_owned()
+}
+
+/// The content of a JSON string without the surrounding quotes.
+fn escape_json(text: &str) -> String {
+    let quoted = serde_json::Value::from(text).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+pub fn find_webhook_by_id(
+    conn: &mut PgConnection,
+    webhook_id: i32,
+) -> Result<Option<Webhook>, db::Error> {
+    Ok(webhooks::table
+        .find(webhook_id)
+        .first::<Webhook>(conn)
+        .optional()?)
+}
+
+pub fn get_webhooks(conn: &mut PgConnection) -> Result<Vec<Webhook>, db::Error> {
+    Ok(webhooks::table.order(webhooks::id).load::<Webhook>(conn)?)
+}
+
+/// Webhooks which want to hear about the given event.
+pub fn get_webhooks_for_event(
+    conn: &mut PgConnection,
+    event: WebhookEvent,
+) -> Result<Vec<Webhook>, db::Error> {
+    Ok(webhooks::table
+        .filter(webhooks::events.contains(vec![event]))
+        .order(webhooks::id)
+        .load::<Webhook>(conn)?)
+}
+
+pub fn add_webhook(conn: &mut PgConnection, webhook: NewWebhook) -> Result<Webhook, db::Error> {
+    Ok(diesel::insert_into(webhooks::table)
+        .values(&webhook)
+        .get_result(conn)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    /// Answer requests with the given status codes and 200 after them.
+    /// Returns the URL and the bodies of the received requests.
+    fn serve(statuses: &'static [u16]) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let bodies = Arc::new(Mutex::new(Vec::new()));
+        let received = bodies.clone();
+        let mut statuses = statuses.iter().copied();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                received
+                    .lock()
+                    .unwrap()
+                    .push(String::from_utf8(body).unwrap());
+
+                let status = statuses.next().unwrap_or(200);
+                let response = format!(
+                    "HTTP/1.1 {status} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                );
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (url, bodies)
+    }
+
+    fn webhook(url: String) -> Webhook {
+        Webhook {
+            id: 1,
+            name: "test".to_string(),
+            url,
+            body_template: DEFAULT_BODY_TEMPLATE.to_string(),
+            events: vec![WebhookEvent::FirstBlood],
+        }
+    }
+
+    fn notification() -> Notification {
+        Notification::new(
+            WebhookEvent::FirstBlood,
+            "First blood on web".to_string(),
+            json!({ "challenge": "web" }),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_posts_rendered_body() {
+        let (url, bodies) = serve(&[]);
+        webhook(url).send(&notification()).await.unwrap();
+        assert_eq!(
+            *bodies.lock().unwrap(),
+            [r#"{"text": "First blood on web"}"#]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_retries_server_errors_and_rate_limits() {
+        let (url, bodies) = serve(&[500, 429]);
+        webhook(url).send(&notification()).await.unwrap();
+        assert_eq!(bodies.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_gives_up_after_all_attempts() {
+        let (url, bodies) = serve(&[503, 503, 503]);
+        assert!(webhook(url).send(&notification()).await.is_err());
+        assert_eq!(bodies.lock().unwrap().len(), WEBHOOK_ATTEMPTS as usize);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn send_does_not_retry_client_errors() {
+        let (url, bodies) = serve(&[404]);
+        assert!(webhook(url).send(&notification()).await.is_err());
+        assert_eq!(bodies.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn render_body_fills_placeholders() {
+        let notification = Notification::new(
+            WebhookEvent::ExploitBroken,
+            "Exploit \"sqli\"\nfailed".to_string(),
+            json!({ "exploit_id": 3 }),
+        );
+        let body = render_body(
+            r#"{"event": "{event}", "text": "{message}", "data": {data}, "other": "{other}"}"#,
+            &notification,
+        );
+        assert_eq!(
+            body,
+            r#"{"event": "ExploitBroken", "text": "Exploit \"sqli\"\nfailed", "data": {"exploit_id":3}, "other": "{other}"}"#
+        );
+        let body = serde_json::from_str::<serde_json::Value>(&body).unwrap();
+        assert_eq!(body["text"], "Exploit \"sqli\"\nfailed");
+    }
+
+    #[test]
+    fn escape_json_escapes_string_content() {
+        assert_eq!(escape_json("plain"), "plain");
+        assert_eq!(escape_json("a\"b\\c\nd\te"), r#"a\"b\\c\nd\te"#);
+        assert_eq!(escape_json("\u{1}"), r"\u0001");
+    }
+}
@@ -0,0 +1,184 @@
+use std::sync::Arc;
+
+use actix::prelude::*;
+use serde_json::json;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+
+use super::{Notification, WebhookEvent};
+use crate::events::{Event, EventSender, FlagSubmission};
+use crate::flag_submitter::{self, FirstBlood, FlagSubmissionResult};
+use crate::DbPool;
+
+/// Turns events into notifications and posts them to the webhooks that enabled them.
+pub struct WebhookNotifier {
+    pool: DbPool,
+    events: EventSender,
+    /// Did the last submission fail? Only changes of this are reported.
+    submitter_down: bool,
+    /// Did the submission server reject flags because of a rate limit lately?
+    rate_limited: bool,
+}
+
+impl WebhookNotifier {
+    pub fn new(pool: DbPool, events: EventSender) -> Self {
+        Self {
+            pool,
+            events,
+            submitter_down: false,
+            rate_limited: false,
+        }
+    }
+
+    fn check_submissions(
+        &mut self,
+        ctx: &mut <Self as Actor>::Context,
+        submissions: &[FlagSubmission],
+    ) {
+        let answered = submissions
+            .iter()
+            .any(|submission| submission.submission_result != FlagSubmissionResult::Error);
+        if self.submitter_down && answered {
+            self.submitter_down = false;
+            self.notify(Notification::new(
+                WebhookEvent::SubmitterDown,
+                "The flag submitter works again".to_string(),
+                json!({ "down": false }),
+            ));
+        }
+
+        let rate_limited = submissions
+            .iter()
+            .filter(|submission| submission.submission_result == FlagSubmissionResult::RateLimited)
+            .count();
+        if rate_limited > 0 && !self.rate_limited {
+            self.rate_limited = true;
+            self.notify(Notification::new(
+                WebhookEvent::FlagQuotaHit,
+                format!(
+                    "The submission server rejected {rate_limited} flags because of a rate limit"
+                ),
+                json!({ "rate_limited": rate_limited }),
+            ));
+        } else if rate_limited == 0 && answered {
+            self.rate_limited = false;
+        }
+
+        let valid_flag_ids = submissions
+            .iter()
+            .filter(|submission| submission.submission_result == FlagSubmissionResult::Valid)
+            .map(|submission| submission.flag_id)
+            .collect::<Vec<_>>();
+        if valid_flag_ids.is_empty() {
+            return;
+        }
+        let pool = self.pool.clone();
+        ctx.spawn(
+            async move {
+                tokio::task::spawn_blocking(move || {
+                    let conn = &mut pool.get()?;
+                    flag_submitter::find_first_bloods(conn, &valid_flag_ids)
+                })
+                .await
+            }
+            .into_actor(self)
+            .map(|result, act, _ctx| match result {
+                Ok(Ok(first_bloods)) => {
+                    for first_blood in first_bloods {
+                        act.notify(first_blood_notification(first_blood));
+                    }
+                }
+                Ok(Err(err)) => log::error!("Failed to check for first blood: {}", err),
+                Err(err) => log::error!("Failed to check for first blood: {}", err),
+            }),
+        );
+    }
+
+    /// Post the notification to all interested webhooks in the background.
+    fn notify(&self, notification: Notification) {
+        let pool = self.pool.clone();
+        actix::spawn(async move {
+            let event = notification.event;
+            let webhooks = tokio::task::spawn_blocking(move || {
+                let conn = &mut pool.get()?;
+                super::get_webhooks_for_event(conn, event)
+            })
+            .await;
+            let webhooks = match webhooks {
+                Ok(Ok(webhooks)) => webhooks,
+                Ok(Err(err)) => {
+                    log::error!("Failed to load webhooks: {}", err);
+                    return;
+                }
+                Err(err) => {
+                    log::error!("Failed to load webhooks: {}", err);
+                    return;
+                }
+            };
+            for webhook in webhooks {
+                let notification = notification.clone();
+                actix::spawn(async move {
+                    if let Err(err) = webhook.send(&notification).await {
+                        log::error!("Failed to notify webhook {}: {}", webhook.id, err);
+                    }
+                });
+            }
+        });
+    }
+}
+
+fn first_blood_notification(first_blood: FirstBlood) -> Notification {
+    let source = match (first_blood.exploit_id, first_blood.team_id) {
+        (Some(exploit_id), Some(team_id)) => format!("exploit {exploit_id} against team {team_id}"),
+        (Some(exploit_id), None) => format!("exploit {exploit_id}"),
+        (None, Some(team_id)) => format!("a flag added by hand from team {team_id}"),
+        (None, None) => "a flag added by hand".to_string(),
+    };
+    Notification::new(
+        WebhookEvent::FirstBlood,
+        format!("First blood on {} by {}", first_blood.challenge, source),
+        json!(first_blood),
+    )
+}
+
+impl Actor for WebhookNotifier {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.add_stream(BroadcastStream::new(self.events.subscribe()));
+    }
+}
+
+impl StreamHandler<Result<Arc<Event>, BroadcastStreamRecvError>> for WebhookNotifier {
+    fn handle(
+        &mut self,
+        event: Result<Arc<Event>, BroadcastStreamRecvError>,
+        ctx: &mut Self::Context,
+    ) {
+        let event = match event {
+            Ok(event) => event,
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                log::warn!("Webhook notifier missed {} events", skipped);
+                return;
+            }
+        };
+
+        match event.as_ref() {
+            Event::AlertRaised(alert) => self.notify(Notification::new(
+                WebhookEvent::ExploitBroken,
+                alert.message.clone(),
+                json!(alert),
+            )),
+            Event::SubmitterFailed { error } if !self.submitter_down => {
+                self.submitter_down = true;
+                self.notify(Notification::new(
+                    WebhookEvent::SubmitterDown,
+                    format!("The flag submitter is failing: {error}"),
+                    json!({ "down": true, "error": error }),
+                ));
+            }
+            Event::FlagsSubmitted(submissions) => self.check_submissions(ctx, submissions),
+            _ => (),
+        }
+    }
+}
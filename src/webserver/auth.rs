@@ -0,0 +1,216 @@
+use std::future::{ready, Ready};
+
+use actix_web::body::MessageBody;
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::{header, Method};
+use actix_web::middleware::Next;
+use actix_web::{get, post, web, Error, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use serde::Deserialize;
+
+use super::rest_api::ApiError;
+use crate::user::{self, User};
+use crate::DbPool;
+
+/// Name of the cookie holding the session id.
+const SESSION_COOKIE: &str = "anthill_session";
+
+/// Minimal page to log in before the frontend is served.
+const LOGIN_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>anthill login</title></head>
+<body>
+<form id="login">
+<input name="username" placeholder="Username" autofocus required>
+<input name="password" type="password" placeholder="Password" required>
+<button>Login</button>
+<p id="error"></p>
+</form>
+<script>
+document.getElementById("login").addEventListener("submit", async (event) => {
+  event.preventDefault();
+  const form = new FormData(event.target);
+  const response = await fetch("/api/login", {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify(Object.fromEntries(form)),
+  });
+  if (response.ok) {
+    window.location = "/";
+  } else {
+    document.getElementById("error").textContent = (await response.json()).error;
+  }
+});
+</script>
+</body>
+</html>
+"#;
+
+/// The user who sent the request. Available in every handler behind `authenticate`.
+#[derive(Clone, Debug)]
+pub struct AuthenticatedUser(pub User);
+
+impl FromRequest for AuthenticatedUser {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<AuthenticatedUser>()
+                .cloned()
+                .ok_or_else(|| actix_web::error::ErrorUnauthorized("Authentication required")),
+        )
+    }
+}
+
+/// Requests which don't need a logged in user.
+fn is_public(req: &ServiceRequest) -> bool {
+    matches!(
+        (req.method(), req.path()),
+        (&Method::GET, "/login") | (&Method::POST, "/api/login")
+    )
+}
+
+/// Bearer token of the `Authorization` header, the `X-Token` header or the session cookie.
+enum Credentials {
+    ApiToken(String),
+    Session(String),
+}
+
+/// Header clients of other exploit farms send their token in.
+const FARM_TOKEN_HEADER: &str = "X-Token";
+
+fn credentials(req: &ServiceRequest) -> Option<Credentials> {
+    if let Some(token) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            req.headers()
+                .get(FARM_TOKEN_HEADER)
+                .and_then(|value| value.to_str().ok())
+        })
+    {
+        return Some(Credentials::ApiToken(token.trim().to_string()));
+    }
+    req.cookie(SESSION_COOKIE)
+        .map(|cookie| Credentials::Session(cookie.value().to_string()))
+}
+
+/// Only let logged in users or scripts with a valid API token through.
+/// Browsers are sent to the login page, API clients get a 401.
+pub async fn authenticate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if is_public(&req) {
+        return Ok(next.call(req).await?.map_into_left_body());
+    }
+
+    let user = match (credentials(&req), req.app_data::<web::Data<DbPool>>()) {
+        (Some(credentials), Some(pool)) => {
+            let pool = pool.clone();
+            web::block(move || {
+                let conn = &mut pool.get()?;
+                match credentials {
+                    Credentials::ApiToken(token) => user::find_user_by_api_token(conn, &token),
+                    Credentials::Session(token) => user::find_user_by_session(conn, &token),
+                }
+            })
+            .await?
+            .map_err(actix_web::error::ErrorInternalServerError)?
+        }
+        _ => None,
+    };
+
+    match user {
+        Some(user) => {
+            req.extensions_mut().insert(AuthenticatedUser(user));
+            Ok(next.call(req).await?.map_into_left_body())
+        }
+        None => {
+            let path = req.path();
+            let response = if path.starts_with("/api") || path == "/ws" || path == "/metrics" {
+                HttpResponse::Unauthorized().json(ApiError {
+                    error: "Authentication required".to_string(),
+                })
+            } else {
+                HttpResponse::SeeOther()
+                    .insert_header((header::LOCATION, "/login"))
+                    .finish()
+            };
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
+
+#[get("/login")]
+async fn login_page() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(LOGIN_PAGE)
+}
+
+#[derive(Deserialize)]
+struct LoginArguments {
+    username: String,
+    password: String,
+}
+
+#[post("/login")]
+async fn login(
+    pool: web::Data<DbPool>,
+    args: web::Json<LoginArguments>,
+) -> Result<HttpResponse, Error> {
+    let args = args.into_inner();
+    let session = web::block(move || -> Result<_, crate::db::Error> {
+        let conn = &mut pool.get()?;
+        match user::find_user_by_name(conn, &args.username)? {
+            Some(user) if user.verify_password(&args.password) => {
+                let token = user::add_session(conn, &user)?;
+                Ok(Some((user, token)))
+            }
+            Some(_) => Ok(None),
+            None => {
+                user::verify_dummy_password(&args.password);
+                Ok(None)
+            }
+        }
+    })
+    .await?
+    .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    match session {
+        Some((user, token)) => {
+            let cookie = Cookie::build(SESSION_COOKIE, token)
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Strict)
+                .max_age(time::Duration::days(user::SESSION_LIFETIME_DAYS))
+                .finish();
+            Ok(HttpResponse::Ok().cookie(cookie).json(user))
+        }
+        None => Ok(HttpResponse::Unauthorized().json(ApiError {
+            error: "Invalid username or password".to_string(),
+        })),
+    }
+}
+
+#[post("/logout")]
+async fn logout(pool: web::Data<DbPool>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        let token = cookie.value().to_string();
+        web::block(move || {
+            let conn = &mut pool.get()?;
+            user::delete_session(conn, &token)
+        })
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    }
+
+    let mut cookie = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    cookie.make_removal();
+    Ok(HttpResponse::NoContent().cookie(cookie).finish())
+}
@@ -0,0 +1,108 @@
+//! Endpoints of the DestructiveFarm server, so its `start_sploit.py` client
+//! and similar scripts can feed flags into our queue.
+//! They send the API token in the `X-Token` header.
+
+use std::collections::{BTreeMap, HashMap};
+
+use actix_web::{get, post, web, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+use super::auth::AuthenticatedUser;
+use super::rest_api::ApiResult;
+use super::service::{ManualFlagArguments, Service, TeamArguments};
+use crate::team::{Team, TeamState};
+
+/// Team meta value holding the address the clients should attack.
+const TEAM_ADDRESS_KEY: &str = "ip";
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(get_config).service(post_flags);
+}
+
+/// The part of the farm configuration the clients use.
+#[derive(Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+struct FarmConfig {
+    flag_format: String,
+    /// Team name to address.
+    teams: BTreeMap<String, String>,
+    submit_period: i32,
+    submit_flag_limit: i32,
+}
+
+/// The name of the exploit on the client side in `sploit` is ignored.
+#[derive(Deserialize)]
+struct FarmFlag {
+    flag: String,
+    /// Key of the team in the `TEAMS` config.
+    team: Option<String>,
+}
+
+/// Teams are identified by their name, or the id if they don't have one.
+fn team_key(team: &Team) -> String {
+    team.name.clone().unwrap_or_else(|| team.id.to_string())
+}
+
+#[get("/get_config")]
+async fn get_config(service: web::Data<Service>) -> ApiResult {
+    let settings = service.get_settings().await?;
+    let teams = service
+        .get_teams(TeamArguments {
+            include_meta_values: Some(true),
+        })
+        .await?
+        .into_iter()
+        .filter(|result| {
+            result.team.state == TeamState::Active && Some(result.team.id) != settings.own_team_id
+        })
+        .map(|result| {
+            let address = result
+                .meta_data
+                .unwrap_or_default()
+                .into_iter()
+                .find(|meta| meta.key == TEAM_ADDRESS_KEY)
+                .map(|meta| meta.value)
+                .unwrap_or_else(|| team_key(&result.team));
+            (team_key(&result.team), address)
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(FarmConfig {
+        flag_format: settings.flag_regex,
+        teams,
+        submit_period: settings.flag_submission_interval,
+        submit_flag_limit: settings.flag_submission_batch_size,
+    }))
+}
+
+#[post("/post_flags")]
+async fn post_flags(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    flags: web::Json<Vec<FarmFlag>>,
+) -> ApiResult {
+    let team_ids = service
+        .get_teams(TeamArguments::default())
+        .await?
+        .into_iter()
+        .map(|result| (team_key(&result.team), result.team.id))
+        .collect::<HashMap<_, _>>();
+
+    // Flags of teams we don't know are still submitted, just without a team.
+    let mut batches = BTreeMap::<Option<i32>, Vec<String>>::new();
+    for flag in flags.into_inner() {
+        let team_id = flag.team.and_then(|team| team_ids.get(&team).copied());
+        batches.entry(team_id).or_default().push(flag.flag);
+    }
+    let batches = batches
+        .into_iter()
+        .map(|(team_id, flags)| ManualFlagArguments {
+            text: flags.join("\n"),
+            team_id,
+            target_challenge: None,
+        })
+        .collect();
+
+    let result = service.add_flag_batches(&user.0, batches).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
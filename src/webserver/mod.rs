@@ -1,25 +1,61 @@
 use actix_files::Files;
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 
+use crate::events::EventSender;
+use crate::exploit_runner::OutputSender;
+use crate::metrics::Metrics;
 use crate::DbPool;
 use actix_web_actors::ws;
+mod auth;
+mod farm_api;
 mod rest_api;
+mod service;
 mod websocket;
 
+pub use auth::authenticate;
+pub use service::Service;
+
 pub fn config(cfg: &mut web::ServiceConfig, frontend_path: &str) {
     cfg.configure(rest_api::config)
+        .service(auth::login_page)
         .route("/ws", web::get().to(handle_websocket))
+        .route("/metrics", web::get().to(render_metrics))
         .service(Files::new("/", frontend_path).index_file("index.html"));
 }
 
 async fn handle_websocket(
     req: HttpRequest,
     stream: web::Payload,
-    pool: web::Data<DbPool>,
+    service: web::Data<Service>,
+    user: auth::AuthenticatedUser,
+    live_output: web::Data<OutputSender>,
+    events: web::Data<EventSender>,
+    metrics: web::Data<Metrics>,
 ) -> Result<HttpResponse, Error> {
-    ws::start(
-        websocket::WsApiSession::new(pool.get_ref().clone()),
+    ws::WsResponseBuilder::new(
+        websocket::WsApiSession::new(
+            service.get_ref().clone(),
+            user.0,
+            live_output.get_ref().clone(),
+            events.get_ref().clone(),
+            metrics.websocket_sessions.clone(),
+        ),
         &req,
         stream,
     )
+    .frame_size(websocket::MAX_FRAME_SIZE)
+    .start()
+}
+
+/// Prometheus metrics in the text format.
+async fn render_metrics(
+    pool: web::Data<DbPool>,
+    metrics: web::Data<Metrics>,
+) -> Result<HttpResponse, Error> {
+    let text = web::block(move || metrics.render(&pool))
+        .await?
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(text))
 }
@@ -1,6 +1,23 @@
+use super::auth::{self, AuthenticatedUser};
+use super::farm_api;
+use super::service::{
+    ExploitArguments, ManualFlagArguments, NewUserArguments, PolicyArguments, Service,
+    ServiceError, TeamArguments, TestRunArguments, MAX_EXPLOIT_VERSION_SIZE,
+};
+use crate::alerts;
+use crate::audit;
+use crate::challenge;
+use crate::environment;
+use crate::exploit;
+use crate::exploit_runner;
+use crate::flag_submitter;
+use crate::settings;
+use crate::stats;
 use crate::team;
-use crate::DbPool;
-use actix_web::{get, patch, put, web, Error, HttpResponse};
+use crate::webhook;
+use actix_web::http::header::ContentDisposition;
+use actix_web::http::StatusCode;
+use actix_web::{delete, get, patch, post, put, web, HttpResponse, ResponseError};
 use serde::Deserialize;
 use serde::Serialize;
 
@@ -9,132 +26,829 @@ pub struct ApiError {
     pub error: String,
 }
 
+#[derive(Serialize)]
+struct PolicyInUseError<'a> {
+    error: String,
+    usage: &'a exploit::PolicyUsage,
+}
+
+impl ResponseError for ServiceError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ServiceError::NotFound(_) => StatusCode::NOT_FOUND,
+            ServiceError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ServiceError::InvalidArguments(_) => StatusCode::BAD_REQUEST,
+            ServiceError::PolicyInUse { .. } => StatusCode::CONFLICT,
+            ServiceError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            ServiceError::PolicyInUse { usage, .. } => response.json(PolicyInUseError {
+                error: self.to_string(),
+                usage,
+            }),
+            ServiceError::Internal(err) => {
+                log::error!("API request failed: {}", err);
+                response.json(ApiError {
+                    error: self.to_string(),
+                })
+            }
+            _ => response.json(ApiError {
+                error: self.to_string(),
+            }),
+        }
+    }
+}
+
+pub(super) type ApiResult = Result<HttpResponse, ServiceError>;
+
 pub fn config(cfg: &mut web::ServiceConfig) {
     let rest_api = web::scope("/api")
+        .service(auth::login)
+        .service(auth::logout)
+        .service(get_me)
+        .service(get_api_tokens)
+        .service(add_api_token)
+        .service(remove_api_token)
+        .service(get_users)
+        .service(add_user)
+        .service(update_user)
+        .service(delete_user)
         .service(get_teams)
         .service(get_team)
         .service(add_team)
-        .service(update_team);
+        .service(update_team)
+        .service(set_team_meta)
+        .service(remove_team_meta)
+        .service(get_challenges)
+        .service(get_challenge)
+        .service(add_challenge)
+        .service(update_challenge)
+        .service(delete_challenge)
+        .service(get_skipped_targets)
+        .service(restore_target)
+        .service(get_policies)
+        .service(get_policy)
+        .service(add_policy)
+        .service(update_policy)
+        .service(delete_policy)
+        .service(get_exploits)
+        .service(get_exploit)
+        .service(add_exploit)
+        .service(update_exploit)
+        .service(set_exploit_target)
+        .service(remove_exploit_target)
+        .service(set_exploit_meta)
+        .service(remove_exploit_meta)
+        .service(
+            web::resource("/exploit/{exploit_id}/version")
+                .app_data(web::PayloadConfig::new(MAX_EXPLOIT_VERSION_SIZE))
+                .route(web::put().to(upload_exploit_version)),
+        )
+        .service(get_exploit_environment)
+        .service(set_exploit_environment_variable)
+        .service(remove_exploit_environment_variable)
+        .service(test_run_exploit)
+        .service(get_exploit_versions)
+        .service(get_exploit_version_content)
+        .service(get_runs)
+        .service(get_run)
+        .service(get_run_output)
+        .service(get_environment)
+        .service(set_environment_variable)
+        .service(remove_environment_variable)
+        .service(get_settings)
+        .service(update_settings)
+        .service(get_flag_response_rules)
+        .service(set_flag_response_rules)
+        .service(get_flag_stats)
+        .service(get_run_stats)
+        .service(get_webhooks)
+        .service(add_webhook)
+        .service(update_webhook)
+        .service(delete_webhook)
+        .service(test_webhook)
+        .service(get_alerts)
+        .service(acknowledge_alert)
+        .service(get_audit_log)
+        .service(get_flags)
+        .service(add_flags)
+        .service(requeue_flags)
+        .service(remap_flag_responses)
+        .configure(farm_api::config);
 
     cfg.service(rest_api);
 }
 
+#[get("/me")]
+async fn get_me(user: AuthenticatedUser) -> ApiResult {
+    Ok(HttpResponse::Ok().json(user.0))
+}
+
+#[get("/tokens")]
+async fn get_api_tokens(service: web::Data<Service>, user: AuthenticatedUser) -> ApiResult {
+    let tokens = service.get_api_tokens(user.0.id).await?;
+    Ok(HttpResponse::Ok().json(tokens))
+}
+
 #[derive(Deserialize)]
-struct TeamArguments {
-    include_meta_values: Option<bool>,
+struct ApiTokenArguments {
+    name: String,
 }
 
-#[derive(Serialize)]
-struct TeamResult {
-    team: team::Team,
-    meta_data: Option<Vec<team::TeamMeta>>,
+#[put("/token")]
+async fn add_api_token(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    args: web::Json<ApiTokenArguments>,
+) -> ApiResult {
+    let token = service
+        .add_api_token(&user.0, args.into_inner().name)
+        .await?;
+    Ok(HttpResponse::Ok().json(token))
 }
 
-#[get("/teams")]
-async fn get_teams(
-    pool: web::Data<DbPool>,
-    args: web::Query<TeamArguments>,
-) -> Result<HttpResponse, Error> {
-    let team_list = web::block(move || -> Result<Vec<TeamResult>, crate::db::Error> {
-        let conn = &mut pool.get()?;
-        let db_team_list = team::get_teams(conn)?;
-
-        let mut team_list = Vec::new();
-        for db_team in db_team_list {
-            let meta_data = if args.include_meta_values.unwrap_or(false) {
-                Some(db_team.get_meta_data(conn)?)
-            } else {
-                None
-            };
-            team_list.push(TeamResult {
-                team: db_team,
-                meta_data,
-            });
-        }
-        Ok(team_list)
-    })
-    .await?
-    .map_err(actix_web::error::ErrorInternalServerError)?;
+#[delete("/token/{token_id}")]
+async fn remove_api_token(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    token_id: web::Path<i32>,
+) -> ApiResult {
+    service
+        .remove_api_token(&user.0, token_id.into_inner())
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/users")]
+async fn get_users(service: web::Data<Service>, user: AuthenticatedUser) -> ApiResult {
+    let users = service.get_users(&user.0).await?;
+    Ok(HttpResponse::Ok().json(users))
+}
+
+#[put("/user")]
+async fn add_user(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    new_user: web::Json<NewUserArguments>,
+) -> ApiResult {
+    let new_user = service.add_user(&user.0, new_user.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(new_user))
+}
 
+#[patch("/user/{user_id}")]
+async fn update_user(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    user_id: web::Path<i32>,
+    new_user: web::Json<NewUserArguments>,
+) -> ApiResult {
+    let updated_user = service
+        .update_user(&user.0, user_id.into_inner(), new_user.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(updated_user))
+}
+
+#[delete("/user/{user_id}")]
+async fn delete_user(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    user_id: web::Path<i32>,
+) -> ApiResult {
+    service.delete_user(&user.0, user_id.into_inner()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/teams")]
+async fn get_teams(service: web::Data<Service>, args: web::Query<TeamArguments>) -> ApiResult {
+    let team_list = service.get_teams(args.into_inner()).await?;
     Ok(HttpResponse::Ok().json(team_list))
 }
 
 #[get("/team/{team_id}")]
 async fn get_team(
-    pool: web::Data<DbPool>,
+    service: web::Data<Service>,
     args: web::Query<TeamArguments>,
     team_id: web::Path<i32>,
-) -> Result<HttpResponse, Error> {
-    let team_id = team_id.into_inner();
-    let team = web::block(move || -> Result<Option<TeamResult>, crate::db::Error> {
-        let conn = &mut pool.get()?;
-        match team::find_team_by_id(conn, team_id)? {
-            Some(team) => {
-                let meta_data = if args.include_meta_values.unwrap_or(false) {
-                    Some(team.get_meta_data(conn)?)
-                } else {
-                    None
-                };
-                Ok(Some(TeamResult { team, meta_data }))
-            }
-            None => Ok(None),
-        }
-    })
-    .await?
-    .map_err(actix_web::error::ErrorInternalServerError)?;
-
-    if let Some(team) = team {
-        Ok(HttpResponse::Ok().json(team))
-    } else {
-        Ok(HttpResponse::NotFound().json(ApiError {
-            error: format!("No team found with id: {team_id}"),
-        }))
-    }
+) -> ApiResult {
+    let team = service
+        .get_team(team_id.into_inner(), args.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(team))
 }
 
 #[put("/team")]
 async fn add_team(
-    pool: web::Data<DbPool>,
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
     team: web::Json<team::Team>,
-) -> Result<HttpResponse, Error> {
-    let team = web::block(move || {
-        let conn = &mut pool.get()?;
-        team::add_team(conn, team.into_inner())
-    })
-    .await?
-    .map_err(actix_web::error::ErrorInternalServerError)?;
-
+) -> ApiResult {
+    let team = service.add_team(&user.0, team.into_inner()).await?;
     Ok(HttpResponse::Ok().json(team))
 }
 
 #[patch("/team/{team_id}")]
 async fn update_team(
-    pool: web::Data<DbPool>,
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
     team_id: web::Path<i32>,
     new_team: web::Json<team::Team>,
-) -> Result<HttpResponse, Error> {
-    let team_id = team_id.into_inner();
-    let new_team = new_team.into_inner();
-    let team = web::block(move || -> Result<Option<team::Team>, crate::db::Error> {
-        let conn = &mut pool.get()?;
-        match team::find_team_by_id(conn, team_id)? {
-            Some(mut team) => {
-                team.name = new_team.name.clone();
-                team.state = new_team.state;
-                team.save(conn)?;
-                Ok(Some(team))
-            }
-            None => Ok(None),
-        }
-    })
-    .await?
-    .map_err(actix_web::error::ErrorInternalServerError)?;
-
-    if let Some(team) = team {
-        Ok(HttpResponse::Ok().json(team))
-    } else {
-        Ok(HttpResponse::NotFound().json(ApiError {
-            error: format!("No team found with id: {team_id}"),
-        }))
-    }
+) -> ApiResult {
+    let team = service
+        .update_team(&user.0, team_id.into_inner(), new_team.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(team))
+}
+
+#[derive(Deserialize)]
+struct MetaValueArguments {
+    value: String,
+}
+
+#[put("/team/{team_id}/meta/{key}")]
+async fn set_team_meta(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, String)>,
+    args: web::Json<MetaValueArguments>,
+) -> ApiResult {
+    let (team_id, key) = path.into_inner();
+    let meta = service
+        .set_team_meta(&user.0, team_id, key, args.into_inner().value)
+        .await?;
+    Ok(HttpResponse::Ok().json(meta))
+}
+
+#[delete("/team/{team_id}/meta/{key}")]
+async fn remove_team_meta(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, String)>,
+) -> ApiResult {
+    let (team_id, key) = path.into_inner();
+    service.remove_team_meta(&user.0, team_id, key).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/challenges")]
+async fn get_challenges(service: web::Data<Service>) -> ApiResult {
+    let challenges = service.get_challenges().await?;
+    Ok(HttpResponse::Ok().json(challenges))
+}
+
+#[get("/challenge/{challenge_id}")]
+async fn get_challenge(service: web::Data<Service>, challenge_id: web::Path<i32>) -> ApiResult {
+    let challenge = service.get_challenge(challenge_id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(challenge))
+}
+
+#[put("/challenge")]
+async fn add_challenge(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    challenge: web::Json<challenge::NewChallenge>,
+) -> ApiResult {
+    let challenge = service
+        .add_challenge(&user.0, challenge.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(challenge))
+}
+
+#[patch("/challenge/{challenge_id}")]
+async fn update_challenge(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    challenge_id: web::Path<i32>,
+    new_challenge: web::Json<challenge::NewChallenge>,
+) -> ApiResult {
+    let challenge = service
+        .update_challenge(
+            &user.0,
+            challenge_id.into_inner(),
+            new_challenge.into_inner(),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().json(challenge))
+}
+
+#[delete("/challenge/{challenge_id}")]
+async fn delete_challenge(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    challenge_id: web::Path<i32>,
+) -> ApiResult {
+    service
+        .delete_challenge(&user.0, challenge_id.into_inner())
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/skipped_targets")]
+async fn get_skipped_targets(service: web::Data<Service>) -> ApiResult {
+    let targets = service.get_skipped_targets().await?;
+    Ok(HttpResponse::Ok().json(targets))
+}
+
+#[delete("/team/{team_id}/skipped/{target_challenge}")]
+async fn restore_target(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, String)>,
+) -> ApiResult {
+    let (team_id, target_challenge) = path.into_inner();
+    let target = service
+        .restore_target(&user.0, team_id, target_challenge)
+        .await?;
+    Ok(HttpResponse::Ok().json(target))
+}
+
+#[get("/policies")]
+async fn get_policies(service: web::Data<Service>, args: web::Query<PolicyArguments>) -> ApiResult {
+    let policy_list = service.get_policies(args.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(policy_list))
+}
+
+#[get("/policy/{policy_id}")]
+async fn get_policy(
+    service: web::Data<Service>,
+    args: web::Query<PolicyArguments>,
+    policy_id: web::Path<i32>,
+) -> ApiResult {
+    let policy = service
+        .get_policy(policy_id.into_inner(), args.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(policy))
+}
+
+#[put("/policy")]
+async fn add_policy(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    policy: web::Json<exploit::NewPolicy>,
+) -> ApiResult {
+    let policy = service.add_policy(&user.0, policy.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(policy))
+}
+
+#[patch("/policy/{policy_id}")]
+async fn update_policy(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    policy_id: web::Path<i32>,
+    new_policy: web::Json<exploit::NewPolicy>,
+) -> ApiResult {
+    let policy = service
+        .update_policy(&user.0, policy_id.into_inner(), new_policy.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(policy))
+}
+
+#[delete("/policy/{policy_id}")]
+async fn delete_policy(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    policy_id: web::Path<i32>,
+) -> ApiResult {
+    service
+        .delete_policy(&user.0, policy_id.into_inner())
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/exploits")]
+async fn get_exploits(
+    service: web::Data<Service>,
+    args: web::Query<ExploitArguments>,
+) -> ApiResult {
+    let exploit_list = service.get_exploits(args.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(exploit_list))
+}
+
+#[get("/exploit/{exploit_id}")]
+async fn get_exploit(
+    service: web::Data<Service>,
+    args: web::Query<ExploitArguments>,
+    exploit_id: web::Path<i32>,
+) -> ApiResult {
+    let exploit = service
+        .get_exploit(exploit_id.into_inner(), args.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(exploit))
+}
+
+#[put("/exploit")]
+async fn add_exploit(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    exploit: web::Json<exploit::NewExploit>,
+) -> ApiResult {
+    let exploit = service.add_exploit(&user.0, exploit.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(exploit))
+}
+
+#[patch("/exploit/{exploit_id}")]
+async fn update_exploit(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    exploit_id: web::Path<i32>,
+    new_exploit: web::Json<exploit::NewExploit>,
+) -> ApiResult {
+    let exploit = service
+        .update_exploit(&user.0, exploit_id.into_inner(), new_exploit.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(exploit))
+}
+
+#[derive(Deserialize)]
+struct TargetArguments {
+    policy_id: i32,
+}
+
+#[put("/exploit/{exploit_id}/target/{team_id}")]
+async fn set_exploit_target(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+    args: web::Json<TargetArguments>,
+) -> ApiResult {
+    let (exploit_id, team_id) = path.into_inner();
+    let target = service
+        .set_exploit_target(&user.0, exploit_id, team_id, args.policy_id)
+        .await?;
+    Ok(HttpResponse::Ok().json(target))
+}
+
+#[delete("/exploit/{exploit_id}/target/{team_id}")]
+async fn remove_exploit_target(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, i32)>,
+) -> ApiResult {
+    let (exploit_id, team_id) = path.into_inner();
+    service
+        .remove_exploit_target(&user.0, exploit_id, team_id)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[put("/exploit/{exploit_id}/meta/{key}")]
+async fn set_exploit_meta(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, String)>,
+    args: web::Json<MetaValueArguments>,
+) -> ApiResult {
+    let (exploit_id, key) = path.into_inner();
+    let meta = service
+        .set_exploit_meta(&user.0, exploit_id, key, args.into_inner().value)
+        .await?;
+    Ok(HttpResponse::Ok().json(meta))
+}
+
+#[delete("/exploit/{exploit_id}/meta/{key}")]
+async fn remove_exploit_meta(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, String)>,
+) -> ApiResult {
+    let (exploit_id, key) = path.into_inner();
+    service
+        .remove_exploit_meta(&user.0, exploit_id, key)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/exploit/{exploit_id}/environment")]
+async fn get_exploit_environment(
+    service: web::Data<Service>,
+    exploit_id: web::Path<i32>,
+) -> ApiResult {
+    let variables = service
+        .get_environment(Some(exploit_id.into_inner()))
+        .await?;
+    Ok(HttpResponse::Ok().json(variables))
+}
+
+#[put("/exploit/{exploit_id}/environment/{name}")]
+async fn set_exploit_environment_variable(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, String)>,
+    variable: web::Json<environment::NewEnvironmentVariable>,
+) -> ApiResult {
+    let (exploit_id, name) = path.into_inner();
+    let variable = service
+        .set_environment_variable(&user.0, Some(exploit_id), name, variable.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(variable))
+}
+
+#[delete("/exploit/{exploit_id}/environment/{name}")]
+async fn remove_exploit_environment_variable(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    path: web::Path<(i32, String)>,
+) -> ApiResult {
+    let (exploit_id, name) = path.into_inner();
+    service
+        .remove_environment_variable(&user.0, Some(exploit_id), name)
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Answers once the run finished. Use the websocket to follow the output while it's running.
+#[post("/exploit/{exploit_id}/test_run")]
+async fn test_run_exploit(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    exploit_id: web::Path<i32>,
+    args: web::Json<TestRunArguments>,
+) -> ApiResult {
+    let result = service
+        .test_run_exploit(
+            &user.0,
+            exploit_id.into_inner(),
+            args.into_inner(),
+            exploit_runner::output_channel(),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[derive(Deserialize)]
+struct UploadArguments {
+    filename: String,
+}
+
+/// The request body is the script or archive itself.
+async fn upload_exploit_version(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    exploit_id: web::Path<i32>,
+    args: web::Query<UploadArguments>,
+    content: web::Bytes,
+) -> ApiResult {
+    let version = service
+        .upload_exploit_version(
+            &user.0,
+            exploit_id.into_inner(),
+            args.into_inner().filename,
+            content.to_vec(),
+        )
+        .await?;
+    Ok(HttpResponse::Ok().json(version))
+}
+
+#[get("/exploit/{exploit_id}/versions")]
+async fn get_exploit_versions(
+    service: web::Data<Service>,
+    exploit_id: web::Path<i32>,
+) -> ApiResult {
+    let versions = service
+        .get_exploit_versions(exploit_id.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(versions))
+}
+
+#[get("/exploit_version/{version_id}/content")]
+async fn get_exploit_version_content(
+    service: web::Data<Service>,
+    version_id: web::Path<i32>,
+) -> ApiResult {
+    let (version, content) = service
+        .get_exploit_version_content(version_id.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/octet-stream")
+        .insert_header(ContentDisposition::attachment(version.filename))
+        .body(content))
+}
+
+#[get("/runs")]
+async fn get_runs(
+    service: web::Data<Service>,
+    filter: web::Query<exploit::RunFilter>,
+) -> ApiResult {
+    let runs = service.get_runs(filter.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(runs))
+}
+
+#[get("/run/{run_id}")]
+async fn get_run(service: web::Data<Service>, run_id: web::Path<i64>) -> ApiResult {
+    let run = service.get_run(run_id.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(run))
+}
+
+#[get("/run/{run_id}/output")]
+async fn get_run_output(service: web::Data<Service>, run_id: web::Path<i64>) -> ApiResult {
+    let output = service.get_run_output(run_id.into_inner()).await?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .body(output))
+}
+
+#[get("/environment")]
+async fn get_environment(service: web::Data<Service>) -> ApiResult {
+    let variables = service.get_environment(None).await?;
+    Ok(HttpResponse::Ok().json(variables))
+}
+
+#[put("/environment/{name}")]
+async fn set_environment_variable(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    name: web::Path<String>,
+    variable: web::Json<environment::NewEnvironmentVariable>,
+) -> ApiResult {
+    let variable = service
+        .set_environment_variable(&user.0, None, name.into_inner(), variable.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(variable))
+}
+
+#[delete("/environment/{name}")]
+async fn remove_environment_variable(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    name: web::Path<String>,
+) -> ApiResult {
+    service
+        .remove_environment_variable(&user.0, None, name.into_inner())
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/settings")]
+async fn get_settings(service: web::Data<Service>) -> ApiResult {
+    let settings = service.get_settings().await?;
+    Ok(HttpResponse::Ok().json(settings))
+}
+
+#[patch("/settings")]
+async fn update_settings(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    new_settings: web::Json<settings::Settings>,
+) -> ApiResult {
+    let settings = service
+        .update_settings(&user.0, new_settings.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(settings))
+}
+
+#[get("/flag_response_rules")]
+async fn get_flag_response_rules(service: web::Data<Service>) -> ApiResult {
+    let rules = service.get_flag_response_rules().await?;
+    Ok(HttpResponse::Ok().json(rules))
+}
+
+#[put("/flag_response_rules")]
+async fn set_flag_response_rules(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    rules: web::Json<Vec<flag_submitter::NewFlagResponseRule>>,
+) -> ApiResult {
+    let rules = service
+        .set_flag_response_rules(&user.0, rules.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(rules))
+}
+
+#[get("/flags")]
+async fn get_flags(
+    service: web::Data<Service>,
+    filter: web::Query<flag_submitter::FlagFilter>,
+) -> ApiResult {
+    let flags = service.get_flags(filter.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(flags))
+}
+
+#[post("/flags")]
+async fn add_flags(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    args: web::Json<ManualFlagArguments>,
+) -> ApiResult {
+    let result = service.add_flags(&user.0, args.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/flags/requeue")]
+async fn requeue_flags(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    filter: web::Json<flag_submitter::FlagFilter>,
+) -> ApiResult {
+    let result = service.requeue_flags(&user.0, filter.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[post("/flags/remap")]
+async fn remap_flag_responses(service: web::Data<Service>, user: AuthenticatedUser) -> ApiResult {
+    let result = service.remap_flag_responses(&user.0).await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/stats/flags")]
+async fn get_flag_stats(
+    service: web::Data<Service>,
+    filter: web::Query<stats::StatsFilter>,
+) -> ApiResult {
+    let stats = service.get_flag_stats(filter.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+#[get("/stats/runs")]
+async fn get_run_stats(
+    service: web::Data<Service>,
+    filter: web::Query<stats::StatsFilter>,
+) -> ApiResult {
+    let stats = service.get_run_stats(filter.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(stats))
+}
+
+#[get("/webhooks")]
+async fn get_webhooks(service: web::Data<Service>, user: AuthenticatedUser) -> ApiResult {
+    let webhooks = service.get_webhooks(&user.0).await?;
+    Ok(HttpResponse::Ok().json(webhooks))
+}
+
+#[put("/webhook")]
+async fn add_webhook(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    webhook: web::Json<webhook::NewWebhook>,
+) -> ApiResult {
+    let webhook = service.add_webhook(&user.0, webhook.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(webhook))
+}
+
+#[patch("/webhook/{webhook_id}")]
+async fn update_webhook(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    webhook_id: web::Path<i32>,
+    new_webhook: web::Json<webhook::NewWebhook>,
+) -> ApiResult {
+    let webhook = service
+        .update_webhook(&user.0, webhook_id.into_inner(), new_webhook.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(webhook))
+}
+
+#[delete("/webhook/{webhook_id}")]
+async fn delete_webhook(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    webhook_id: web::Path<i32>,
+) -> ApiResult {
+    service
+        .delete_webhook(&user.0, webhook_id.into_inner())
+        .await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[post("/webhook/{webhook_id}/test")]
+async fn test_webhook(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    webhook_id: web::Path<i32>,
+) -> ApiResult {
+    let result = service
+        .test_webhook(&user.0, webhook_id.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(result))
+}
+
+#[get("/alerts")]
+async fn get_alerts(
+    service: web::Data<Service>,
+    filter: web::Query<alerts::AlertFilter>,
+) -> ApiResult {
+    let alerts = service.get_alerts(filter.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(alerts))
+}
+
+#[post("/alert/{alert_id}/acknowledge")]
+async fn acknowledge_alert(
+    service: web::Data<Service>,
+    user: AuthenticatedUser,
+    alert_id: web::Path<i64>,
+) -> ApiResult {
+    let alert = service
+        .acknowledge_alert(&user.0, alert_id.into_inner())
+        .await?;
+    Ok(HttpResponse::Ok().json(alert))
+}
+
+#[get("/audit")]
+async fn get_audit_log(
+    service: web::Data<Service>,
+    filter: web::Query<audit::AuditFilter>,
+) -> ApiResult {
+    let entries = service.get_audit_log(filter.into_inner()).await?;
+    Ok(HttpResponse::Ok().json(entries))
 }
@@ -0,0 +1,1796 @@
+//! Operations of the API shared by the REST and the websocket transport.
+//!
+//! Both only parse their arguments and encode the result,
+//! so they can't drift apart. Database access runs on the blocking thread pool.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use actix::Addr;
+use diesel::{Connection, PgConnection};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::alerts;
+use crate::audit;
+use crate::challenge;
+use crate::db;
+use crate::environment::{self, EnvironmentVariable, NewEnvironmentVariable};
+use crate::events::{self, Event, EventSender};
+use crate::exploit;
+use crate::exploit_runner;
+use crate::flag_submitter;
+use crate::settings;
+use crate::stats;
+use crate::team;
+use crate::user::{self, Role, User};
+use crate::webhook;
+use crate::DbPool;
+
+#[derive(Debug)]
+pub enum ServiceError {
+    NotFound(String),
+    /// The user isn't allowed to do this.
+    Forbidden(String),
+    /// The arguments are syntactically fine, but don't make sense.
+    InvalidArguments(String),
+    /// The policy can't be deleted while something still uses it.
+    PolicyInUse {
+        policy_id: i32,
+        usage: exploit::PolicyUsage,
+    },
+    Internal(db::Error),
+}
+
+impl std::fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServiceError::NotFound(message)
+            | ServiceError::Forbidden(message)
+            | ServiceError::InvalidArguments(message) => write!(f, "{message}"),
+            ServiceError::PolicyInUse { policy_id, .. } => {
+                write!(f, "Policy {policy_id} is still in use")
+            }
+            ServiceError::Internal(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl From<db::Error> for ServiceError {
+    fn from(err: db::Error) -> Self {
+        ServiceError::Internal(err)
+    }
+}
+
+impl From<diesel::result::Error> for ServiceError {
+    fn from(err: diesel::result::Error) -> Self {
+        ServiceError::Internal(err.into())
+    }
+}
+
+impl From<r2d2::Error> for ServiceError {
+    fn from(err: r2d2::Error) -> Self {
+        ServiceError::Internal(err.into())
+    }
+}
+
+pub type ServiceResult<T> = Result<T, ServiceError>;
+
+/// Largest exploit script or archive which can be uploaded.
+pub const MAX_EXPLOIT_VERSION_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Deserialize, Default)]
+pub struct TeamArguments {
+    pub include_meta_values: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct TeamResult {
+    pub team: team::Team,
+    pub meta_data: Option<Vec<team::TeamMeta>>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct PolicyArguments {
+    pub include_usage: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct PolicyResult {
+    policy: exploit::Policy,
+    usage: Option<exploit::PolicyUsage>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ExploitArguments {
+    pub include_meta_values: Option<bool>,
+    pub include_targets: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct ExploitResult {
+    exploit: exploit::Exploit,
+    meta_data: Option<Vec<exploit::ExploitMeta>>,
+    targets: Option<Vec<exploit::ExploitTarget>>,
+}
+
+impl ExploitArguments {
+    fn load_result(
+        &self,
+        conn: &mut PgConnection,
+        exploit: exploit::Exploit,
+    ) -> Result<ExploitResult, db::Error> {
+        let meta_data = if self.include_meta_values.unwrap_or(false) {
+            Some(exploit.get_meta_data(conn)?)
+        } else {
+            None
+        };
+        let targets = if self.include_targets.unwrap_or(false) {
+            Some(exploit.get_targets(conn)?)
+        } else {
+            None
+        };
+        Ok(ExploitResult {
+            exploit,
+            meta_data,
+            targets,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct RunListResult {
+    runs: Vec<exploit::ExploitRun>,
+    /// Pass as `before` to get the next page. Not set on the last page.
+    next_cursor: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RunResult {
+    pub run: exploit::ExploitRun,
+    pub flags: Vec<flag_submitter::Flag>,
+}
+
+#[derive(Serialize)]
+pub struct FlagListResult {
+    flags: Vec<flag_submitter::FlagListEntry>,
+    /// Pass as `before` to get the next page. Not set on the last page.
+    next_cursor: Option<i64>,
+    /// Number of flags per submission result matching the filter apart from the submission result.
+    counts: BTreeMap<flag_submitter::FlagSubmissionResult, i64>,
+}
+
+#[derive(Serialize)]
+pub struct RequeueResult {
+    requeued: usize,
+}
+
+#[derive(Serialize)]
+pub struct RemapResult {
+    /// Flags which match a response rule now.
+    remapped: Vec<events::FlagSubmission>,
+    /// Number of responses which still don't match any rule.
+    unknown: i64,
+}
+
+#[derive(Serialize)]
+pub struct AlertListResult {
+    alerts: Vec<alerts::Alert>,
+    /// Pass as `before` to get the next page. Not set on the last page.
+    next_cursor: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct WebhookTestResult {
+    delivered: bool,
+    /// Why the webhook couldn't be reached. Only set if it wasn't delivered.
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct AuditListResult {
+    entries: Vec<audit::AuditEntry>,
+    /// Pass as `before` to get the next page. Not set on the last page.
+    next_cursor: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ManualFlagArguments {
+    /// Anything containing flags, e.g. the output of a manual exploit.
+    pub text: String,
+    /// The team the flags were stolen from.
+    pub team_id: Option<i32>,
+    pub target_challenge: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TestRunArguments {
+    pub team_id: i32,
+    /// Queue the found flags for submission like flags added by hand.
+    #[serde(default)]
+    pub submit: bool,
+}
+
+#[derive(Serialize)]
+pub struct TestRunResponse {
+    #[serde(flatten)]
+    run: exploit_runner::TestRunResult,
+    /// The queued flags if `submit` was set.
+    submitted: Option<ManualFlagResult>,
+}
+
+#[derive(Serialize)]
+pub struct ManualFlagResult {
+    /// Flags which weren't seen before and were queued for submission.
+    flags: Vec<flag_submitter::Flag>,
+    /// Flags which are known already.
+    duplicates: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct NewUserArguments {
+    pub username: String,
+    /// Required for new users. Keeps the current password when updating if not set.
+    pub password: Option<String>,
+    pub role: Role,
+}
+
+#[derive(Serialize)]
+pub struct NewApiTokenResult {
+    token: user::ApiToken,
+    /// The token to use in the `Authorization: Bearer` header. It's only shown once.
+    secret: String,
+}
+
+/// Entry point to all API operations. Cheap to clone.
+#[derive(Clone)]
+pub struct Service {
+    pool: DbPool,
+    events: EventSender,
+    /// Test runs are started by the runner, so they count against its parallel runs.
+    exploit_runner: Addr<exploit_runner::ExploitRunner>,
+    /// Uploaded exploit versions are unpacked into this directory.
+    storage_path: PathBuf,
+}
+
+impl Service {
+    pub fn new(
+        pool: DbPool,
+        events: EventSender,
+        exploit_runner: Addr<exploit_runner::ExploitRunner>,
+        storage_path: PathBuf,
+    ) -> Self {
+        Self {
+            pool,
+            events,
+            exploit_runner,
+            storage_path,
+        }
+    }
+
+    /// Run the database work on the blocking thread pool.
+    async fn with_conn<T, F>(&self, f: F) -> ServiceResult<T>
+    where
+        F: FnOnce(&mut PgConnection) -> ServiceResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.pool.clone();
+        actix_web::web::block(move || {
+            let conn = &mut pool.get()?;
+            f(conn)
+        })
+        .await
+        .map_err(|err| ServiceError::Internal(err.into()))?
+    }
+
+    /// Like `with_conn`, but records the returned change in the audit log.
+    /// Both happen in one transaction, so failed changes leave no trace.
+    async fn with_audited_conn<T, F>(&self, user: &User, f: F) -> ServiceResult<T>
+    where
+        F: FnOnce(&mut PgConnection) -> ServiceResult<(T, audit::Change)> + Send + 'static,
+        T: Send + 'static,
+    {
+        let user = user.clone();
+        let (result, entry) = self
+            .with_conn(move |conn| {
+                conn.transaction(|conn| {
+                    let (result, change) = f(conn)?;
+                    let entry = audit::add_entry(conn, &user, change)?;
+                    Ok((result, entry))
+                })
+            })
+            .await?;
+        self.publish(Event::AuditLogged(entry));
+        Ok(result)
+    }
+
+    fn publish(&self, event: Event) {
+        events::publish(&self.events, event);
+    }
+
+    pub async fn get_api_tokens(&self, user_id: i32) -> ServiceResult<Vec<user::ApiToken>> {
+        self.with_conn(move |conn| Ok(user::get_api_tokens(conn, user_id)?))
+            .await
+    }
+
+    pub async fn add_api_token(
+        &self,
+        user: &User,
+        name: String,
+    ) -> ServiceResult<NewApiTokenResult> {
+        let user_id = user.id;
+        self.with_audited_conn(user, move |conn| {
+            let (token, secret) = user::add_api_token(conn, user_id, &name)?;
+            let change = audit::Change::new("add_api_token", "api_token", Some(token.id.into()))
+                .after(&token);
+            Ok((NewApiTokenResult { token, secret }, change))
+        })
+        .await
+    }
+
+    pub async fn remove_api_token(&self, user: &User, token_id: i32) -> ServiceResult<()> {
+        let user_id = user.id;
+        self.with_audited_conn(user, move |conn| {
+            if user::delete_api_token(conn, user_id, token_id)? {
+                let change =
+                    audit::Change::new("remove_api_token", "api_token", Some(token_id.into()));
+                Ok(((), change))
+            } else {
+                Err(ServiceError::NotFound(format!(
+                    "No API token found with id: {token_id}"
+                )))
+            }
+        })
+        .await
+    }
+
+    pub async fn get_users(&self, user: &User) -> ServiceResult<Vec<User>> {
+        require_admin(user)?;
+        self.with_conn(|conn| Ok(user::get_users(conn)?)).await
+    }
+
+    pub async fn add_user(&self, user: &User, new_user: NewUserArguments) -> ServiceResult<User> {
+        require_admin(user)?;
+        let password = new_user.password.ok_or_else(|| {
+            ServiceError::InvalidArguments("A password is required for new users".to_string())
+        })?;
+        if password.is_empty() {
+            return Err(ServiceError::InvalidArguments(
+                "The password must not be empty".to_string(),
+            ));
+        }
+        self.with_audited_conn(user, move |conn| {
+            if user::find_user_by_name(conn, &new_user.username)?.is_some() {
+                return Err(ServiceError::InvalidArguments(format!(
+                    "User {} already exists",
+                    new_user.username
+                )));
+            }
+            let user = user::add_user(conn, &new_user.username, &password, new_user.role)?;
+            let change = audit::Change::new("add_user", "user", Some(user.id.into())).after(&user);
+            Ok((user, change))
+        })
+        .await
+    }
+
+    /// Change the role and optionally the password of a user.
+    pub async fn update_user(
+        &self,
+        user: &User,
+        user_id: i32,
+        new_user: NewUserArguments,
+    ) -> ServiceResult<User> {
+        require_admin(user)?;
+        if user.id == user_id && new_user.role != Role::Admin {
+            return Err(ServiceError::InvalidArguments(
+                "Admins can't take away their own admin role".to_string(),
+            ));
+        }
+        self.with_audited_conn(user, move |conn| {
+            let mut user = find_user(conn, user_id)?;
+            let change =
+                audit::Change::new("update_user", "user", Some(user_id.into())).before(&user);
+            user.set_role(conn, new_user.role)?;
+            match new_user.password.as_deref() {
+                Some("") => {
+                    return Err(ServiceError::InvalidArguments(
+                        "The password must not be empty".to_string(),
+                    ))
+                }
+                Some(password) => user.set_password(conn, password)?,
+                None => (),
+            }
+            let change = change.after(&user);
+            Ok((user, change))
+        })
+        .await
+    }
+
+    pub async fn delete_user(&self, user: &User, user_id: i32) -> ServiceResult<()> {
+        require_admin(user)?;
+        if user.id == user_id {
+            return Err(ServiceError::InvalidArguments(
+                "Admins can't delete themselves".to_string(),
+            ));
+        }
+        self.with_audited_conn(user, move |conn| {
+            let user = find_user(conn, user_id)?;
+            user::delete_user(conn, user_id)?;
+            let change =
+                audit::Change::new("delete_user", "user", Some(user_id.into())).before(&user);
+            Ok(((), change))
+        })
+        .await
+    }
+
+    pub async fn get_teams(&self, args: TeamArguments) -> ServiceResult<Vec<TeamResult>> {
+        self.with_conn(move |conn| {
+            let mut team_list = Vec::new();
+            for team in team::get_teams(conn)? {
+                let meta_data = if args.include_meta_values.unwrap_or(false) {
+                    Some(team.get_meta_data(conn)?)
+                } else {
+                    None
+                };
+                team_list.push(TeamResult { team, meta_data });
+            }
+            Ok(team_list)
+        })
+        .await
+    }
+
+    pub async fn get_team(&self, team_id: i32, args: TeamArguments) -> ServiceResult<TeamResult> {
+        self.with_conn(move |conn| {
+            let team = find_team(conn, team_id)?;
+            let meta_data = if args.include_meta_values.unwrap_or(false) {
+                Some(team.get_meta_data(conn)?)
+            } else {
+                None
+            };
+            Ok(TeamResult { team, meta_data })
+        })
+        .await
+    }
+
+    pub async fn add_team(&self, user: &User, team: team::Team) -> ServiceResult<team::Team> {
+        require_admin(user)?;
+        let team = self
+            .with_audited_conn(user, move |conn| {
+                let team = team::add_team(conn, team)?;
+                let change =
+                    audit::Change::new("add_team", "team", Some(team.id.into())).after(&team);
+                Ok((team, change))
+            })
+            .await?;
+        self.publish(Event::TeamChanged(team.clone()));
+        Ok(team)
+    }
+
+    pub async fn update_team(
+        &self,
+        user: &User,
+        team_id: i32,
+        new_team: team::Team,
+    ) -> ServiceResult<team::Team> {
+        require_admin(user)?;
+        let team = self
+            .with_audited_conn(user, move |conn| {
+                let mut team = find_team(conn, team_id)?;
+                let change =
+                    audit::Change::new("update_team", "team", Some(team_id.into())).before(&team);
+                team.name = new_team.name;
+                team.state = new_team.state;
+                team.save(conn)?;
+                let change = change.after(&team);
+                Ok((team, change))
+            })
+            .await?;
+        self.publish(Event::TeamChanged(team.clone()));
+        Ok(team)
+    }
+
+    pub async fn set_team_meta(
+        &self,
+        user: &User,
+        team_id: i32,
+        key: String,
+        value: String,
+    ) -> ServiceResult<team::TeamMeta> {
+        require_admin(user)?;
+        self.with_audited_conn(user, move |conn| {
+            let team = find_team(conn, team_id)?;
+            let mut change = audit::Change::new("set_team_meta", "team", Some(team_id.into()));
+            if let Some(meta) = team
+                .get_meta_data(conn)?
+                .iter()
+                .find(|meta| meta.key == key)
+            {
+                change = change.before(meta);
+            }
+            let meta = team.set_meta_value(conn, key, value)?;
+            let change = change.after(&meta);
+            Ok((meta, change))
+        })
+        .await
+    }
+
+    pub async fn remove_team_meta(
+        &self,
+        user: &User,
+        team_id: i32,
+        key: String,
+    ) -> ServiceResult<()> {
+        require_admin(user)?;
+        self.with_audited_conn(user, move |conn| {
+            let team = find_team(conn, team_id)?;
+            let meta_data = team.get_meta_data(conn)?;
+            if let Some(meta) = meta_data.iter().find(|meta| meta.key == key) {
+                team.remove_meta_value(conn, &key)?;
+                let change = audit::Change::new("remove_team_meta", "team", Some(team_id.into()))
+                    .before(meta);
+                Ok(((), change))
+            } else {
+                Err(ServiceError::NotFound(format!(
+                    "No meta value {key} for team {team_id}"
+                )))
+            }
+        })
+        .await
+    }
+
+    pub async fn get_challenges(&self) -> ServiceResult<Vec<challenge::Challenge>> {
+        self.with_conn(|conn| Ok(challenge::get_challenges(conn)?))
+            .await
+    }
+
+    pub async fn get_challenge(&self, challenge_id: i32) -> ServiceResult<challenge::Challenge> {
+        self.with_conn(move |conn| find_challenge(conn, challenge_id))
+            .await
+    }
+
+    pub async fn add_challenge(
+        &self,
+        user: &User,
+        new_challenge: challenge::NewChallenge,
+    ) -> ServiceResult<challenge::Challenge> {
+        require_admin(user)?;
+        validate_challenge(&new_challenge)?;
+        let challenge = self
+            .with_audited_conn(user, move |conn| {
+                if challenge::find_challenge_by_name(conn, &new_challenge.name)?.is_some() {
+                    return Err(ServiceError::InvalidArguments(format!(
+                        "There already is a challenge named {}",
+                        new_challenge.name
+                    )));
+                }
+                let challenge = challenge::add_challenge(conn, new_challenge)?;
+                let change =
+                    audit::Change::new("add_challenge", "challenge", Some(challenge.id.into()))
+                        .after(&challenge);
+                Ok((challenge, change))
+            })
+            .await?;
+        self.publish(Event::ChallengeChanged(challenge.clone()));
+        Ok(challenge)
+    }
+
+    /// Renaming a challenge updates the exploits and flags referencing it.
+    pub async fn update_challenge(
+        &self,
+        user: &User,
+        challenge_id: i32,
+        new_challenge: challenge::NewChallenge,
+    ) -> ServiceResult<challenge::Challenge> {
+        require_admin(user)?;
+        validate_challenge(&new_challenge)?;
+        let challenge = self
+            .with_audited_conn(user, move |conn| {
+                let mut challenge = find_challenge(conn, challenge_id)?;
+                if new_challenge.name != challenge.name
+                    && challenge::find_challenge_by_name(conn, &new_challenge.name)?.is_some()
+                {
+                    return Err(ServiceError::InvalidArguments(format!(
+                        "There already is a challenge named {}",
+                        new_challenge.name
+                    )));
+                }
+                let change =
+                    audit::Change::new("update_challenge", "challenge", Some(challenge_id.into()))
+                        .before(&challenge);
+                challenge.name = new_challenge.name;
+                challenge.ports = new_challenge.ports;
+                challenge.flag_id_key = new_challenge.flag_id_key;
+                challenge.disabled = new_challenge.disabled;
+                challenge.save(conn)?;
+                let change = change.after(&challenge);
+                Ok((challenge, change))
+            })
+            .await?;
+        self.publish(Event::ChallengeChanged(challenge.clone()));
+        Ok(challenge)
+    }
+
+    /// Only challenges no exploit attacks can be deleted.
+    pub async fn delete_challenge(&self, user: &User, challenge_id: i32) -> ServiceResult<()> {
+        require_admin(user)?;
+        self.with_audited_conn(user, move |conn| {
+            let challenge = find_challenge(conn, challenge_id)?;
+            let exploit_ids = challenge.get_exploit_ids(conn)?;
+            if !exploit_ids.is_empty() {
+                return Err(ServiceError::InvalidArguments(format!(
+                    "Challenge {} is still attacked by the exploits {:?}",
+                    challenge.name, exploit_ids
+                )));
+            }
+            let change =
+                audit::Change::new("delete_challenge", "challenge", Some(challenge_id.into()))
+                    .before(&challenge);
+            challenge::delete_challenge(conn, challenge)?;
+            Ok(((), change))
+        })
+        .await
+    }
+
+    pub async fn get_skipped_targets(&self) -> ServiceResult<Vec<team::SkippedTarget>> {
+        self.with_conn(|conn| Ok(team::get_skipped_targets(conn)?))
+            .await
+    }
+
+    /// Attack a skipped team normally again without waiting for a successful run.
+    pub async fn restore_target(
+        &self,
+        user: &User,
+        team_id: i32,
+        target_challenge: String,
+    ) -> ServiceResult<team::SkippedTarget> {
+        require_admin(user)?;
+        let target = self
+            .with_audited_conn(user, move |conn| {
+                let target =
+                    team::restore_target(conn, team_id, &target_challenge)?.ok_or_else(|| {
+                        ServiceError::NotFound(format!(
+                            "Team {team_id} isn't skipped for {target_challenge}"
+                        ))
+                    })?;
+                let change = audit::Change::new("restore_target", "team", Some(team_id.into()))
+                    .before(&target);
+                Ok((target, change))
+            })
+            .await?;
+        self.publish(Event::TargetRestored(target.clone()));
+        Ok(target)
+    }
+
+    pub async fn get_policies(&self, args: PolicyArguments) -> ServiceResult<Vec<PolicyResult>> {
+        self.with_conn(move |conn| {
+            let mut policy_list = Vec::new();
+            for policy in exploit::get_policies(conn)? {
+                let usage = if args.include_usage.unwrap_or(false) {
+                    Some(policy.get_usage(conn)?)
+                } else {
+                    None
+                };
+                policy_list.push(PolicyResult { policy, usage });
+            }
+            Ok(policy_list)
+        })
+        .await
+    }
+
+    pub async fn get_policy(
+        &self,
+        policy_id: i32,
+        args: PolicyArguments,
+    ) -> ServiceResult<PolicyResult> {
+        self.with_conn(move |conn| {
+            let policy = find_policy(conn, policy_id)?;
+            let usage = if args.include_usage.unwrap_or(false) {
+                Some(policy.get_usage(conn)?)
+            } else {
+                None
+            };
+            Ok(PolicyResult { policy, usage })
+        })
+        .await
+    }
+
+    pub async fn add_policy(
+        &self,
+        user: &User,
+        policy: exploit::NewPolicy,
+    ) -> ServiceResult<exploit::Policy> {
+        require_admin(user)?;
+        self.with_audited_conn(user, move |conn| {
+            require_unique_policy_name(conn, &policy.name)?;
+            let policy = exploit::add_policy(conn, policy)?;
+            let change =
+                audit::Change::new("add_policy", "policy", Some(policy.id.into())).after(&policy);
+            Ok((policy, change))
+        })
+        .await
+    }
+
+    /// Exploits only reference the policy, so changes apply to all of them on their next run.
+    pub async fn update_policy(
+        &self,
+        user: &User,
+        policy_id: i32,
+        new_policy: exploit::NewPolicy,
+    ) -> ServiceResult<exploit::Policy> {
+        require_admin(user)?;
+        self.with_audited_conn(user, move |conn| {
+            let mut policy = find_policy(conn, policy_id)?;
+            if new_policy.name != policy.name {
+                require_unique_policy_name(conn, &new_policy.name)?;
+            }
+            let change = audit::Change::new("update_policy", "policy", Some(policy_id.into()))
+                .before(&policy);
+            policy.name = new_policy.name;
+            policy.argv_pattern = new_policy.argv_pattern;
+            policy.repeat_interval = new_policy.repeat_interval;
+            policy.disabled = new_policy.disabled;
+            policy.save(conn)?;
+            let change = change.after(&policy);
+            Ok((policy, change))
+        })
+        .await
+    }
+
+    pub async fn delete_policy(&self, user: &User, policy_id: i32) -> ServiceResult<()> {
+        require_admin(user)?;
+        self.with_audited_conn(user, move |conn| {
+            let policy = find_policy(conn, policy_id)?;
+            let change = audit::Change::new("delete_policy", "policy", Some(policy_id.into()))
+                .before(&policy);
+            let usage = exploit::delete_policy(conn, policy)?;
+            if usage.is_unused() {
+                Ok(((), change))
+            } else {
+                Err(ServiceError::PolicyInUse { policy_id, usage })
+            }
+        })
+        .await
+    }
+
+    pub async fn get_exploits(&self, args: ExploitArguments) -> ServiceResult<Vec<ExploitResult>> {
+        self.with_conn(move |conn| {
+            Ok(exploit::get_exploits(conn)?
+                .into_iter()
+                .map(|exploit| args.load_result(conn, exploit))
+                .collect::<Result<_, _>>()?)
+        })
+        .await
+    }
+
+    pub async fn get_exploit(
+        &self,
+        exploit_id: i32,
+        args: ExploitArguments,
+    ) -> ServiceResult<ExploitResult> {
+        self.with_conn(move |conn| {
+            let exploit = find_exploit(conn, exploit_id)?;
+            Ok(args.load_result(conn, exploit)?)
+        })
+        .await
+    }
+
+    pub async fn add_exploit(
+        &self,
+        user: &User,
+        exploit: exploit::NewExploit,
+    ) -> ServiceResult<exploit::Exploit> {
+        require_author(user, &exploit.author)?;
+        self.with_audited_conn(user, move |conn| {
+            require_challenge(conn, &exploit.target_challenge)?;
+            validate_exploit_source(conn, None, &exploit)?;
+            validate_exploit_limits(&exploit)?;
+            let exploit = exploit::add_exploit(conn, exploit)?;
+            let change = audit::Change::new("add_exploit", "exploit", Some(exploit.id.into()))
+                .after(&exploit);
+            Ok((exploit, change))
+        })
+        .await
+    }
+
+    pub async fn update_exploit(
+        &self,
+        user: &User,
+        exploit_id: i32,
+        new_exploit: exploit::NewExploit,
+    ) -> ServiceResult<exploit::Exploit> {
+        // Authors can't hand their exploits to somebody else.
+        require_author(user, &new_exploit.author)?;
+        let editor = user.clone();
+        self.with_audited_conn(user, move |conn| {
+            let mut exploit = find_editable_exploit(conn, &editor, exploit_id)?;
+            require_challenge(conn, &new_exploit.target_challenge)?;
+            validate_exploit_source(conn, Some(exploit_id), &new_exploit)?;
+            validate_exploit_limits(&new_exploit)?;
+            let change = audit::Change::new("update_exploit", "exploit", Some(exploit_id.into()))
+                .before(&exploit);
+            exploit.command = new_exploit.command;
+            exploit.author = new_exploit.author;
+            exploit.vuln_title = new_exploit.vuln_title;
+            exploit.target_challenge = new_exploit.target_challenge;
+            exploit.policy_id = new_exploit.policy_id;
+            exploit.script_timeout = new_exploit.script_timeout;
+            exploit.overrun_policy = new_exploit.overrun_policy;
+            exploit.working_directory = new_exploit.working_directory;
+            exploit.disabled = new_exploit.disabled;
+            exploit.source = new_exploit.source;
+            exploit.pinned_version_id = new_exploit.pinned_version_id;
+            exploit.git_repository = new_exploit.git_repository;
+            exploit.git_ref = new_exploit.git_ref;
+            exploit.cpu_time_limit = new_exploit.cpu_time_limit;
+            exploit.memory_limit = new_exploit.memory_limit;
+            exploit.process_limit = new_exploit.process_limit;
+            exploit.open_files_limit = new_exploit.open_files_limit;
+            exploit.cgroup_memory_max = new_exploit.cgroup_memory_max;
+            exploit.cgroup_cpu_max = new_exploit.cgroup_cpu_max;
+            exploit.save(conn)?;
+            let change = change.after(&exploit);
+            Ok((exploit, change))
+        })
+        .await
+    }
+
+    pub async fn set_exploit_target(
+        &self,
+        user: &User,
+        exploit_id: i32,
+        team_id: i32,
+        policy_id: i32,
+    ) -> ServiceResult<exploit::ExploitTarget> {
+        let editor = user.clone();
+        self.with_audited_conn(user, move |conn| {
+            let exploit = find_editable_exploit(conn, &editor, exploit_id)?;
+            find_team(conn, team_id)?;
+            find_policy(conn, policy_id)?;
+            let mut change =
+                audit::Change::new("set_exploit_target", "exploit", Some(exploit_id.into()));
+            if let Some(target) = exploit
+                .get_targets(conn)?
+                .iter()
+                .find(|target| target.team_id == team_id)
+            {
+                change = change.before(target);
+            }
+            let target = exploit.set_target_policy(conn, team_id, policy_id)?;
+            let change = change.after(&target);
+            Ok((target, change))
+        })
+        .await
+    }
+
+    pub async fn remove_exploit_target(
+        &self,
+        user: &User,
+        exploit_id: i32,
+        team_id: i32,
+    ) -> ServiceResult<()> {
+        let editor = user.clone();
+        self.with_audited_conn(user, move |conn| {
+            let exploit = find_editable_exploit(conn, &editor, exploit_id)?;
+            let targets = exploit.get_targets(conn)?;
+            if let Some(target) = targets.iter().find(|target| target.team_id == team_id) {
+                exploit.remove_target_policy(conn, team_id)?;
+                let change =
+                    audit::Change::new("remove_exploit_target", "exploit", Some(exploit_id.into()))
+                        .before(target);
+                Ok(((), change))
+            } else {
+                Err(ServiceError::NotFound(format!(
+                    "No target policy for team {team_id} in exploit {exploit_id}"
+                )))
+            }
+        })
+        .await
+    }
+
+    pub async fn set_exploit_meta(
+        &self,
+        user: &User,
+        exploit_id: i32,
+        key: String,
+        value: String,
+    ) -> ServiceResult<exploit::ExploitMeta> {
+        let editor = user.clone();
+        self.with_audited_conn(user, move |conn| {
+            let exploit = find_editable_exploit(conn, &editor, exploit_id)?;
+            let mut change =
+                audit::Change::new("set_exploit_meta", "exploit", Some(exploit_id.into()));
+            if let Some(meta) = exploit
+                .get_meta_data(conn)?
+                .iter()
+                .find(|meta| meta.key == key)
+            {
+                change = change.before(meta);
+            }
+            let meta = exploit.set_meta_value(conn, key, value)?;
+            let change = change.after(&meta);
+            Ok((meta, change))
+        })
+        .await
+    }
+
+    pub async fn remove_exploit_meta(
+        &self,
+        user: &User,
+        exploit_id: i32,
+        key: String,
+    ) -> ServiceResult<()> {
+        let editor = user.clone();
+        self.with_audited_conn(user, move |conn| {
+            let exploit = find_editable_exploit(conn, &editor, exploit_id)?;
+            let meta_data = exploit.get_meta_data(conn)?;
+            if let Some(meta) = meta_data.iter().find(|meta| meta.key == key) {
+                exploit.remove_meta_value(conn, &key)?;
+                let change =
+                    audit::Change::new("remove_exploit_meta", "exploit", Some(exploit_id.into()))
+                        .before(meta);
+                Ok(((), change))
+            } else {
+                Err(ServiceError::NotFound(format!(
+                    "No meta value {key} for exploit {exploit_id}"
+                )))
+            }
+        })
+        .await
+    }
+
+    /// Variables passed to the runs of the exploit or to all exploits if `exploit_id` is `None`.
+    /// Secret values are masked.
+    pub async fn get_environment(
+        &self,
+        exploit_id: Option<i32>,
+    ) -> ServiceResult<Vec<EnvironmentVariable>> {
+        self.with_conn(move |conn| {
+            if let Some(exploit_id) = exploit_id {
+                find_exploit(conn, exploit_id)?;
+            }
+            Ok(environment::get_variables(conn, exploit_id)?
+                .iter()
+                .map(EnvironmentVariable::masked)
+                .collect())
+        })
+        .await
+    }
+
+    /// Add or replace a variable of the exploit or a global one if `exploit_id` is `None`.
+    /// Only admins may change global variables.
+    pub async fn set_environment_variable(
+        &self,
+        user: &User,
+        exploit_id: Option<i32>,
+        name: String,
+        variable: NewEnvironmentVariable,
+    ) -> ServiceResult<EnvironmentVariable> {
+        environment::validate_name(&name).map_err(ServiceError::InvalidArguments)?;
+        if exploit_id.is_none() {
+            require_admin(user)?;
+        }
+        let editor = user.clone();
+        self.with_audited_conn(user, move |conn| {
+            if let Some(exploit_id) = exploit_id {
+                find_editable_exploit(conn, &editor, exploit_id)?;
+            }
+            let mut change = environment_change("set_environment_variable", exploit_id);
+            if let Some(existing) = environment::get_variables(conn, exploit_id)?
+                .iter()
+                .find(|existing| existing.name == name)
+            {
+                change = change.before(&existing.masked());
+            }
+            let variable = environment::set_variable(conn, exploit_id, name, variable)?.masked();
+            let change = change.after(&variable);
+            Ok((variable, change))
+        })
+        .await
+    }
+
+    pub async fn remove_environment_variable(
+        &self,
+        user: &User,
+        exploit_id: Option<i32>,
+        name: String,
+    ) -> ServiceResult<()> {
+        if exploit_id.is_none() {
+            require_admin(user)?;
+        }
+        let editor = user.clone();
+        self.with_audited_conn(user, move |conn| {
+            if let Some(exploit_id) = exploit_id {
+                find_editable_exploit(conn, &editor, exploit_id)?;
+            }
+            let variable = environment::get_variables(conn, exploit_id)?
+                .into_iter()
+                .find(|variable| variable.name == name)
+                .ok_or_else(|| ServiceError::NotFound(format!("No environment variable {name}")))?;
+            environment::delete_variable(conn, &variable)?;
+            let change = environment_change("remove_environment_variable", exploit_id)
+                .before(&variable.masked());
+            Ok(((), change))
+        })
+        .await
+    }
+
+    /// Store a new version of the exploit. Exploits following the latest
+    /// version run it from their next run on.
+    pub async fn upload_exploit_version(
+        &self,
+        user: &User,
+        exploit_id: i32,
+        filename: String,
+        content: Vec<u8>,
+    ) -> ServiceResult<exploit::ExploitVersion> {
+        if filename.is_empty()
+            || filename.starts_with('.')
+            || filename.contains(['/', '\\'])
+            || filename.contains(char::is_control)
+        {
+            return Err(ServiceError::InvalidArguments(format!(
+                "Invalid file name {filename:?}"
+            )));
+        }
+        if content.len() > MAX_EXPLOIT_VERSION_SIZE {
+            return Err(ServiceError::InvalidArguments(format!(
+                "Exploit versions are limited to {MAX_EXPLOIT_VERSION_SIZE} bytes"
+            )));
+        }
+        exploit_runner::check_upload(&filename, &content)
+            .map_err(ServiceError::InvalidArguments)?;
+
+        let editor = user.clone();
+        self.with_audited_conn(user, move |conn| {
+            find_editable_exploit(conn, &editor, exploit_id)?;
+            let sha256 = Sha256::digest(&content)
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect();
+            let version = exploit::add_version(
+                conn,
+                exploit::NewExploitVersion {
+                    exploit_id,
+                    filename,
+                    sha256,
+                    size: content.len() as i32,
+                    author: editor.username.clone(),
+                    content,
+                },
+            )?;
+            let change =
+                audit::Change::new("upload_exploit_version", "exploit", Some(exploit_id.into()))
+                    .after(&version);
+            Ok((version, change))
+        })
+        .await
+    }
+
+    pub async fn get_exploit_versions(
+        &self,
+        exploit_id: i32,
+    ) -> ServiceResult<Vec<exploit::ExploitVersion>> {
+        self.with_conn(move |conn| {
+            find_exploit(conn, exploit_id)?;
+            Ok(exploit::get_versions(conn, exploit_id)?)
+        })
+        .await
+    }
+
+    /// The version together with the uploaded file.
+    pub async fn get_exploit_version_content(
+        &self,
+        version_id: i32,
+    ) -> ServiceResult<(exploit::ExploitVersion, Vec<u8>)> {
+        self.with_conn(move |conn| {
+            let version = exploit::find_version_by_id(conn, version_id)?.ok_or_else(|| {
+                ServiceError::NotFound(format!("No exploit version found with id: {version_id}"))
+            })?;
+            let content = exploit::load_version_content(conn, version_id)?;
+            Ok((version, content))
+        })
+        .await
+    }
+
+    /// Run the exploit once against the team, even if it's disabled, and wait for it to finish.
+    /// The output is published to `live_output` while it's running.
+    pub async fn test_run_exploit(
+        &self,
+        user: &User,
+        exploit_id: i32,
+        args: TestRunArguments,
+        live_output: exploit_runner::OutputSender,
+    ) -> ServiceResult<TestRunResponse> {
+        let editor = user.clone();
+        let storage_path = self.storage_path.clone();
+        let test_run = self
+            .with_audited_conn(user, move |conn| {
+                let exploit = find_editable_exploit(conn, &editor, exploit_id)?;
+                let team = find_team(conn, args.team_id)?;
+                let test_run =
+                    exploit_runner::prepare_test_run(conn, &storage_path, &exploit, &team)?;
+                let change =
+                    audit::Change::new("test_run_exploit", "exploit", Some(exploit_id.into()))
+                        .after(&serde_json::json!({
+                            "team_id": team.id,
+                            "command": test_run.command(),
+                        }));
+                Ok((test_run, change))
+            })
+            .await?;
+        let target_challenge = test_run.target_challenge().to_string();
+        let run = self
+            .exploit_runner
+            .send(exploit_runner::StartTestRun {
+                test_run,
+                live_output,
+            })
+            .await
+            .map_err(|err| ServiceError::Internal(err.into()))?
+            .map_err(|err| ServiceError::Internal(err.into()))?;
+
+        let submitted = if args.submit && !run.flags.is_empty() {
+            let flags = ManualFlagArguments {
+                text: run.flags.join("\n"),
+                team_id: Some(run.team_id),
+                target_challenge: Some(target_challenge),
+            };
+            Some(self.add_flags(user, flags).await?)
+        } else {
+            None
+        };
+        Ok(TestRunResponse { run, submitted })
+    }
+
+    pub async fn get_runs(&self, filter: exploit::RunFilter) -> ServiceResult<RunListResult> {
+        let limit = filter
+            .limit
+            .unwrap_or(50)
+            .clamp(1, exploit::MAX_RUNS_PER_PAGE) as usize;
+        let runs = self
+            .with_conn(move |conn| Ok(exploit::get_runs(conn, &filter)?))
+            .await?;
+
+        let next_cursor = if runs.len() == limit {
+            runs.last().map(|run| run.id)
+        } else {
+            None
+        };
+        Ok(RunListResult { runs, next_cursor })
+    }
+
+    pub async fn get_run(&self, run_id: i64) -> ServiceResult<RunResult> {
+        self.with_conn(move |conn| {
+            let run = find_run(conn, run_id)?;
+            let flags = flag_submitter::get_flags_of_run(conn, run.id)?;
+            Ok(RunResult { run, flags })
+        })
+        .await
+    }
+
+    /// Combined stdout and stderr of the run with a timestamp and stream marker per line.
+    pub async fn get_run_output(&self, run_id: i64) -> ServiceResult<String> {
+        self.with_conn(move |conn| match exploit::find_run_output(conn, run_id)? {
+            Some(output) => Ok(String::from_utf8_lossy(&output.decompress()?).into_owned()),
+            None => Err(ServiceError::NotFound(format!(
+                "No output stored for exploit run with id: {run_id}"
+            ))),
+        })
+        .await
+    }
+
+    pub async fn get_settings(&self) -> ServiceResult<settings::Settings> {
+        self.with_conn(|conn| Ok(settings::get_settings(conn)?))
+            .await
+    }
+
+    pub async fn update_settings(
+        &self,
+        user: &User,
+        new_settings: settings::Settings,
+    ) -> ServiceResult<settings::Settings> {
+        require_admin(user)?;
+        if let Err(err) = regex::Regex::new(&new_settings.flag_regex) {
+            return Err(ServiceError::InvalidArguments(format!(
+                "Invalid flag regex: {err}"
+            )));
+        }
+        require_non_negative(&[
+            ("run_cpu_time_limit", new_settings.run_cpu_time_limit),
+            ("run_memory_limit", new_settings.run_memory_limit),
+            ("run_process_limit", new_settings.run_process_limit),
+            ("run_open_files_limit", new_settings.run_open_files_limit),
+            ("run_cgroup_memory_max", new_settings.run_cgroup_memory_max),
+            ("run_cgroup_cpu_max", new_settings.run_cgroup_cpu_max),
+        ])?;
+        let cgroup_path = new_settings.run_cgroup_path.trim();
+        if !cgroup_path.is_empty() && !std::path::Path::new(cgroup_path).is_absolute() {
+            return Err(ServiceError::InvalidArguments(
+                "run_cgroup_path must be an absolute path".to_string(),
+            ));
+        }
+
+        let settings = self
+            .with_audited_conn(user, move |conn| {
+                let change = audit::Change::new("update_settings", "settings", None)
+                    .before(&settings::get_settings(conn)?);
+                new_settings.save(conn)?;
+                let settings = settings::get_settings(conn)?;
+                let change = change.after(&settings);
+                Ok((settings, change))
+            })
+            .await?;
+        self.publish(Event::SettingsChanged(settings.clone()));
+        Ok(settings)
+    }
+
+    pub async fn get_flag_stats(
+        &self,
+        filter: stats::StatsFilter,
+    ) -> ServiceResult<Vec<stats::FlagStats>> {
+        self.with_conn(move |conn| Ok(stats::get_flag_stats(conn, &filter)?))
+            .await
+    }
+
+    pub async fn get_run_stats(
+        &self,
+        filter: stats::StatsFilter,
+    ) -> ServiceResult<Vec<stats::RunStats>> {
+        self.with_conn(move |conn| Ok(stats::get_run_stats(conn, &filter)?))
+            .await
+    }
+
+    pub async fn get_alerts(&self, filter: alerts::AlertFilter) -> ServiceResult<AlertListResult> {
+        let limit = filter
+            .limit
+            .unwrap_or(50)
+            .clamp(1, alerts::MAX_ALERTS_PER_PAGE) as usize;
+        let alerts = self
+            .with_conn(move |conn| Ok(alerts::get_alerts(conn, &filter)?))
+            .await?;
+
+        let next_cursor = if alerts.len() == limit {
+            alerts.last().map(|alert| alert.id)
+        } else {
+            None
+        };
+        Ok(AlertListResult {
+            alerts,
+            next_cursor,
+        })
+    }
+
+    /// Mark the alert as taken care of.
+    pub async fn acknowledge_alert(
+        &self,
+        user: &User,
+        alert_id: i64,
+    ) -> ServiceResult<alerts::Alert> {
+        if user.role == Role::Viewer {
+            return Err(ServiceError::Forbidden(
+                "Viewers can't acknowledge alerts".to_string(),
+            ));
+        }
+        let user_id = user.id;
+        let alert = self
+            .with_audited_conn(user, move |conn| {
+                let mut alert = alerts::find_alert_by_id(conn, alert_id)?.ok_or_else(|| {
+                    ServiceError::NotFound(format!("No alert found with id: {alert_id}"))
+                })?;
+                if alert.acknowledged_at.is_some() {
+                    return Err(ServiceError::InvalidArguments(format!(
+                        "Alert {alert_id} was acknowledged already"
+                    )));
+                }
+                let change =
+                    audit::Change::new("acknowledge_alert", "alert", Some(alert_id)).before(&alert);
+                alert.acknowledge(conn, user_id)?;
+                let change = change.after(&alert);
+                Ok((alert, change))
+            })
+            .await?;
+        self.publish(Event::AlertAcknowledged(alert.clone()));
+        Ok(alert)
+    }
+
+    pub async fn get_audit_log(
+        &self,
+        filter: audit::AuditFilter,
+    ) -> ServiceResult<AuditListResult> {
+        let limit = filter
+            .limit
+            .unwrap_or(50)
+            .clamp(1, audit::MAX_ENTRIES_PER_PAGE) as usize;
+        let entries = self
+            .with_conn(move |conn| Ok(audit::get_entries(conn, &filter)?))
+            .await?;
+
+        let next_cursor = if entries.len() == limit {
+            entries.last().map(|entry| entry.id)
+        } else {
+            None
+        };
+        Ok(AuditListResult {
+            entries,
+            next_cursor,
+        })
+    }
+
+    pub async fn get_flags(
+        &self,
+        filter: flag_submitter::FlagFilter,
+    ) -> ServiceResult<FlagListResult> {
+        let limit = filter
+            .limit
+            .unwrap_or(50)
+            .clamp(1, flag_submitter::MAX_FLAGS_PER_PAGE) as usize;
+        let (flags, counts) = self
+            .with_conn(move |conn| {
+                let flags = flag_submitter::get_flags(conn, &filter)?;
+                let counts = flag_submitter::count_flags_by_result(conn, &filter)?;
+                Ok((flags, counts))
+            })
+            .await?;
+
+        let next_cursor = if flags.len() == limit {
+            flags.last().map(|entry| entry.flag.id)
+        } else {
+            None
+        };
+        Ok(FlagListResult {
+            flags,
+            next_cursor,
+            counts,
+        })
+    }
+
+    /// Submit the flags matching the filter again, e.g. after an outage of the submission server.
+    pub async fn requeue_flags(
+        &self,
+        user: &User,
+        filter: flag_submitter::FlagFilter,
+    ) -> ServiceResult<RequeueResult> {
+        require_admin(user)?;
+        if filter.submission_result.is_none() {
+            return Err(ServiceError::InvalidArguments(
+                "A submission_result is required to select the flags to requeue".to_string(),
+            ));
+        }
+        self.with_audited_conn(user, move |conn| {
+            let requeued = flag_submitter::requeue_flags(conn, &filter)?;
+            let result = RequeueResult { requeued };
+            let change = audit::Change::new("requeue_flags", "flag", None)
+                .before(&filter)
+                .after(&result);
+            Ok((result, change))
+        })
+        .await
+    }
+
+    /// Classify the stored unknown responses with the current response rules.
+    pub async fn remap_flag_responses(&self, user: &User) -> ServiceResult<RemapResult> {
+        require_admin(user)?;
+        let result = self
+            .with_audited_conn(user, move |conn| {
+                let mapper =
+                    flag_submitter::ResponseMapper::new(&flag_submitter::get_response_rules(conn)?);
+                let remapped = flag_submitter::remap_unknown_responses(conn, &mapper)?;
+                let unknown = flag_submitter::count_unknown_responses(conn)?;
+                let result = RemapResult { remapped, unknown };
+                let change =
+                    audit::Change::new("remap_flag_responses", "flag", None).after(&result);
+                Ok((result, change))
+            })
+            .await?;
+        if !result.remapped.is_empty() {
+            self.publish(Event::FlagsSubmitted(result.remapped.clone()));
+        }
+        Ok(result)
+    }
+
+    /// Extract flags from the text using the flag regex and queue the new ones for submission.
+    pub async fn add_flags(
+        &self,
+        user: &User,
+        args: ManualFlagArguments,
+    ) -> ServiceResult<ManualFlagResult> {
+        self.add_flag_batches(user, vec![args]).await
+    }
+
+    /// Like `add_flags` for texts from several teams at once. All of them are stored together.
+    pub async fn add_flag_batches(
+        &self,
+        user: &User,
+        batches: Vec<ManualFlagArguments>,
+    ) -> ServiceResult<ManualFlagResult> {
+        if user.role == Role::Viewer {
+            return Err(ServiceError::Forbidden(
+                "Viewers can't submit flags".to_string(),
+            ));
+        }
+        let user_id = user.id;
+        let result = self
+            .with_audited_conn(user, move |conn| {
+                let settings = settings::get_settings(conn)?;
+                let flag_regex = regex::Regex::new(&settings.flag_regex)
+                    .map_err(|err| ServiceError::Internal(err.into()))?;
+                let mut result = ManualFlagResult {
+                    flags: Vec::new(),
+                    duplicates: Vec::new(),
+                };
+                for batch in batches {
+                    let mut found_flags = Vec::new();
+                    flag_submitter::extract_flags(&flag_regex, &batch.text, &mut found_flags);
+                    if found_flags.is_empty() {
+                        continue;
+                    }
+                    if let Some(team_id) = batch.team_id {
+                        find_team(conn, team_id)?;
+                    }
+                    if let Some(target_challenge) = &batch.target_challenge {
+                        require_challenge(conn, target_challenge)?;
+                    }
+
+                    let submission_result = match batch.team_id {
+                        Some(team_id) if Some(team_id) == settings.own_team_id => {
+                            flag_submitter::FlagSubmissionResult::Own
+                        }
+                        Some(team_id)
+                            if Some(team_id) == settings.nop_team_id
+                                && !settings.nop_team_grants_points =>
+                        {
+                            flag_submitter::FlagSubmissionResult::NOPTeam
+                        }
+                        _ => flag_submitter::FlagSubmissionResult::Pending,
+                    };
+                    let flags = flag_submitter::add_manual_flags(
+                        conn,
+                        &flag_submitter::ManualFlags {
+                            user_id,
+                            team_id: batch.team_id,
+                            target_challenge: batch.target_challenge.as_deref(),
+                            tick: settings.current_tick(),
+                            submission_result,
+                        },
+                        &found_flags,
+                    )?;
+                    result.duplicates.extend(
+                        found_flags.into_iter().filter(|found_flag| {
+                            !flags.iter().any(|flag| &flag.flag == found_flag)
+                        }),
+                    );
+                    result.flags.extend(flags);
+                }
+                if result.flags.is_empty() && result.duplicates.is_empty() {
+                    return Err(ServiceError::InvalidArguments(format!(
+                        "No flags matching {} found",
+                        settings.flag_regex
+                    )));
+                }
+
+                let change = audit::Change::new("add_flags", "flag", None).after(&result);
+                Ok((result, change))
+            })
+            .await?;
+        if !result.flags.is_empty() {
+            self.publish(Event::FlagsAdded {
+                user_id,
+                flags: result.flags.clone(),
+            });
+        }
+        Ok(result)
+    }
+
+    pub async fn get_flag_response_rules(
+        &self,
+    ) -> ServiceResult<Vec<flag_submitter::FlagResponseRule>> {
+        self.with_conn(|conn| Ok(flag_submitter::get_response_rules(conn)?))
+            .await
+    }
+
+    /// Replace all rules. They're tried in the order of the list.
+    pub async fn set_flag_response_rules(
+        &self,
+        user: &User,
+        rules: Vec<flag_submitter::NewFlagResponseRule>,
+    ) -> ServiceResult<Vec<flag_submitter::FlagResponseRule>> {
+        require_admin(user)?;
+        for rule in &rules {
+            if let Err(err) = regex::Regex::new(&rule.pattern) {
+                return Err(ServiceError::InvalidArguments(format!(
+                    "Invalid response pattern {}: {err}",
+                    rule.pattern
+                )));
+            }
+        }
+
+        self.with_audited_conn(user, move |conn| {
+            let change = audit::Change::new("set_flag_response_rules", "flag_response_rules", None)
+                .before(&flag_submitter::get_response_rules(conn)?);
+            let rules = flag_submitter::set_response_rules(conn, rules)?;
+            let change = change.after(&rules);
+            Ok((rules, change))
+        })
+        .await
+    }
+
+    /// Webhook URLs usually contain a secret, so only admins get to see them.
+    pub async fn get_webhooks(&self, user: &User) -> ServiceResult<Vec<webhook::Webhook>> {
+        require_admin(user)?;
+        self.with_conn(|conn| Ok(webhook::get_webhooks(conn)?))
+            .await
+    }
+
+    pub async fn add_webhook(
+        &self,
+        user: &User,
+        new_webhook: webhook::NewWebhook,
+    ) -> ServiceResult<webhook::Webhook> {
+        require_admin(user)?;
+        new_webhook
+            .validate()
+            .map_err(ServiceError::InvalidArguments)?;
+        self.with_audited_conn(user, move |conn| {
+            let webhook = webhook::add_webhook(conn, new_webhook)?;
+            let change = audit::Change::new("add_webhook", "webhook", Some(webhook.id.into()))
+                .after(&webhook);
+            Ok((webhook, change))
+        })
+        .await
+    }
+
+    pub async fn update_webhook(
+        &self,
+        user: &User,
+        webhook_id: i32,
+        new_webhook: webhook::NewWebhook,
+    ) -> ServiceResult<webhook::Webhook> {
+        require_admin(user)?;
+        new_webhook
+            .validate()
+            .map_err(ServiceError::InvalidArguments)?;
+        self.with_audited_conn(user, move |conn| {
+            let mut webhook = find_webhook(conn, webhook_id)?;
+            let change = audit::Change::new("update_webhook", "webhook", Some(webhook_id.into()))
+                .before(&webhook);
+            webhook.name = new_webhook.name;
+            webhook.url = new_webhook.url;
+            webhook.body_template = new_webhook.body_template;
+            webhook.events = new_webhook.events;
+            webhook.save(conn)?;
+            let change = change.after(&webhook);
+            Ok((webhook, change))
+        })
+        .await
+    }
+
+    pub async fn delete_webhook(&self, user: &User, webhook_id: i32) -> ServiceResult<()> {
+        require_admin(user)?;
+        self.with_audited_conn(user, move |conn| {
+            let webhook = find_webhook(conn, webhook_id)?;
+            let change = audit::Change::new("delete_webhook", "webhook", Some(webhook_id.into()))
+                .before(&webhook);
+            webhook.delete(conn)?;
+            Ok(((), change))
+        })
+        .await
+    }
+
+    /// Post a test message to the webhook right away, regardless of its enabled events.
+    pub async fn test_webhook(
+        &self,
+        user: &User,
+        webhook_id: i32,
+    ) -> ServiceResult<WebhookTestResult> {
+        require_admin(user)?;
+        let webhook = self
+            .with_conn(move |conn| find_webhook(conn, webhook_id))
+            .await?;
+        let notification = webhook::Notification::new(
+            webhook
+                .events
+                .first()
+                .copied()
+                .unwrap_or(webhook::WebhookEvent::FirstBlood),
+            format!(
+                "Test message for webhook {} from {}",
+                webhook.name, user.username
+            ),
+            serde_json::json!({ "test": true }),
+        );
+        let error = webhook.send(&notification).await.err();
+        Ok(WebhookTestResult {
+            delivered: error.is_none(),
+            error,
+        })
+    }
+}
+
+fn require_admin(user: &User) -> ServiceResult<()> {
+    if user.is_admin() {
+        Ok(())
+    } else {
+        Err(ServiceError::Forbidden(
+            "Only admins are allowed to do this".to_string(),
+        ))
+    }
+}
+
+/// Exploit authors may only create and edit exploits in their own name.
+fn require_author(user: &User, author: &str) -> ServiceResult<()> {
+    match user.role {
+        Role::Admin => Ok(()),
+        Role::ExploitAuthor if author == user.username => Ok(()),
+        Role::ExploitAuthor => Err(ServiceError::Forbidden(format!(
+            "Exploit authors can only manage exploits with author {}",
+            user.username
+        ))),
+        Role::Viewer => Err(ServiceError::Forbidden(
+            "Viewers can't change exploits".to_string(),
+        )),
+    }
+}
+
+fn find_user(conn: &mut PgConnection, user_id: i32) -> ServiceResult<User> {
+    user::find_user_by_id(conn, user_id)?
+        .ok_or_else(|| ServiceError::NotFound(format!("No user found with id: {user_id}")))
+}
+
+fn find_team(conn: &mut PgConnection, team_id: i32) -> ServiceResult<team::Team> {
+    team::find_team_by_id(conn, team_id)?
+        .ok_or_else(|| ServiceError::NotFound(format!("No team found with id: {team_id}")))
+}
+
+fn find_webhook(conn: &mut PgConnection, webhook_id: i32) -> ServiceResult<webhook::Webhook> {
+    webhook::find_webhook_by_id(conn, webhook_id)?
+        .ok_or_else(|| ServiceError::NotFound(format!("No webhook found with id: {webhook_id}")))
+}
+
+fn find_challenge(
+    conn: &mut PgConnection,
+    challenge_id: i32,
+) -> ServiceResult<challenge::Challenge> {
+    challenge::find_challenge_by_id(conn, challenge_id)?.ok_or_else(|| {
+        ServiceError::NotFound(format!("No challenge found with id: {challenge_id}"))
+    })
+}
+
+/// References to challenges by name have to point to an existing one.
+fn require_challenge(conn: &mut PgConnection, name: &str) -> ServiceResult<()> {
+    match challenge::find_challenge_by_name(conn, name)? {
+        Some(_) => Ok(()),
+        None => Err(ServiceError::InvalidArguments(format!(
+            "No challenge named {name}, add it first"
+        ))),
+    }
+}
+
+fn validate_challenge(challenge: &challenge::NewChallenge) -> ServiceResult<()> {
+    if challenge.name.trim().is_empty() {
+        return Err(ServiceError::InvalidArguments(
+            "The challenge needs a name".to_string(),
+        ));
+    }
+    if let Some(port) = challenge
+        .ports
+        .iter()
+        .find(|port| !(1..=65535).contains(*port))
+    {
+        return Err(ServiceError::InvalidArguments(format!(
+            "Invalid port {port}"
+        )));
+    }
+    Ok(())
+}
+
+fn require_unique_policy_name(conn: &mut PgConnection, name: &str) -> ServiceResult<()> {
+    if exploit::find_policy_by_name(conn, name)?.is_some() {
+        return Err(ServiceError::InvalidArguments(format!(
+            "There already is a policy named {name}"
+        )));
+    }
+    Ok(())
+}
+
+fn find_policy(conn: &mut PgConnection, policy_id: i32) -> ServiceResult<exploit::Policy> {
+    exploit::find_policy_by_id(conn, policy_id)?
+        .ok_or_else(|| ServiceError::NotFound(format!("No policy found with id: {policy_id}")))
+}
+
+fn find_exploit(conn: &mut PgConnection, exploit_id: i32) -> ServiceResult<exploit::Exploit> {
+    exploit::find_exploit_by_id(conn, exploit_id)?
+        .ok_or_else(|| ServiceError::NotFound(format!("No exploit found with id: {exploit_id}")))
+}
+
+fn find_editable_exploit(
+    conn: &mut PgConnection,
+    user: &User,
+    exploit_id: i32,
+) -> ServiceResult<exploit::Exploit> {
+    let exploit = find_exploit(conn, exploit_id)?;
+    if user.can_edit_exploit(&exploit) {
+        Ok(exploit)
+    } else {
+        Err(ServiceError::Forbidden(format!(
+            "Exploit {exploit_id} belongs to {}",
+            exploit.author
+        )))
+    }
+}
+
+/// A pinned version must belong to the exploit and a git ref must exist in the repository.
+/// The exploit id is `None` for new exploits.
+fn validate_exploit_source(
+    conn: &mut PgConnection,
+    exploit_id: Option<i32>,
+    exploit: &exploit::NewExploit,
+) -> ServiceResult<()> {
+    if let Some(version_id) = exploit.pinned_version_id {
+        let exploit_id = exploit_id.ok_or_else(|| {
+            ServiceError::InvalidArguments(
+                "Upload a version of the exploit before pinning it".to_string(),
+            )
+        })?;
+        exploit::find_version_by_id(conn, version_id)?
+            .filter(|version| version.exploit_id == exploit_id)
+            .ok_or_else(|| {
+                ServiceError::InvalidArguments(format!(
+                    "Exploit {exploit_id} has no version {version_id}"
+                ))
+            })?;
+    }
+    if exploit.source == exploit::ExploitSource::Git {
+        match (&exploit.git_repository, &exploit.git_ref) {
+            (Some(repository), Some(git_ref)) => {
+                exploit_runner::resolve_ref(repository, git_ref)
+                    .map_err(|err| ServiceError::InvalidArguments(err.to_string()))?;
+            }
+            _ => {
+                return Err(ServiceError::InvalidArguments(
+                    "Git exploits need a git_repository and a git_ref".to_string(),
+                ))
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Overrides of the resource limits are optional, but can't be negative.
+fn validate_exploit_limits(exploit: &exploit::NewExploit) -> ServiceResult<()> {
+    let limits = [
+        ("cpu_time_limit", exploit.cpu_time_limit),
+        ("memory_limit", exploit.memory_limit),
+        ("process_limit", exploit.process_limit),
+        ("open_files_limit", exploit.open_files_limit),
+        ("cgroup_memory_max", exploit.cgroup_memory_max),
+        ("cgroup_cpu_max", exploit.cgroup_cpu_max),
+    ];
+    require_non_negative(
+        &limits
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?)))
+            .collect::<Vec<_>>(),
+    )
+}
+
+fn require_non_negative(values: &[(&str, i32)]) -> ServiceResult<()> {
+    match values.iter().find(|(_, value)| *value < 0) {
+        Some((name, _)) => Err(ServiceError::InvalidArguments(format!(
+            "{name} can't be negative, use 0 for unlimited"
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Changes of exploit variables are logged for the exploit.
+fn environment_change(action: &'static str, exploit_id: Option<i32>) -> audit::Change {
+    match exploit_id {
+        Some(exploit_id) => audit::Change::new(action, "exploit", Some(exploit_id.into())),
+        None => audit::Change::new(action, "environment", None),
+    }
+}
+
+fn find_run(conn: &mut PgConnection, run_id: i64) -> ServiceResult<exploit::ExploitRun> {
+    exploit::find_run_by_id(conn, run_id)?
+        .ok_or_else(|| ServiceError::NotFound(format!("No exploit run found with id: {run_id}")))
+}
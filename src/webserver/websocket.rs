@@ -1,12 +1,36 @@
+use std::collections::HashMap;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use actix::prelude::*;
-use actix_web_actors::ws::{self, CloseCode, CloseReason};
+use actix_web_actors::ws;
+use base64::prelude::*;
+use prometheus::IntGauge;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, ReceiverStream};
+use tokio_stream::StreamExt;
 
-use super::rest_api::ApiError;
+use super::service::{
+    ExploitArguments, NewUserArguments, PolicyArguments, Service, ServiceError, TeamArguments,
+    TestRunArguments, MAX_EXPLOIT_VERSION_SIZE,
+};
+use crate::challenge;
+use crate::environment;
+use crate::events::{Event, EventSender, Topic};
+use crate::exploit;
+use crate::exploit_runner::{self, OutputLine, OutputSender};
+use crate::flag_submitter;
+use crate::settings;
 use crate::team;
-use crate::DbPool;
-use serde::Deserialize;
+use crate::user::User;
+use crate::webhook;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Version of the request/response envelope. Bumped on incompatible changes.
+const PROTOCOL_VERSION: u32 = 1;
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -14,6 +38,17 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// How often buffered live exploit output is sent to the client
+const OUTPUT_FLUSH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Largest message accepted from the client. Fits a base64 encoded exploit version upload.
+pub const MAX_FRAME_SIZE: usize = MAX_EXPLOIT_VERSION_SIZE / 3 * 4 + 64 * 1024;
+
+/// Maximum number of live output lines buffered between two flushes.
+/// Further lines are dropped and only counted, so a chatty exploit
+/// can't make the session buffer grow without bounds.
+const MAX_PENDING_OUTPUT_LINES: usize = 1000;
+
 /// websocket connection is long running connection, it easier
 /// to handle with an actor
 pub struct WsApiSession {
@@ -21,33 +56,233 @@ pub struct WsApiSession {
     /// otherwise we drop connection.
     hb: Instant,
 
-    pool: DbPool,
+    service: Service,
+    /// The logged in user who opened the connection.
+    user: User,
+
+    live_output: OutputSender,
+    /// Runs and exploits the client wants to see the live output of.
+    output_subscriptions: Vec<OutputSubscription>,
+    /// Stream of live output while there are subscriptions.
+    output_stream: Option<SpawnHandle>,
+    /// Output lines waiting for the next flush.
+    pending_output: Vec<Arc<OutputLine>>,
+    /// Number of output lines dropped since the last flush.
+    skipped_output: u64,
+    /// Number of the next expected output line of every subscribed run seen so far,
+    /// to count the lines missed because the session fell behind.
+    output_seqs: HashMap<i64, u64>,
+
+    events: EventSender,
+    /// Event topics the client is interested in.
+    topics: Vec<Topic>,
+    /// Stream of events while there are subscribed topics or output subscriptions,
+    /// which end with their run.
+    event_stream: Option<SpawnHandle>,
+
+    /// Number of connected sessions in the metrics.
+    sessions: IntGauge,
+}
+
+#[derive(Serialize, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+enum OutputSubscription {
+    Run(i64),
+    Exploit(i32),
+}
+
+impl OutputSubscription {
+    fn matches(&self, line: &OutputLine) -> bool {
+        match *self {
+            OutputSubscription::Run(run_id) => line.exploit_run_id == run_id,
+            OutputSubscription::Exploit(exploit_id) => line.exploit_id == exploit_id,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WsOutputEvent<'a> {
+    event: &'static str,
+    lines: Vec<&'a OutputLine>,
+    /// Number of lines which were dropped because the client couldn't keep up.
+    skipped: u64,
+}
+
+#[derive(Serialize)]
+struct WsEventsSkipped {
+    event: &'static str,
+    /// Number of events which were dropped because the client couldn't keep up.
+    skipped: u64,
+}
+
+/// A command sent by the client.
+#[derive(Deserialize, Debug)]
+struct WsRequest {
+    /// Protocol version the client speaks.
+    v: u32,
+    /// Chosen by the client and sent back with the reply to match it to the request.
+    #[serde(default)]
+    id: serde_json::Value,
+    cmd: String,
+    #[serde(default)]
+    args: serde_json::Value,
+}
+
+/// Reply to exactly one `WsRequest`.
+#[derive(Serialize)]
+struct WsResponse {
+    v: u32,
+    id: serde_json::Value,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<WsError>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+enum WsErrorCode {
+    /// The message isn't a valid request envelope.
+    MalformedRequest,
+    UnsupportedVersion,
+    UnknownCommand,
+    /// The `args` don't fit the command.
+    InvalidArguments,
+    NotFound,
+    /// The user isn't allowed to run the command.
+    Forbidden,
+    /// The command can't be applied in the current state.
+    Conflict,
+    /// Something went wrong on our side, e.g. the database isn't reachable.
+    Internal,
 }
 
-#[derive(Debug)]
-struct WsApiError {
-    error: String,
+#[derive(Serialize, Debug)]
+struct WsError {
+    code: WsErrorCode,
+    message: String,
+    /// Additional machine readable information about the error.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
 }
-impl std::fmt::Display for WsApiError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.error)
+
+impl WsError {
+    fn new(code: WsErrorCode, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            details: None,
+        }
     }
 }
-impl std::error::Error for WsApiError {}
+
+impl From<ServiceError> for WsError {
+    fn from(err: ServiceError) -> Self {
+        let code = match &err {
+            ServiceError::NotFound(_) => WsErrorCode::NotFound,
+            ServiceError::Forbidden(_) => WsErrorCode::Forbidden,
+            ServiceError::InvalidArguments(_) => WsErrorCode::InvalidArguments,
+            ServiceError::PolicyInUse { .. } => WsErrorCode::Conflict,
+            ServiceError::Internal(err) => {
+                log::error!("Websocket command failed: {}", err);
+                WsErrorCode::Internal
+            }
+        };
+        let details = match &err {
+            ServiceError::PolicyInUse { usage, .. } => serde_json::to_value(usage)
+                .ok()
+                .map(|usage| json!({ "usage": usage })),
+            _ => None,
+        };
+        WsError {
+            code,
+            message: err.to_string(),
+            details,
+        }
+    }
+}
+
+type WsResult = Result<serde_json::Value, WsError>;
 
 #[derive(Deserialize)]
-struct WsApiCommandGetTeam {
-    team_id: i32,
+struct WsApiCommandSubscribeOutput {
+    run_id: Option<i64>,
+    exploit_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct WsApiCommandTestRun {
+    exploit_id: i32,
+    #[serde(flatten)]
+    options: TestRunArguments,
+}
+
+/// Output of a test run started by this session, followed by its result.
+struct TestRunUpdate {
+    /// Id of the request which started the test run.
+    id: serde_json::Value,
+    message: TestRunMessage,
+}
+
+enum TestRunMessage {
+    Output(Arc<OutputLine>),
+    Finished(WsResult),
+}
+
+#[derive(Serialize)]
+struct WsTestRunOutputEvent<'a> {
+    event: &'static str,
+    id: &'a serde_json::Value,
+    line: &'a OutputLine,
+}
+
+#[derive(Deserialize)]
+struct WsApiCommandTopics {
+    topics: Vec<Topic>,
 }
 
 impl WsApiSession {
-    pub fn new(pool: DbPool) -> Self {
+    pub fn new(
+        service: Service,
+        user: User,
+        live_output: OutputSender,
+        events: EventSender,
+        sessions: IntGauge,
+    ) -> Self {
         Self {
             hb: Instant::now(),
-            pool,
+            service,
+            user,
+            live_output,
+            output_subscriptions: Vec::new(),
+            output_stream: None,
+            pending_output: Vec::new(),
+            skipped_output: 0,
+            output_seqs: HashMap::new(),
+            events,
+            topics: Vec::new(),
+            event_stream: None,
+            sessions,
         }
     }
 
+    /// Send the live output collected since the last flush in one message.
+    fn flush_output(&mut self, ctx: &mut <Self as Actor>::Context) {
+        if self.pending_output.is_empty() && self.skipped_output == 0 {
+            return;
+        }
+
+        let event = WsOutputEvent {
+            event: "run_output",
+            lines: self.pending_output.iter().map(Arc::as_ref).collect(),
+            skipped: self.skipped_output,
+        };
+        ctx.text(serde_json::to_string(&event).unwrap());
+        self.pending_output.clear();
+        self.skipped_output = 0;
+    }
+
     /// helper method that sends ping to client every second.
     ///
     /// also this method checks heartbeats from client
@@ -69,45 +304,746 @@ impl WsApiSession {
         });
     }
 
+    /// Parse a request, run the command and send the reply carrying the id of the request.
+    fn handle_message(&mut self, ctx: &mut <WsApiSession as Actor>::Context, message: &str) {
+        let request = match serde_json::from_str::<serde_json::Value>(message) {
+            Ok(request) => request,
+            Err(err) => {
+                let error = WsError::new(WsErrorCode::MalformedRequest, err);
+                send_reply(ctx, serde_json::Value::Null, Err(error));
+                return;
+            }
+        };
+        let id = request.get("id").cloned().unwrap_or_default();
+        let request = match serde_json::from_value::<WsRequest>(request) {
+            Ok(request) => request,
+            Err(err) => {
+                let error = WsError::new(WsErrorCode::MalformedRequest, err);
+                send_reply(ctx, id, Err(error));
+                return;
+            }
+        };
+        if request.v != PROTOCOL_VERSION {
+            let error = WsError::new(
+                WsErrorCode::UnsupportedVersion,
+                format!(
+                    "Unsupported protocol version {}, expected {PROTOCOL_VERSION}",
+                    request.v
+                ),
+            );
+            send_reply(ctx, request.id, Err(error));
+            return;
+        }
+
+        self.handle_command(ctx, request.id, request.cmd, request.args);
+    }
+
+    /// Commands changing the state of this session are handled right away.
+    /// Everything else goes to the API service and is answered once it's done,
+    /// so slow commands don't block the session.
     fn handle_command(
         &mut self,
         ctx: &mut <WsApiSession as Actor>::Context,
-        message: &str,
-    ) -> Result<(), crate::db::Error> {
-        let command: ApiCommand = serde_json::from_str(message)?;
-
-        match command.cmd.as_str() {
-            "teams" => {
-                let conn = &mut self.pool.get()?;
-                let team_list = team::get_teams(conn)?;
-                ctx.text(serde_json::to_string(&team_list).unwrap());
-            }
-            "get_team" => {
-                let command: WsApiCommandGetTeam = serde_json::from_str(message)?;
-                let conn = &mut self.pool.get()?;
-                let team = team::find_team_by_id(conn, command.team_id)?;
-
-                if let Some(team) = team {
-                    ctx.text(serde_json::to_string(&team).unwrap());
-                } else {
-                    ctx.text(
-                        serde_json::to_string(&ApiError {
-                            error: format!("No team found with id: {}", command.team_id),
-                        })
-                        .unwrap(),
-                    );
-                }
+        id: serde_json::Value,
+        cmd: String,
+        args: serde_json::Value,
+    ) {
+        let result = match cmd.as_str() {
+            "subscribe_output" => match parse_args(args) {
+                Ok(args) => return self.subscribe_output(ctx, id, args),
+                Err(err) => Err(err),
+            },
+            "unsubscribe_output" => self.unsubscribe_output(ctx),
+            "test_run_exploit" => match parse_args(args) {
+                Ok(args) => return self.test_run_exploit(ctx, id, args),
+                Err(err) => Err(err),
+            },
+            "subscribe" => parse_args(args).and_then(|args| self.subscribe(ctx, args)),
+            "unsubscribe" => parse_args(args).and_then(|args| self.unsubscribe(ctx, args)),
+            _ => {
+                ctx.spawn(
+                    api_command(self.service.clone(), self.user.clone(), cmd, args)
+                        .into_actor(self)
+                        .map(move |result, _, ctx| send_reply(ctx, id, result)),
+                );
+                return;
+            }
+        };
+        send_reply(ctx, id, result);
+    }
+
+    fn subscribe_output(
+        &mut self,
+        ctx: &mut <WsApiSession as Actor>::Context,
+        id: serde_json::Value,
+        args: WsApiCommandSubscribeOutput,
+    ) {
+        match (args.run_id, args.exploit_id) {
+            (Some(run_id), None) => {
+                // Subscribe before looking at the run, so the end of the run can't be missed
+                // in between. Only running exploits produce live output.
+                let subscription = OutputSubscription::Run(run_id);
+                let subscribed = self.output_subscriptions.contains(&subscription);
+                let _ = self.add_output_subscription(ctx, subscription);
+                let service = self.service.clone();
+                let run = async move { service.get_run(run_id).await };
+                ctx.spawn(run.into_actor(self).map(move |run, act, ctx| {
+                    let result = match run {
+                        Ok(run) if run.run.end_time.is_none() => reply(&act.output_subscriptions),
+                        Ok(_) => Err(WsError::new(
+                            WsErrorCode::Conflict,
+                            format!("Exploit run {run_id} already finished"),
+                        )),
+                        Err(err) => Err(err.into()),
+                    };
+                    if result.is_err() && !subscribed {
+                        act.remove_output_subscription(ctx, subscription);
+                    }
+                    send_reply(ctx, id, result);
+                }));
+            }
+            (None, Some(exploit_id)) => {
+                let result =
+                    self.add_output_subscription(ctx, OutputSubscription::Exploit(exploit_id));
+                send_reply(ctx, id, result);
             }
             _ => {
-                let error = ApiError {
-                    error: format!("Unknown command: {}", command.cmd),
-                };
-                ctx.text(serde_json::to_string(&error).unwrap());
-                log::error!("{}", error.error);
-                return Err(Box::new(WsApiError { error: error.error }));
+                let error = WsError::new(
+                    WsErrorCode::InvalidArguments,
+                    "Either run_id or exploit_id is required",
+                );
+                send_reply(ctx, id, Err(error));
             }
         }
-        Ok(())
+    }
+
+    /// Send the output of the test run to this session only while it's running.
+    /// The reply follows the last line of output.
+    fn test_run_exploit(
+        &mut self,
+        ctx: &mut <WsApiSession as Actor>::Context,
+        id: serde_json::Value,
+        args: WsApiCommandTestRun,
+    ) {
+        let live_output = exploit_runner::output_channel();
+        let (result_tx, result_rx) = mpsc::channel(1);
+        let output = BroadcastStream::new(live_output.subscribe())
+            .filter_map(|line| line.ok().map(TestRunMessage::Output));
+        let result = ReceiverStream::new(result_rx).map(TestRunMessage::Finished);
+        ctx.add_stream(output.chain(result).map(move |message| TestRunUpdate {
+            id: id.clone(),
+            message,
+        }));
+
+        let service = self.service.clone();
+        let user = self.user.clone();
+        let test_run = async move {
+            let result = service
+                .test_run_exploit(&user, args.exploit_id, args.options, live_output)
+                .await;
+            let _ = result_tx
+                .send(
+                    result
+                        .map_err(WsError::from)
+                        .and_then(|result| reply(&result)),
+                )
+                .await;
+        };
+        ctx.spawn(test_run.into_actor(self));
+    }
+
+    fn add_output_subscription(
+        &mut self,
+        ctx: &mut <WsApiSession as Actor>::Context,
+        subscription: OutputSubscription,
+    ) -> WsResult {
+        if !self.output_subscriptions.contains(&subscription) {
+            self.output_subscriptions.push(subscription);
+        }
+        if self.output_stream.is_none() {
+            let stream = BroadcastStream::new(self.live_output.subscribe());
+            self.output_stream = Some(ctx.add_stream(stream));
+        }
+        self.update_event_stream(ctx);
+        reply(&self.output_subscriptions)
+    }
+
+    fn remove_output_subscription(
+        &mut self,
+        ctx: &mut <WsApiSession as Actor>::Context,
+        subscription: OutputSubscription,
+    ) {
+        self.output_subscriptions.retain(|s| *s != subscription);
+        if self.output_subscriptions.is_empty() {
+            if let Some(stream) = self.output_stream.take() {
+                ctx.cancel_future(stream);
+            }
+        }
+        self.update_event_stream(ctx);
+    }
+
+    fn unsubscribe_output(&mut self, ctx: &mut <WsApiSession as Actor>::Context) -> WsResult {
+        self.output_subscriptions.clear();
+        if let Some(stream) = self.output_stream.take() {
+            ctx.cancel_future(stream);
+        }
+        self.pending_output.clear();
+        self.skipped_output = 0;
+        self.output_seqs.clear();
+        self.update_event_stream(ctx);
+        reply(&self.output_subscriptions)
+    }
+
+    /// Forget a run once it finished and its remaining output was handled.
+    fn output_run_finished(&mut self, ctx: &mut <WsApiSession as Actor>::Context, run_id: i64) {
+        let subscription = OutputSubscription::Run(run_id);
+        if !self.output_seqs.contains_key(&run_id)
+            && !self.output_subscriptions.contains(&subscription)
+        {
+            return;
+        }
+        // The last lines are published before the run finishes, but the output
+        // stream may be polled after the event stream.
+        ctx.run_later(OUTPUT_FLUSH_INTERVAL, move |act, ctx| {
+            act.output_seqs.remove(&run_id);
+            if act.output_subscriptions.contains(&subscription) {
+                act.remove_output_subscription(ctx, subscription);
+            }
+        });
+    }
+
+    fn subscribe(
+        &mut self,
+        ctx: &mut <WsApiSession as Actor>::Context,
+        args: WsApiCommandTopics,
+    ) -> WsResult {
+        for topic in args.topics {
+            if !self.topics.contains(&topic) {
+                self.topics.push(topic);
+            }
+        }
+        self.update_event_stream(ctx);
+        reply(&self.topics)
+    }
+
+    fn unsubscribe(
+        &mut self,
+        ctx: &mut <WsApiSession as Actor>::Context,
+        args: WsApiCommandTopics,
+    ) -> WsResult {
+        self.topics.retain(|topic| !args.topics.contains(topic));
+        self.update_event_stream(ctx);
+        reply(&self.topics)
+    }
+
+    /// Listen to events while there are subscribed topics or output subscriptions.
+    fn update_event_stream(&mut self, ctx: &mut <WsApiSession as Actor>::Context) {
+        let needed = !self.topics.is_empty() || !self.output_subscriptions.is_empty();
+        if needed && self.event_stream.is_none() {
+            let stream = BroadcastStream::new(self.events.subscribe());
+            self.event_stream = Some(ctx.add_stream(stream));
+        } else if !needed {
+            if let Some(stream) = self.event_stream.take() {
+                ctx.cancel_future(stream);
+            }
+        }
+    }
+}
+
+fn parse_args<T: DeserializeOwned>(args: serde_json::Value) -> Result<T, WsError> {
+    serde_json::from_value(args).map_err(|err| WsError::new(WsErrorCode::InvalidArguments, err))
+}
+
+/// Like `parse_args`, but commands with only optional arguments may leave them out completely.
+fn parse_optional_args<T: DeserializeOwned + Default>(
+    args: serde_json::Value,
+) -> Result<T, WsError> {
+    if args.is_null() {
+        Ok(T::default())
+    } else {
+        parse_args(args)
+    }
+}
+
+fn reply<T: Serialize>(data: &T) -> WsResult {
+    serde_json::to_value(data).map_err(|err| WsError::new(WsErrorCode::Internal, err))
+}
+
+fn send_reply(ctx: &mut <WsApiSession as Actor>::Context, id: serde_json::Value, result: WsResult) {
+    let response = match result {
+        Ok(data) => WsResponse {
+            v: PROTOCOL_VERSION,
+            id,
+            ok: true,
+            data: Some(data),
+            error: None,
+        },
+        Err(error) => {
+            log::debug!("Websocket command {} failed: {}", id, error.message);
+            WsResponse {
+                v: PROTOCOL_VERSION,
+                id,
+                ok: false,
+                data: None,
+                error: Some(error),
+            }
+        }
+    };
+    ctx.text(serde_json::to_string(&response).unwrap());
+}
+
+#[derive(Deserialize)]
+struct ApiTokenArgs {
+    token_id: Option<i32>,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct UserIdArgs {
+    user_id: i32,
+}
+
+#[derive(Deserialize)]
+struct UpdateUserArgs {
+    user_id: i32,
+    user: NewUserArguments,
+}
+
+#[derive(Deserialize)]
+struct TeamIdArgs {
+    team_id: i32,
+    #[serde(flatten)]
+    options: TeamArguments,
+}
+
+#[derive(Deserialize)]
+struct TeamArgs {
+    team: team::Team,
+}
+
+#[derive(Deserialize)]
+struct UpdateTeamArgs {
+    team_id: i32,
+    team: team::Team,
+}
+
+#[derive(Deserialize)]
+struct TeamMetaArgs {
+    team_id: i32,
+    key: String,
+    value: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct PolicyIdArgs {
+    policy_id: i32,
+    #[serde(flatten)]
+    options: PolicyArguments,
+}
+
+#[derive(Deserialize)]
+struct PolicyArgs {
+    policy: exploit::NewPolicy,
+}
+
+#[derive(Deserialize)]
+struct UpdatePolicyArgs {
+    policy_id: i32,
+    policy: exploit::NewPolicy,
+}
+
+#[derive(Deserialize)]
+struct ExploitIdArgs {
+    exploit_id: i32,
+    #[serde(flatten)]
+    options: ExploitArguments,
+}
+
+#[derive(Deserialize)]
+struct ExploitArgs {
+    exploit: exploit::NewExploit,
+}
+
+#[derive(Deserialize)]
+struct UpdateExploitArgs {
+    exploit_id: i32,
+    exploit: exploit::NewExploit,
+}
+
+#[derive(Deserialize)]
+struct ExploitTargetArgs {
+    exploit_id: i32,
+    team_id: i32,
+    policy_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct ExploitMetaArgs {
+    exploit_id: i32,
+    key: String,
+    value: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct EnvironmentArgs {
+    /// Global variables if not set.
+    exploit_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+struct EnvironmentVariableArgs {
+    exploit_id: Option<i32>,
+    name: String,
+    #[serde(flatten)]
+    variable: Option<environment::NewEnvironmentVariable>,
+}
+
+#[derive(Deserialize)]
+struct ExploitVersionsArgs {
+    exploit_id: i32,
+}
+
+#[derive(Deserialize)]
+struct UploadExploitVersionArgs {
+    exploit_id: i32,
+    filename: String,
+    /// Base64 encoded script or archive.
+    content: String,
+}
+
+#[derive(Deserialize)]
+struct ExploitVersionIdArgs {
+    version_id: i32,
+}
+
+#[derive(Deserialize)]
+struct RunIdArgs {
+    run_id: i64,
+}
+
+#[derive(Deserialize)]
+struct WebhookArgs {
+    webhook: webhook::NewWebhook,
+}
+
+#[derive(Deserialize)]
+struct WebhookIdArgs {
+    webhook_id: i32,
+}
+
+#[derive(Deserialize)]
+struct UpdateWebhookArgs {
+    webhook_id: i32,
+    webhook: webhook::NewWebhook,
+}
+
+#[derive(Deserialize)]
+struct ChallengeArgs {
+    challenge: challenge::NewChallenge,
+}
+
+#[derive(Deserialize)]
+struct ChallengeIdArgs {
+    challenge_id: i32,
+}
+
+#[derive(Deserialize)]
+struct UpdateChallengeArgs {
+    challenge_id: i32,
+    challenge: challenge::NewChallenge,
+}
+
+#[derive(Deserialize)]
+struct SkippedTargetArgs {
+    team_id: i32,
+    target_challenge: String,
+}
+
+#[derive(Deserialize)]
+struct AlertIdArgs {
+    alert_id: i64,
+}
+
+#[derive(Deserialize)]
+struct SettingsArgs {
+    settings: settings::Settings,
+}
+
+#[derive(Deserialize)]
+struct FlagResponseRulesArgs {
+    rules: Vec<flag_submitter::NewFlagResponseRule>,
+}
+
+fn missing_arg(name: &str) -> WsError {
+    WsError::new(
+        WsErrorCode::InvalidArguments,
+        format!("missing field `{name}`"),
+    )
+}
+
+/// Commands mirroring the REST API. The arguments are the path parameters,
+/// query parameters and the request body of the matching REST endpoint.
+async fn api_command(
+    service: Service,
+    user: User,
+    cmd: String,
+    args: serde_json::Value,
+) -> WsResult {
+    match cmd.as_str() {
+        "me" => reply(&user),
+        "api_tokens" => reply(&service.get_api_tokens(user.id).await?),
+        "add_api_token" => {
+            let args: ApiTokenArgs = parse_args(args)?;
+            let name = args.name.ok_or_else(|| missing_arg("name"))?;
+            reply(&service.add_api_token(&user, name).await?)
+        }
+        "remove_api_token" => {
+            let args: ApiTokenArgs = parse_args(args)?;
+            let token_id = args.token_id.ok_or_else(|| missing_arg("token_id"))?;
+            reply(&service.remove_api_token(&user, token_id).await?)
+        }
+        "users" => reply(&service.get_users(&user).await?),
+        "add_user" => reply(&service.add_user(&user, parse_args(args)?).await?),
+        "update_user" => {
+            let args: UpdateUserArgs = parse_args(args)?;
+            reply(&service.update_user(&user, args.user_id, args.user).await?)
+        }
+        "delete_user" => {
+            let args: UserIdArgs = parse_args(args)?;
+            reply(&service.delete_user(&user, args.user_id).await?)
+        }
+        "teams" => reply(&service.get_teams(parse_optional_args(args)?).await?),
+        "get_team" => {
+            let args: TeamIdArgs = parse_args(args)?;
+            reply(&service.get_team(args.team_id, args.options).await?)
+        }
+        "add_team" => {
+            let args: TeamArgs = parse_args(args)?;
+            reply(&service.add_team(&user, args.team).await?)
+        }
+        "update_team" => {
+            let args: UpdateTeamArgs = parse_args(args)?;
+            reply(&service.update_team(&user, args.team_id, args.team).await?)
+        }
+        "set_team_meta" => {
+            let args: TeamMetaArgs = parse_args(args)?;
+            let value = args.value.ok_or_else(|| missing_arg("value"))?;
+            reply(
+                &service
+                    .set_team_meta(&user, args.team_id, args.key, value)
+                    .await?,
+            )
+        }
+        "remove_team_meta" => {
+            let args: TeamMetaArgs = parse_args(args)?;
+            reply(
+                &service
+                    .remove_team_meta(&user, args.team_id, args.key)
+                    .await?,
+            )
+        }
+        "challenges" => reply(&service.get_challenges().await?),
+        "get_challenge" => {
+            let args: ChallengeIdArgs = parse_args(args)?;
+            reply(&service.get_challenge(args.challenge_id).await?)
+        }
+        "add_challenge" => {
+            let args: ChallengeArgs = parse_args(args)?;
+            reply(&service.add_challenge(&user, args.challenge).await?)
+        }
+        "update_challenge" => {
+            let args: UpdateChallengeArgs = parse_args(args)?;
+            reply(
+                &service
+                    .update_challenge(&user, args.challenge_id, args.challenge)
+                    .await?,
+            )
+        }
+        "delete_challenge" => {
+            let args: ChallengeIdArgs = parse_args(args)?;
+            reply(&service.delete_challenge(&user, args.challenge_id).await?)
+        }
+        "skipped_targets" => reply(&service.get_skipped_targets().await?),
+        "restore_target" => {
+            let args: SkippedTargetArgs = parse_args(args)?;
+            reply(
+                &service
+                    .restore_target(&user, args.team_id, args.target_challenge)
+                    .await?,
+            )
+        }
+        "policies" => reply(&service.get_policies(parse_optional_args(args)?).await?),
+        "get_policy" => {
+            let args: PolicyIdArgs = parse_args(args)?;
+            reply(&service.get_policy(args.policy_id, args.options).await?)
+        }
+        "add_policy" => {
+            let args: PolicyArgs = parse_args(args)?;
+            reply(&service.add_policy(&user, args.policy).await?)
+        }
+        "update_policy" => {
+            let args: UpdatePolicyArgs = parse_args(args)?;
+            reply(
+                &service
+                    .update_policy(&user, args.policy_id, args.policy)
+                    .await?,
+            )
+        }
+        "delete_policy" => {
+            let args: PolicyIdArgs = parse_args(args)?;
+            reply(&service.delete_policy(&user, args.policy_id).await?)
+        }
+        "exploits" => reply(&service.get_exploits(parse_optional_args(args)?).await?),
+        "get_exploit" => {
+            let args: ExploitIdArgs = parse_args(args)?;
+            reply(&service.get_exploit(args.exploit_id, args.options).await?)
+        }
+        "add_exploit" => {
+            let args: ExploitArgs = parse_args(args)?;
+            reply(&service.add_exploit(&user, args.exploit).await?)
+        }
+        "update_exploit" => {
+            let args: UpdateExploitArgs = parse_args(args)?;
+            reply(
+                &service
+                    .update_exploit(&user, args.exploit_id, args.exploit)
+                    .await?,
+            )
+        }
+        "set_exploit_target" => {
+            let args: ExploitTargetArgs = parse_args(args)?;
+            let policy_id = args.policy_id.ok_or_else(|| missing_arg("policy_id"))?;
+            reply(
+                &service
+                    .set_exploit_target(&user, args.exploit_id, args.team_id, policy_id)
+                    .await?,
+            )
+        }
+        "remove_exploit_target" => {
+            let args: ExploitTargetArgs = parse_args(args)?;
+            reply(
+                &service
+                    .remove_exploit_target(&user, args.exploit_id, args.team_id)
+                    .await?,
+            )
+        }
+        "set_exploit_meta" => {
+            let args: ExploitMetaArgs = parse_args(args)?;
+            let value = args.value.ok_or_else(|| missing_arg("value"))?;
+            reply(
+                &service
+                    .set_exploit_meta(&user, args.exploit_id, args.key, value)
+                    .await?,
+            )
+        }
+        "remove_exploit_meta" => {
+            let args: ExploitMetaArgs = parse_args(args)?;
+            reply(
+                &service
+                    .remove_exploit_meta(&user, args.exploit_id, args.key)
+                    .await?,
+            )
+        }
+        "environment" => {
+            let args: EnvironmentArgs = parse_optional_args(args)?;
+            reply(&service.get_environment(args.exploit_id).await?)
+        }
+        "set_environment_variable" => {
+            let args: EnvironmentVariableArgs = parse_args(args)?;
+            let variable = args.variable.ok_or_else(|| missing_arg("value"))?;
+            reply(
+                &service
+                    .set_environment_variable(&user, args.exploit_id, args.name, variable)
+                    .await?,
+            )
+        }
+        "remove_environment_variable" => {
+            let args: EnvironmentVariableArgs = parse_args(args)?;
+            reply(
+                &service
+                    .remove_environment_variable(&user, args.exploit_id, args.name)
+                    .await?,
+            )
+        }
+        "exploit_versions" => {
+            let args: ExploitVersionsArgs = parse_args(args)?;
+            reply(&service.get_exploit_versions(args.exploit_id).await?)
+        }
+        "upload_exploit_version" => {
+            let args: UploadExploitVersionArgs = parse_args(args)?;
+            let content = BASE64_STANDARD
+                .decode(&args.content)
+                .map_err(|err| WsError::new(WsErrorCode::InvalidArguments, err))?;
+            reply(
+                &service
+                    .upload_exploit_version(&user, args.exploit_id, args.filename, content)
+                    .await?,
+            )
+        }
+        "get_exploit_version_content" => {
+            let args: ExploitVersionIdArgs = parse_args(args)?;
+            let (version, content) = service.get_exploit_version_content(args.version_id).await?;
+            reply(&json!({
+                "version": version,
+                "content": BASE64_STANDARD.encode(content),
+            }))
+        }
+        "runs" => reply(&service.get_runs(parse_optional_args(args)?).await?),
+        "get_run" => {
+            let args: RunIdArgs = parse_args(args)?;
+            reply(&service.get_run(args.run_id).await?)
+        }
+        "get_run_output" => {
+            let args: RunIdArgs = parse_args(args)?;
+            reply(&service.get_run_output(args.run_id).await?)
+        }
+        "settings" => reply(&service.get_settings().await?),
+        "update_settings" => {
+            let args: SettingsArgs = parse_args(args)?;
+            reply(&service.update_settings(&user, args.settings).await?)
+        }
+        "flag_response_rules" => reply(&service.get_flag_response_rules().await?),
+        "set_flag_response_rules" => {
+            let args: FlagResponseRulesArgs = parse_args(args)?;
+            reply(&service.set_flag_response_rules(&user, args.rules).await?)
+        }
+        "flags" => reply(&service.get_flags(parse_optional_args(args)?).await?),
+        "add_flags" => reply(&service.add_flags(&user, parse_args(args)?).await?),
+        "requeue_flags" => reply(&service.requeue_flags(&user, parse_args(args)?).await?),
+        "remap_flag_responses" => reply(&service.remap_flag_responses(&user).await?),
+        "flag_stats" => reply(&service.get_flag_stats(parse_optional_args(args)?).await?),
+        "run_stats" => reply(&service.get_run_stats(parse_optional_args(args)?).await?),
+        "webhooks" => reply(&service.get_webhooks(&user).await?),
+        "add_webhook" => {
+            let args: WebhookArgs = parse_args(args)?;
+            reply(&service.add_webhook(&user, args.webhook).await?)
+        }
+        "update_webhook" => {
+            let args: UpdateWebhookArgs = parse_args(args)?;
+            reply(
+                &service
+                    .update_webhook(&user, args.webhook_id, args.webhook)
+                    .await?,
+            )
+        }
+        "delete_webhook" => {
+            let args: WebhookIdArgs = parse_args(args)?;
+            reply(&service.delete_webhook(&user, args.webhook_id).await?)
+        }
+        "test_webhook" => {
+            let args: WebhookIdArgs = parse_args(args)?;
+            reply(&service.test_webhook(&user, args.webhook_id).await?)
+        }
+        "alerts" => reply(&service.get_alerts(parse_optional_args(args)?).await?),
+        "acknowledge_alert" => {
+            let args: AlertIdArgs = parse_args(args)?;
+            reply(&service.acknowledge_alert(&user, args.alert_id).await?)
+        }
+        "audit" => reply(&service.get_audit_log(parse_optional_args(args)?).await?),
+        _ => Err(WsError::new(
+            WsErrorCode::UnknownCommand,
+            format!("Unknown command: {cmd}"),
+        )),
     }
 }
 
@@ -116,13 +1052,99 @@ impl Actor for WsApiSession {
 
     /// Method is called on actor start. We start the heartbeat process here.
     fn started(&mut self, ctx: &mut Self::Context) {
+        self.sessions.inc();
         self.hb(ctx);
+        ctx.run_interval(OUTPUT_FLUSH_INTERVAL, |act, ctx| act.flush_output(ctx));
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.sessions.dec();
     }
 }
 
-#[derive(Deserialize, Debug)]
-struct ApiCommand {
-    cmd: String,
+/// Handler for live exploit output
+impl StreamHandler<Result<Arc<OutputLine>, BroadcastStreamRecvError>> for WsApiSession {
+    fn handle(
+        &mut self,
+        line: Result<Arc<OutputLine>, BroadcastStreamRecvError>,
+        _ctx: &mut Self::Context,
+    ) {
+        match line {
+            Ok(line) => {
+                if !self.output_subscriptions.iter().any(|s| s.matches(&line)) {
+                    return;
+                }
+                let next_seq = self
+                    .output_seqs
+                    .entry(line.exploit_run_id)
+                    .or_insert(line.seq);
+                self.skipped_output += line.seq.saturating_sub(*next_seq);
+                *next_seq = line.seq + 1;
+                if self.pending_output.len() < MAX_PENDING_OUTPUT_LINES {
+                    self.pending_output.push(line);
+                } else {
+                    self.skipped_output += 1;
+                }
+            }
+            // Most of the skipped lines usually belong to other runs. The ones of subscribed
+            // runs show up as gaps in the numbers of their next lines.
+            Err(BroadcastStreamRecvError::Lagged(_)) => (),
+        }
+    }
+
+    /// Keep the session open when the output stream ends.
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
+}
+
+/// Handler for the output of test runs started by this session
+impl StreamHandler<TestRunUpdate> for WsApiSession {
+    fn handle(&mut self, update: TestRunUpdate, ctx: &mut Self::Context) {
+        match update.message {
+            TestRunMessage::Output(line) => {
+                let event = WsTestRunOutputEvent {
+                    event: "test_run_output",
+                    id: &update.id,
+                    line: &line,
+                };
+                ctx.text(serde_json::to_string(&event).unwrap());
+            }
+            TestRunMessage::Finished(result) => send_reply(ctx, update.id, result),
+        }
+    }
+
+    /// Keep the session open when the test run finished.
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
+}
+
+/// Handler for events of the subscribed topics and the end of runs with subscribed output
+impl StreamHandler<Result<Arc<Event>, BroadcastStreamRecvError>> for WsApiSession {
+    fn handle(
+        &mut self,
+        event: Result<Arc<Event>, BroadcastStreamRecvError>,
+        ctx: &mut Self::Context,
+    ) {
+        match event {
+            Ok(event) => {
+                if let Event::RunFinished(run) = event.as_ref() {
+                    self.output_run_finished(ctx, run.id);
+                }
+                if self.topics.contains(&event.topic()) {
+                    ctx.text(serde_json::to_string(event.as_ref()).unwrap());
+                }
+            }
+            Err(BroadcastStreamRecvError::Lagged(skipped)) if !self.topics.is_empty() => {
+                let event = WsEventsSkipped {
+                    event: "events_skipped",
+                    skipped,
+                };
+                ctx.text(serde_json::to_string(&event).unwrap());
+            }
+            Err(BroadcastStreamRecvError::Lagged(_)) => (),
+        }
+    }
+
+    /// Keep the session open when the event stream ends.
+    fn finished(&mut self, _ctx: &mut Self::Context) {}
 }
 
 /// Handler for `ws::Message`
@@ -146,16 +1168,14 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsApiSession {
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
             }
-            ws::Message::Text(text) => {
-                let message = text.trim();
-                if let Err(err) = self.handle_command(ctx, message) {
-                    log::error!("Error: {} ({})", message, err);
-                    ctx.text("Error");
-                    ctx.close(Some(CloseReason::from(CloseCode::Error)));
-                    ctx.stop();
-                }
+            ws::Message::Text(text) => self.handle_message(ctx, text.trim()),
+            ws::Message::Binary(_) => {
+                let error = WsError::new(
+                    WsErrorCode::MalformedRequest,
+                    "Binary messages aren't supported",
+                );
+                send_reply(ctx, serde_json::Value::Null, Err(error));
             }
-            ws::Message::Binary(_) => log::error!("Unexpected binary"),
             ws::Message::Close(reason) => {
                 ctx.close(reason);
                 ctx.stop();